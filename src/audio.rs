@@ -1,64 +1,102 @@
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
-use sdl2::Sdl;
-
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
-}
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+use crate::constants;
+use crate::tone::{Oscillator, Tone};
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
-    }
-}
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::Sdl;
 
+// Audio output fed with the sound events of the machine.
+// Samples are generated for the elapsed emulation time and queued to SDL,
+// so that the beep starts and ends at the exact sample of each event.
 pub struct Beep {
-    device: AudioDevice<SquareWave>,
+    queue: AudioQueue<f32>,
+    oscillator: Oscillator,
+    // Sample rate of the device [Hz]
+    rate: u128,
+    // Time of the first sample [ns]
+    start_t: u128,
+    // Number of samples generated since start_t
+    generated: u128,
+    // Scratch buffer for the generated samples
+    buffer: Vec<f32>,
 }
 
 impl Beep {
-    pub fn new(sdl_context: &Sdl) -> Self {
+    // Opens the audio device with the given tone and start time [ns]
+    pub fn new(sdl_context: &Sdl, tone: Tone, start_t: u128) -> Self {
         let audio_subsystem = sdl_context.audio().unwrap();
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(constants::AUDIO_SAMPLE_RATE as i32),
             channels: Some(1), // mono
             samples: None,     // default sample size
         };
 
-        let device = audio_subsystem
-            .open_playback(None, &desired_spec, |spec| {
-                // initialize the audio callback
-                SquareWave {
-                    phase_inc: 440.0 / spec.freq as f32,
-                    phase: 0.0,
-                    volume: 0.2,
-                }
-            })
+        let queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+        let rate = queue.spec().freq as f32;
+
+        let oscillator = Oscillator::new(tone, rate);
+
+        // Some silence ahead absorbs the jitter of the main loop
+        queue
+            .queue_audio(&vec![0.0; constants::AUDIO_PREFILL as usize])
             .unwrap();
+        queue.resume();
 
-        Beep { device }
+        Beep {
+            queue,
+            oscillator,
+            rate: rate as u128,
+            start_t,
+            generated: 0,
+            buffer: Vec::new(),
+        }
     }
 
-    pub fn play(&self) {
-        if self.device.status() == AudioStatus::Stopped
-            || self.device.status() == AudioStatus::Paused
-        {
-            self.device.resume();
+    // Generates and queues the samples up to time t [ns], applying the given sound events
+    pub fn update(&mut self, t: u128, events: impl Iterator<Item = (u128, bool)>) {
+        for (event_t, on) in events {
+            self.generate(event_t);
+            self.oscillator.gate = on;
         }
+        self.generate(t);
+
+        // Resync if the output fell too far behind (e.g. paused in debug mode)
+        let queued = self.queue.size() / std::mem::size_of::<f32>() as u32;
+        if queued > constants::AUDIO_MAX_LATENCY {
+            self.queue.clear();
+        }
+    }
+
+    // Skips the samples up to time t [ns] without playing them, applying the given sound events
+    pub fn skip(&mut self, t: u128, events: impl Iterator<Item = (u128, bool)>) {
+        for (_, on) in events {
+            self.oscillator.gate = on;
+        }
+        self.generated = self.generated.max(self.target(t));
+    }
+
+    // Returns the number of samples from the start up to time t [ns]
+    fn target(&self, t: u128) -> u128 {
+        t.saturating_sub(self.start_t) * self.rate / 1_000_000_000
     }
 
-    pub fn pause(&self) {
-        self.device.pause();
+    // Generates the samples from the last generated one up to time t [ns]
+    fn generate(&mut self, t: u128) {
+        let target = self.target(t);
+        if target <= self.generated {
+            return;
+        }
+        let mut count = target - self.generated;
+        if count > constants::AUDIO_MAX_LATENCY as u128 {
+            // Skip the samples that are too late to be played
+            self.generated = target - constants::AUDIO_MAX_LATENCY as u128;
+            count = constants::AUDIO_MAX_LATENCY as u128;
+        }
+
+        self.buffer.resize(count as usize, 0.0);
+        self.oscillator.render(&mut self.buffer);
+        self.generated += count;
+
+        self.queue.queue_audio(&self.buffer).unwrap();
     }
 }
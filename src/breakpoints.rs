@@ -0,0 +1,158 @@
+use crate::bus::{Access, AccessKind};
+use crate::chip8::Chip8;
+use crate::expr::{Expr, Register};
+use crate::symbols::Symbols;
+
+use std::collections::HashMap;
+
+// Kind of access a watchpoint stops at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+// Memory watchpoint over an inclusive address range
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub end: usize,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    // Parses 'ADDR[-END][:r|w|rw]', e.g. '0x300-0x30f:w', with addresses or labels.
+    // Defaults to read and write.
+    pub fn parse(spec: &str, symbols: &Symbols) -> Result<Watchpoint, String> {
+        let (range, kind) = match spec.split_once(':') {
+            Some((range, kind)) => (range, kind.trim()),
+            None => (spec, "rw"),
+        };
+        let kind = match kind {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "rw" | "a" => WatchKind::Access,
+            _ => return Err(format!("Unknown watchpoint kind '{}'", kind)),
+        };
+        let (start, end) = symbols.parse_range(range)?;
+        Ok(Watchpoint { start, end, kind })
+    }
+
+    // Returns true if the access triggers the watchpoint
+    pub fn matches(&self, access: &Access) -> bool {
+        let kind = match access.kind {
+            AccessKind::Read => self.kind != WatchKind::Write,
+            AccessKind::Write => self.kind != WatchKind::Read,
+        };
+        kind && access.addr >= self.start && access.addr <= self.end
+    }
+}
+
+// Reason why the machine stopped
+#[derive(Clone, Debug)]
+pub enum StopReason {
+    // Before the instruction at the address
+    Breakpoint(usize),
+    // After an instruction accessed a watched address
+    Watchpoint(Access),
+    // After an instruction changed a watched register, from the old to the new value
+    Register(Register, u32, u32),
+    // After an instruction made the condition true
+    Condition(String),
+}
+
+impl StopReason {
+    // Returns the description of the stop, with the labels of the addresses
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        match self {
+            StopReason::Breakpoint(addr) => format!("Breakpoint at {}", symbols.describe(*addr)),
+            StopReason::Watchpoint(access) => {
+                let kind = match access.kind {
+                    AccessKind::Read => "Read",
+                    AccessKind::Write => "Write",
+                };
+                format!(
+                    "{} 0x{:02x} at {}",
+                    kind,
+                    access.value,
+                    symbols.describe(access.addr)
+                )
+            }
+            StopReason::Register(reg, old, new) => {
+                format!("{} changed 0x{:x} -> 0x{:x}", reg, old, new)
+            }
+            StopReason::Condition(source) => format!("Condition {}", source),
+        }
+    }
+}
+
+// Breakpoints, watchpoints and conditions checked by the machine
+#[derive(Default)]
+pub struct Breakpoints {
+    // Instruction addresses to stop at, with an optional condition
+    pub pc: HashMap<usize, Option<Expr>>,
+    // Memory watchpoints
+    pub watchpoints: Vec<Watchpoint>,
+    // Registers to stop at when they change
+    pub registers: Vec<Register>,
+    // Conditions to stop at when they become true
+    pub conditions: Vec<Expr>,
+}
+
+impl Breakpoints {
+    // Parses and adds a PC breakpoint 'ADDR' or 'ADDR if CONDITION', ADDR being an
+    // address or a label
+    pub fn add_breakpoint(&mut self, spec: &str, symbols: &Symbols) -> Result<(), String> {
+        let (addr, condition) = match spec.split_once(" if ") {
+            Some((addr, condition)) => (addr, Some(Expr::parse(condition, symbols)?)),
+            None => (spec, None),
+        };
+        self.pc.insert(symbols.parse_addr(addr)?, condition);
+        Ok(())
+    }
+
+    // Returns true if something is checked after each instruction
+    pub fn watching(&self) -> bool {
+        !self.watchpoints.is_empty() || !self.registers.is_empty() || !self.conditions.is_empty()
+    }
+
+    // Returns true if the machine must stop before the instruction at the given address
+    pub fn check_pc(&self, chip8: &Chip8, pc: usize) -> bool {
+        match self.pc.get(&pc) {
+            Some(Some(condition)) => condition.is_true(chip8),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    // Returns the values of the watched registers and conditions, taken before an instruction
+    pub fn snapshot(&self, chip8: &Chip8) -> Vec<u32> {
+        let registers = self.registers.iter().map(|r| r.value(chip8));
+        let conditions = self.conditions.iter().map(|c| c.is_true(chip8) as u32);
+        registers.chain(conditions).collect()
+    }
+
+    // Returns the reason to stop after an instruction, given the snapshot taken before it.
+    // Conditions stop only when they become true.
+    pub fn check_after(&self, chip8: &Chip8, before: &[u32]) -> Option<StopReason> {
+        for access in &chip8.bus.accesses {
+            if self.watchpoints.iter().any(|w| w.matches(access)) {
+                return Some(StopReason::Watchpoint(*access));
+            }
+        }
+        let (registers_before, conditions_before) = before.split_at(self.registers.len());
+        for (reg, old) in self.registers.iter().zip(registers_before) {
+            let new = reg.value(chip8);
+            if new != *old {
+                return Some(StopReason::Register(*reg, *old, new));
+            }
+        }
+        for (condition, was_true) in self.conditions.iter().zip(conditions_before) {
+            if *was_true == 0 && condition.is_true(chip8) {
+                return Some(StopReason::Condition(condition.source.clone()));
+            }
+        }
+        None
+    }
+}
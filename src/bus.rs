@@ -0,0 +1,77 @@
+use crate::constants;
+
+// Kind of data access to the RAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// Data access to the RAM by an instruction
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub addr: usize,
+    pub kind: AccessKind,
+    // Value read or written
+    pub value: u8,
+}
+
+// Memory bus of the machine.
+// The CPU goes through read() and write() for every data access, which are logged
+// per instruction so that watchpoints and other tools can inspect them.
+// Direct access to `ram` is not instrumented, it is meant for debuggers and loaders.
+// The addresses wrap around the end of the RAM, as a ROM can compute any address.
+pub struct Bus {
+    // RAM memory: 4 kB
+    pub ram: [u8; constants::RAM_SIZE],
+    // Flags of the bytes written since they were last cleared
+    pub written: [bool; constants::RAM_SIZE],
+    // Data accesses of the current instruction
+    pub accesses: Vec<Access>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            ram: [0; constants::RAM_SIZE],
+            written: [false; constants::RAM_SIZE],
+            accesses: Vec::new(),
+        }
+    }
+
+    // Fetches the instruction at the given address, not logged as a data access
+    pub fn fetch(&self, addr: usize) -> u16 {
+        ((self.ram[addr % constants::RAM_SIZE] as u16) << 8)
+            | self.ram[(addr + 1) % constants::RAM_SIZE] as u16
+    }
+
+    // Reads a data byte
+    pub fn read(&mut self, addr: usize) -> u8 {
+        let addr = addr % constants::RAM_SIZE;
+        let value = self.ram[addr];
+        self.accesses.push(Access {
+            addr,
+            kind: AccessKind::Read,
+            value,
+        });
+        value
+    }
+
+    // Writes a data byte
+    pub fn write(&mut self, addr: usize, value: u8) {
+        let addr = addr % constants::RAM_SIZE;
+        self.ram[addr] = value;
+        self.written[addr] = true;
+        self.accesses.push(Access {
+            addr,
+            kind: AccessKind::Write,
+            value,
+        });
+    }
+}
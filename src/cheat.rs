@@ -0,0 +1,240 @@
+use crate::constants;
+use crate::expr;
+use crate::symbols::Symbols;
+
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// Cheat poking a RAM byte at the end of every frame, e.g. to freeze the lives
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cheat {
+    pub addr: usize,
+    pub value: u8,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    // Parses 'ADDR=VALUE', ADDR being an address or a label
+    pub fn parse(spec: &str, symbols: &Symbols) -> Result<Cheat, String> {
+        let (addr, value) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid cheat '{}', expected 'ADDR=VALUE'", spec))?;
+        let value = expr::parse_number(value)?;
+        if value > 0xFF {
+            return Err(format!("Cheat value out of a byte: '{}'", spec));
+        }
+        Ok(Cheat {
+            addr: symbols.parse_addr(addr)?,
+            value: value as u8,
+            enabled: true,
+        })
+    }
+}
+
+// Cheats of a ROM, saved per ROM SHA-1 in the data directory
+#[derive(Clone, Default)]
+pub struct Cheats {
+    pub list: Vec<Cheat>,
+}
+
+impl Cheats {
+    // Loads the cheats of the ROM with the given SHA-1, none if there are none
+    pub fn load(hash: &str) -> Result<Cheats, String> {
+        match cheats_path() {
+            Some(path) => Cheats::load_from(&path, hash),
+            None => Ok(Cheats::default()),
+        }
+    }
+
+    // Saves the cheats of the ROM with the given SHA-1, keeping those of the other ROMs
+    pub fn save(&self, hash: &str) -> Result<(), String> {
+        let path = cheats_path().ok_or("No data directory for the cheats")?;
+        self.save_to(&path, hash)
+    }
+
+    fn load_from(path: &Path, hash: &str) -> Result<Cheats, String> {
+        let mut cheats = Cheats::default();
+        let entries = read(path)?.remove(hash).unwrap_or_default();
+        for entry in entries.as_array().into_iter().flatten() {
+            let (Some(addr), Some(value)) = (
+                entry.get("addr").and_then(|a| a.as_u64()),
+                entry.get("value").and_then(|v| v.as_u64()),
+            ) else {
+                continue;
+            };
+            if addr as usize >= constants::RAM_SIZE || value > 0xFF {
+                continue;
+            }
+            cheats.list.push(Cheat {
+                addr: addr as usize,
+                value: value as u8,
+                enabled: entry
+                    .get("enabled")
+                    .and_then(|e| e.as_bool())
+                    .unwrap_or(true),
+            });
+        }
+        Ok(cheats)
+    }
+
+    // Refuses to save over an unreadable file, which holds the cheats of the other ROMs
+    fn save_to(&self, path: &Path, hash: &str) -> Result<(), String> {
+        let mut roms = read(path).map_err(|e| format!("{}, not saving the cheats", e))?;
+        let error = |e: std::io::Error| format!("Error writing '{}': {}", path.display(), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        if self.list.is_empty() {
+            roms.remove(hash);
+        } else {
+            let entries: Vec<Value> = self
+                .list
+                .iter()
+                .map(|c| json!({ "addr": c.addr, "value": c.value, "enabled": c.enabled }))
+                .collect();
+            roms.insert(hash.to_string(), Value::Array(entries));
+        }
+        fs::write(path, Value::Object(roms).to_string()).map_err(error)
+    }
+
+    // Adds the cheat, replacing the one at the same address
+    pub fn add(&mut self, cheat: Cheat) {
+        match self.list.iter_mut().find(|c| c.addr == cheat.addr) {
+            Some(existing) => *existing = cheat,
+            None => self.list.push(cheat),
+        }
+    }
+
+    // Enables all the cheats if some are disabled, or disables them all. Returns whether
+    // they are enabled.
+    pub fn toggle(&mut self) -> bool {
+        let enabled = self.list.iter().any(|c| !c.enabled);
+        for cheat in self.list.iter_mut() {
+            cheat.enabled = enabled;
+        }
+        enabled
+    }
+
+    // Pokes the values of the enabled cheats
+    pub fn apply(&self, ram: &mut [u8; constants::RAM_SIZE]) {
+        for cheat in self.list.iter().filter(|c| c.enabled) {
+            ram[cheat.addr] = cheat.value;
+        }
+    }
+}
+
+// Comparison of the RAM bytes with their values in the last snapshot of a search
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+// Memory search narrowing down the addresses of a variable, e.g. the lives or the
+// score. It starts with every address, and each step keeps the addresses whose byte
+// compares to the last snapshot as given, e.g. decreased after losing a life.
+pub struct Search {
+    snapshot: [u8; constants::RAM_SIZE],
+    candidates: Vec<usize>,
+}
+
+impl Search {
+    // Starts a search over the whole RAM
+    pub fn new(ram: &[u8; constants::RAM_SIZE]) -> Search {
+        Search {
+            snapshot: *ram,
+            candidates: (0..constants::RAM_SIZE).collect(),
+        }
+    }
+
+    // Keeps the addresses whose byte compares to the snapshot as given, and takes a new
+    // snapshot. Returns the number of addresses left.
+    pub fn filter(&mut self, ram: &[u8; constants::RAM_SIZE], compare: Compare) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            let (old, new) = (snapshot[addr], ram[addr]);
+            match compare {
+                Compare::Changed => new != old,
+                Compare::Unchanged => new == old,
+                Compare::Increased => new > old,
+                Compare::Decreased => new < old,
+            }
+        });
+        self.snapshot = *ram;
+        self.candidates.len()
+    }
+
+    // Addresses left
+    pub fn candidates(&self) -> &[usize] {
+        &self.candidates
+    }
+}
+
+// Reads the cheats file, the cheats by ROM SHA-1, none if there is no file
+fn read(path: &Path) -> Result<Map<String, Value>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Map::new()),
+        Err(e) => return Err(format!("Error reading '{}': {}", path.display(), e)),
+    };
+    match serde_json::from_str(&text) {
+        Ok(Value::Object(roms)) => Ok(roms),
+        Ok(_) => Err(format!(
+            "Invalid cheats '{}': not an object",
+            path.display()
+        )),
+        Err(e) => Err(format!("Invalid cheats '{}': {}", path.display(), e)),
+    }
+}
+
+fn cheats_path() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("rchip8").join("cheats.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cheats() {
+        let symbols = Symbols::default();
+        assert_eq!(
+            Cheat::parse("0x2F0=3", &symbols),
+            Ok(Cheat {
+                addr: 0x2F0,
+                value: 3,
+                enabled: true,
+            })
+        );
+        assert_eq!(
+            Cheat::parse(" 752 = 0xFF ", &symbols).map(|c| (c.addr, c.value)),
+            Ok((752, 0xFF))
+        );
+        for spec in ["0x2F0", "0x2F0=256", "0x1000=1", "lives=1", "0x2F0=x"] {
+            assert!(Cheat::parse(spec, &symbols).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn saves_the_cheats_of_each_rom() {
+        let path = std::env::temp_dir().join(format!("rchip8-cheats-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(Cheats::load_from(&path, "a").unwrap().list.is_empty());
+        let mut cheats = Cheats::default();
+        cheats.add(Cheat::parse("0x300=1", &Symbols::default()).unwrap());
+        cheats.save_to(&path, "a").unwrap();
+        Cheats::default().save_to(&path, "b").unwrap();
+        assert_eq!(Cheats::load_from(&path, "a").unwrap().list, cheats.list);
+
+        // A corrupt file is reported and kept
+        fs::write(&path, "{\"a\": [").unwrap();
+        assert!(Cheats::load_from(&path, "a").is_err());
+        assert!(cheats.save_to(&path, "b").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"a\": [");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,24 @@
+use crate::breakpoints::{Breakpoints, StopReason};
+use crate::bus::Bus;
+use crate::cheat::Cheats;
 use crate::constants;
+use crate::coverage::Coverage;
 use crate::debug;
-use crate::keyboard;
+use crate::opcode::{self, Op};
+use crate::profile::Profile;
+use crate::quirks::Quirks;
+#[cfg(feature = "script")]
+use crate::script::Script;
+use crate::symbols::Symbols;
+use crate::trace::Trace;
 
-use rand::random;
-use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
-use sdl2::EventPump;
-use std::process;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
 
 // Emulates the CHIP-8 machine
 pub struct Chip8 {
-    // RAM memory: 4 kB
-    pub ram: [u8; constants::RAM_SIZE],
+    // Memory bus with the RAM: 4 kB
+    pub bus: Bus,
     // Registers: 16 * 1 B
     pub registers: [u8; constants::N_REGISTERS],
     // Index register I: 16 b
@@ -32,26 +39,197 @@ pub struct Chip8 {
     pub display_update_flag: bool,
     // Flag: clear display
     pub display_clear_flag: bool,
-    // Flag: beep
-    pub beep_flag: bool,
+    // Sound on/off events with their time [ns], drained by the audio output
+    pub sound_events: Vec<(u128, bool)>,
+    // Keypad state, true if the key is pressed
+    pub keys: [bool; constants::N_KEYS],
+    // Breakpoints, watchpoints and conditions to stop at
+    pub breakpoints: Breakpoints,
+    // Reason why the machine stopped, taken by the caller
+    pub stop: Option<StopReason>,
+    // Labels of the ROM addresses
+    pub symbols: Symbols,
+    // Execution trace, if enabled
+    pub trace: Option<Trace>,
+    // ROM profiler, if enabled
+    pub profile: Option<Profile>,
+    // Code and data coverage map, if enabled
+    pub coverage: Option<Coverage>,
+    // Cheats poked at the end of every frame
+    pub cheats: Cheats,
+    // Automation script, if loaded
+    #[cfg(feature = "script")]
+    pub script: Option<Script>,
+
+    // Behavior differences between the interpreters
+    pub quirks: Quirks,
+    // Emulation speed in instructions per frame (60 Hz)
+    pub cycles_per_frame: u32,
+    // Number of instructions executed
+    pub instruction_count: u64,
+    // Number of frames (timer ticks) run
+    pub frame_count: u64,
+    // Random number generator of RND, seedable for reproducible runs. ChaCha12, the
+    // generator of rand's StdRng, whose position can be saved.
+    rng: ChaCha12Rng,
 
-    // Emulation speed in instruction time [ns]
-    instruction_time_ns: u128,
     // Flag: run in debug mode
     debug_mode: bool,
-    // Last timer time
+    // Whether the sound is currently on
+    sound_on: bool,
+    // Key pressed during FX0A, waiting for its release
+    key_wait: Option<u8>,
+    // Address of the breakpoint the machine stopped at, skipped when resuming
+    stopped_at: Option<usize>,
+    // Time [ns] run up to when the machine stopped, it runs again only past it
+    stopped_t: Option<u128>,
+    // Last timer time, start of the current frame
+    last_timer_t: u128,
+    // Number of instructions executed in the current frame
+    frame_cycles: u32,
+}
+
+// Snapshot of the machine state, to save and restore it
+#[derive(Clone)]
+pub struct State {
+    ram: [u8; constants::RAM_SIZE],
+    registers: [u8; constants::N_REGISTERS],
+    index: u16,
+    stack: [u16; constants::STACK_SIZE],
+    istack: usize,
+    pc: usize,
+    dt: u8,
+    st: u8,
+    display: [u8; constants::DISPLAY_LEN],
+    keys: [bool; constants::N_KEYS],
+    instruction_count: u64,
+    frame_count: u64,
+    rng: ChaCha12Rng,
+    sound_on: bool,
+    key_wait: Option<u8>,
     last_timer_t: u128,
-    // Last instruction time
-    last_instruction_t: u128,
+    frame_cycles: u32,
+}
+
+// Header of the serialized states: magic and format version
+const STATE_MAGIC: &[u8; 4] = b"RC8S";
+const STATE_VERSION: u8 = 1;
+
+impl State {
+    // Serializes the state, in little endian after the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.push(STATE_VERSION);
+        bytes.extend_from_slice(&self.ram);
+        bytes.extend_from_slice(&self.registers);
+        bytes.extend_from_slice(&self.index.to_le_bytes());
+        for addr in self.stack {
+            bytes.extend_from_slice(&addr.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.istack as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.pc as u32).to_le_bytes());
+        bytes.push(self.dt);
+        bytes.push(self.st);
+        bytes.extend_from_slice(&self.display);
+        bytes.extend(self.keys.map(|k| k as u8));
+        bytes.extend_from_slice(&self.instruction_count.to_le_bytes());
+        bytes.extend_from_slice(&self.frame_count.to_le_bytes());
+        bytes.extend_from_slice(&self.rng.get_seed());
+        bytes.extend_from_slice(&self.rng.get_stream().to_le_bytes());
+        bytes.extend_from_slice(&self.rng.get_word_pos().to_le_bytes());
+        bytes.push(self.sound_on as u8);
+        bytes.push(self.key_wait.is_some() as u8);
+        bytes.push(self.key_wait.unwrap_or(0));
+        bytes.extend_from_slice(&self.last_timer_t.to_le_bytes());
+        bytes.extend_from_slice(&self.frame_cycles.to_le_bytes());
+        bytes
+    }
+
+    // Deserializes a state serialized by to_bytes()
+    pub fn from_bytes(bytes: &[u8]) -> Result<State, String> {
+        let mut reader = Reader(bytes);
+        if reader.take()? != *STATE_MAGIC {
+            return Err("Not a saved state".to_string());
+        }
+        let [version] = reader.take()?;
+        if version != STATE_VERSION {
+            return Err(format!("Unsupported saved state version {}", version));
+        }
+        let ram = reader.take()?;
+        let registers = reader.take()?;
+        let index = u16::from_le_bytes(reader.take()?);
+        let mut stack = [0; constants::STACK_SIZE];
+        for addr in stack.iter_mut() {
+            *addr = u16::from_le_bytes(reader.take()?);
+        }
+        let istack = u32::from_le_bytes(reader.take()?) as usize;
+        let pc = u32::from_le_bytes(reader.take()?) as usize;
+        if istack >= constants::STACK_SIZE || pc >= constants::RAM_SIZE {
+            return Err("Invalid saved state".to_string());
+        }
+        let [dt, st] = reader.take()?;
+        let display = reader.take()?;
+        let keys = reader.take::<{ constants::N_KEYS }>()?.map(|k| k != 0);
+        let instruction_count = u64::from_le_bytes(reader.take()?);
+        let frame_count = u64::from_le_bytes(reader.take()?);
+        let mut rng = ChaCha12Rng::from_seed(reader.take()?);
+        rng.set_stream(u64::from_le_bytes(reader.take()?));
+        rng.set_word_pos(u128::from_le_bytes(reader.take()?));
+        let [sound_on, waiting, key] = reader.take()?;
+        let last_timer_t = u128::from_le_bytes(reader.take()?);
+        let frame_cycles = u32::from_le_bytes(reader.take()?);
+        if !reader.0.is_empty() {
+            return Err("Invalid saved state size".to_string());
+        }
+        Ok(State {
+            ram,
+            registers,
+            index,
+            stack,
+            istack,
+            pc,
+            dt,
+            st,
+            display,
+            keys,
+            instruction_count,
+            frame_count,
+            rng,
+            sound_on: sound_on != 0,
+            key_wait: (waiting != 0).then_some(key),
+            last_timer_t,
+            frame_cycles,
+        })
+    }
+}
+
+// Reader of the fields of a serialized state
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.0.len() < N {
+            return Err("Truncated saved state".to_string());
+        }
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(field.try_into().unwrap())
+    }
+}
+
+// Returns the cycles per frame, rounded, that run the given instructions per second
+pub fn cycles_per_frame(ips: u32) -> u32 {
+    ((ips as f64 / constants::FRAME_RATE as f64).round() as u32).max(1)
 }
 
 impl Chip8 {
     // Initializes the machine with the given ROM data [Vec<u8>] and start time [ns]
-    pub fn new(rom: Vec<u8>, start_t: u128, instruction_time_ns: u128, debug_mode: bool) -> Self {
+    pub fn new(rom: Vec<u8>, start_t: u128, cycles_per_frame: u32, debug_mode: bool) -> Self {
         // Initialize the machine
 
-        // RAM memory: 4 kB
-        let mut ram: [u8; constants::RAM_SIZE] = [0; constants::RAM_SIZE];
+        // Memory bus with the RAM: 4 kB
+        let mut bus = Bus::new();
         // Registers: 16 * 1 B
         let registers: [u8; constants::N_REGISTERS] = [0; constants::N_REGISTERS];
         // Index register I: 16 b
@@ -88,24 +266,14 @@ impl Chip8 {
             0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
-        ram[..80].copy_from_slice(&fonts);
-        println!(
-            "Fonts loaded into memory: {} b [0x{:04x}..0x{:04x}]",
-            80, 0, 80
-        );
-        // Copy ROM to memory
-        let bytes = rom.len();
+        bus.ram[..80].copy_from_slice(&fonts);
+        // Copy ROM to memory, truncated to the available memory
+        let bytes = rom.len().min(constants::RAM_SIZE - constants::PROGRAM_LOC);
         let ppos = constants::PROGRAM_LOC + bytes;
-        ram[constants::PROGRAM_LOC..ppos].copy_from_slice(&rom[0..bytes]);
-        println!(
-            "ROM loaded into memory: {} b [0x{:04x}..0x{:04x}]",
-            bytes,
-            constants::PROGRAM_LOC,
-            ppos
-        );
+        bus.ram[constants::PROGRAM_LOC..ppos].copy_from_slice(&rom[0..bytes]);
 
         Chip8 {
-            ram,
+            bus,
             registers,
             index,
             stack,
@@ -116,322 +284,683 @@ impl Chip8 {
             display,
             display_update_flag: false,
             display_clear_flag: false,
-            beep_flag: false,
-            instruction_time_ns,
+            sound_events: Vec::new(),
+            keys: [false; constants::N_KEYS],
+            breakpoints: Breakpoints::default(),
+            stop: None,
+            symbols: Symbols::default(),
+            trace: None,
+            profile: None,
+            coverage: None,
+            cheats: Cheats::default(),
+            #[cfg(feature = "script")]
+            script: None,
+            quirks: Quirks::default(),
+            cycles_per_frame,
+            instruction_count: 0,
+            frame_count: 0,
+            rng: ChaCha12Rng::from_entropy(),
             debug_mode,
+            sound_on: false,
+            key_wait: None,
+            stopped_at: None,
+            stopped_t: None,
             last_timer_t: start_t,
-            last_instruction_t: start_t,
+            frame_cycles: 0,
         }
     }
 
-    // Runs a CPU cycle with the given current time t [ns]
-    pub fn cycle(&mut self, t: u128, event_pump: &mut EventPump) {
+    // Restarts the machine with the given ROM at the start time [ns], keeping the speed,
+    // the quirks, the debugging tools and the cheats
+    pub fn reset(&mut self, rom: Vec<u8>, start_t: u128) {
+        let mut chip8 = Chip8::new(rom, start_t, self.cycles_per_frame, self.debug_mode);
+        chip8.quirks = self.quirks;
+        chip8.breakpoints = std::mem::take(&mut self.breakpoints);
+        chip8.symbols = std::mem::take(&mut self.symbols);
+        chip8.trace = self.trace.take();
+        chip8.profile = self.profile.take();
+        chip8.coverage = self.coverage.take();
+        chip8.cheats = std::mem::take(&mut self.cheats);
+        #[cfg(feature = "script")]
+        {
+            chip8.script = self.script.take();
+        }
+        if let Some(profile) = chip8.profile.as_mut() {
+            profile.restart();
+        }
+        *self = chip8;
+    }
+
+    // Runs the machine up to the given current time t [ns]
+    pub fn cycle(&mut self, t: u128) {
         self.display_update_flag = false;
         self.display_clear_flag = false;
-        // TIMERS
-        // Decrement delay_timer and sound_timer 60 times per second
-        // if their value is > 0
-        if t - self.last_timer_t > 16_666_666 {
-            if self.dt > 0 {
-                self.dt -= 1;
-            }
-            if self.st > 0 {
-                self.st -= 1;
-                self.beep_flag = self.st > 0;
+
+        // Stay stopped until the time moves on, e.g. when paused at the stop
+        if self.stopped_t.is_some_and(|stopped_t| t <= stopped_t) {
+            return;
+        }
+        self.stopped_t = None;
+
+        // Too far behind (e.g. paused in debug mode), skip the missed frames
+        if t.saturating_sub(self.last_timer_t)
+            > constants::TIMER_PERIOD_NS * constants::TIMER_MAX_CATCHUP
+        {
+            self.last_timer_t = t - constants::TIMER_PERIOD_NS;
+        }
+
+        // Run the instructions and timer ticks due, in order, each at its exact time.
+        // The instructions of a frame are evenly spread over the frame period.
+        loop {
+            if self.frame_cycles < self.cycles_per_frame {
+                let instruction_t = self.instruction_t();
+                if instruction_t > t {
+                    break;
+                }
+                // Stop before an instruction with a breakpoint, unless resuming from it
+                if self.stopped_at != Some(self.pc) && self.breakpoints.check_pc(self, self.pc) {
+                    self.stopped_at = Some(self.pc);
+                    if self.stop_at(StopReason::Breakpoint(self.pc)) {
+                        self.stopped_t = Some(t);
+                        break;
+                    }
+                }
+                if self.breakpoints.watching() {
+                    // Stop after an instruction that triggers a watchpoint or condition
+                    let before = self.breakpoints.snapshot(self);
+                    self.run_instruction(instruction_t);
+                    if let Some(reason) = self.breakpoints.check_after(self, &before) {
+                        if self.stop_at(reason) {
+                            self.stopped_t = Some(t);
+                            break;
+                        }
+                    }
+                } else {
+                    self.run_instruction(instruction_t);
+                }
+            } else {
+                if self.last_timer_t + constants::TIMER_PERIOD_NS > t {
+                    break;
+                }
+                self.next_frame();
             }
-            self.last_timer_t = t;
         }
+    }
+
+    // Runs the rest of the current frame and ticks the timers, ignoring the time and
+    // the breakpoints, to run as fast as possible without a display
+    pub fn run_frame(&mut self) {
+        while self.frame_cycles < self.cycles_per_frame {
+            let instruction_t = self.instruction_t();
+            self.run_instruction(instruction_t);
+        }
+        self.next_frame();
+    }
+
+    // Returns the emulated time [ns] of the start of the current frame
+    pub fn time(&self) -> u128 {
+        self.last_timer_t
+    }
+
+    // Seeds the random number generator
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = ChaCha12Rng::seed_from_u64(seed);
+    }
+
+    // Returns a copy of the machine state
+    pub fn save_state(&self) -> State {
+        State {
+            ram: self.bus.ram,
+            registers: self.registers,
+            index: self.index,
+            stack: self.stack,
+            istack: self.istack,
+            pc: self.pc,
+            dt: self.dt,
+            st: self.st,
+            display: self.display,
+            keys: self.keys,
+            instruction_count: self.instruction_count,
+            frame_count: self.frame_count,
+            rng: self.rng.clone(),
+            sound_on: self.sound_on,
+            key_wait: self.key_wait,
+            last_timer_t: self.last_timer_t,
+            frame_cycles: self.frame_cycles,
+        }
+    }
+
+    // Restores a machine state, keeping the speed, the quirks and the debugging tools
+    pub fn load_state(&mut self, state: &State) {
+        self.bus.ram = state.ram;
+        self.registers = state.registers;
+        self.index = state.index;
+        self.stack = state.stack;
+        self.istack = state.istack;
+        self.pc = state.pc;
+        self.dt = state.dt;
+        self.st = state.st;
+        self.display = state.display;
+        self.keys = state.keys;
+        self.instruction_count = state.instruction_count;
+        self.frame_count = state.frame_count;
+        self.rng = state.rng.clone();
+        self.sound_on = state.sound_on;
+        self.key_wait = state.key_wait;
+        self.last_timer_t = state.last_timer_t;
+        self.frame_cycles = state.frame_cycles;
+        self.stopped_at = None;
+        self.stopped_t = None;
+        self.display_update_flag = true;
+    }
+
+    // Runs exactly one instruction, ignoring breakpoints
+    pub fn step(&mut self) {
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.next_frame();
+        }
+        let instruction_t = self.instruction_t();
+        self.run_instruction(instruction_t);
+    }
+
+    // Returns the time [ns] of the next instruction in the current frame
+    fn instruction_t(&self) -> u128 {
+        self.last_timer_t
+            + constants::TIMER_PERIOD_NS * self.frame_cycles as u128 / self.cycles_per_frame as u128
+    }
+
+    // Runs the next instruction of the current frame at time t [ns]
+    fn run_instruction(&mut self, t: u128) {
+        let cycle = self.instruction_count;
+        self.frame_cycles += 1;
+        self.instruction_count += 1;
+        self.stopped_at = None;
+        self.bus.accesses.clear();
+
+        #[cfg(feature = "script")]
+        if let Some(mut script) = self.script.take() {
+            script.before_instruction(self);
+            self.script = Some(script);
+        }
+
+        // Instruction for the debugging tools
+        let (pc, before) = (self.pc, self.registers);
+        let instr = self.bus.fetch(pc);
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(pc, instr);
+        }
+
+        self.execute(t);
 
-        // INTERPRET
-        if t - self.last_instruction_t > self.instruction_time_ns {
-            if self.pc >= constants::RAM_SIZE {
-                panic!("Reached the end!");
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(pc, instr, &self.bus.accesses);
+        }
+        if let Some(mut trace) = self.trace.take() {
+            if trace.traces(pc) {
+                trace.record(cycle, pc, instr, &before, self);
             }
-            // RUN INSTRUCTION
-            let instr: u16 = ((self.ram[self.pc] as u16) << 8) | self.ram[self.pc + 1] as u16;
-            self.pc += 2;
-
-            // INSTRUCTION: 0xIXYN with 0x000N, 0x00NN, 0x0NNN
-            let code = instr & 0xF000;
-            let x = ((instr & 0x0F00) >> 8) as usize;
-            let y = ((instr & 0x00F0) >> 4) as usize;
-            let n = instr & 0x000F;
-            let nn = instr & 0x00FF;
-            let nnn = instr & 0x0FFF;
-
-            if self.debug_mode {
-                debug::debug(
-                    self.pc,
-                    instr,
-                    code,
-                    x,
-                    y,
-                    n,
-                    nn,
-                    nnn,
-                    self.registers,
-                    self.index,
-                );
+            self.trace = Some(trace);
+        }
+        #[cfg(feature = "script")]
+        if let Some(mut script) = self.script.take() {
+            script.after_instruction(self);
+            self.script = Some(script);
+        }
+    }
+
+    // Stops the machine for the given reason, writing the trace history, unless the
+    // script keeps it running. Returns whether it stopped.
+    fn stop_at(&mut self, reason: StopReason) -> bool {
+        #[cfg(feature = "script")]
+        if let Some(mut script) = self.script.take() {
+            let description = reason.describe(&self.symbols);
+            let keep_running = script.stopped(self, description);
+            self.script = Some(script);
+            if keep_running {
+                return false;
             }
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.dump();
+        }
+        self.stop = Some(reason);
+        true
+    }
 
-            match code {
-                0x0000 => {
-                    match n {
-                        // 00E0 - CLS
-                        0 => {
-                            self.display.iter_mut().for_each(|m| *m = 0);
-                            self.display_clear_flag = true;
-                        }
-                        // 00EE - RET
-                        0x0E => {
-                            self.pc = self.stack[self.istack] as usize;
-                            if self.istack > 0 {
-                                self.istack -= 1;
-                            }
-                        }
-                        // Default
-                        _ => (),
-                    }
+    // Starts the next frame, ticking the timers
+    fn next_frame(&mut self) {
+        self.last_timer_t += constants::TIMER_PERIOD_NS;
+        self.frame_cycles = 0;
+        self.frame_count += 1;
+        self.tick_timers(self.last_timer_t);
+        self.cheats.apply(&mut self.bus.ram);
+        #[cfg(feature = "script")]
+        if let Some(mut script) = self.script.take() {
+            script.frame(self);
+            self.script = Some(script);
+        }
+    }
+
+    // TIMERS
+    // Decrements delay_timer and sound_timer if their value is > 0.
+    // Called 60 times per second, at time t [ns]
+    fn tick_timers(&mut self, t: u128) {
+        if self.dt > 0 {
+            self.dt -= 1;
+        }
+        if self.st > 0 {
+            self.st -= 1;
+        }
+        self.update_sound(t);
+    }
+
+    // INTERPRET
+    // Runs a single instruction at time t [ns]
+    fn execute(&mut self, t: u128) {
+        // RUN INSTRUCTION
+        let instr: u16 = self.bus.fetch(self.pc);
+        self.pc += 2;
+
+        if self.debug_mode {
+            debug::debug(self.pc, instr, self.registers, self.index);
+        }
+
+        match opcode::decode(instr) {
+            // 00E0 - CLS
+            Op::Cls => {
+                self.display.iter_mut().for_each(|m| *m = 0);
+                self.display_clear_flag = true;
+            }
+            // 00EE - RET
+            Op::Ret => {
+                self.pc = self.stack[self.istack] as usize;
+                if self.istack > 0 {
+                    self.istack -= 1;
                 }
-                // 1NNN - JMP
-                0x1000 => self.pc = nnn as usize,
-                // 2NNN - CALL NNN
-                0x2000 => {
-                    self.istack += 1;
-                    self.stack[self.istack] = self.pc as u16;
-                    self.pc = nnn as usize;
+            }
+            // 1NNN - JMP
+            Op::Jmp(nnn) => self.pc = nnn as usize,
+            // 2NNN - CALL NNN
+            // The stack wraps around when it is full
+            Op::Call(nnn) => {
+                self.istack = (self.istack + 1) % constants::STACK_SIZE;
+                self.stack[self.istack] = self.pc as u16;
+                self.pc = nnn as usize;
+            }
+            // 3XNN - SE VX, NN
+            Op::SeByte(x, nn) => {
+                if self.registers[x] == nn {
+                    self.pc += 2;
                 }
-                // 3XNN - SE VX, NN
-                0x3000 => {
-                    if self.registers[x] as u16 == nn {
-                        self.pc += 2;
-                    }
+            }
+            // 4XNN - SNE VX, NN
+            Op::SneByte(x, nn) => {
+                if self.registers[x] != nn {
+                    self.pc += 2;
                 }
-                // 4XNN - SNE VX, NN
-                0x4000 => {
-                    if self.registers[x] as u16 != nn {
-                        self.pc += 2;
-                    }
+            }
+            // 5XY0 - SE VX, VY
+            Op::SeReg(x, y) => {
+                if self.registers[x] == self.registers[y] {
+                    self.pc += 2;
                 }
-                // 5XY0 - SE VX, VY
-                0x5000 => {
-                    if self.registers[x] == self.registers[y] {
-                        self.pc += 2;
-                    }
+            }
+            // 6XNN - LD  VX, NN
+            Op::LdByte(x, nn) => self.registers[x] = nn,
+            // 7XNN - ADD  VX, NN
+            Op::AddByte(x, nn) => self.registers[x] = self.registers[x].wrapping_add(nn),
+            // 8XY0 - LD VX, VY
+            Op::LdReg(x, y) => self.registers[x] = self.registers[y],
+            // 8XY1 - OR VX, VY
+            Op::Or(x, y) => {
+                self.registers[x] = self.registers[x] | self.registers[y];
+                self.logic_quirk();
+            }
+            // 8XY2 - AND VX, VY
+            Op::And(x, y) => {
+                self.registers[x] = self.registers[x] & self.registers[y];
+                self.logic_quirk();
+            }
+            // 8XY3 - XOR VX, VY
+            Op::Xor(x, y) => {
+                self.registers[x] = self.registers[x] ^ self.registers[y];
+                self.logic_quirk();
+            }
+            // 8XY4 - ADD VX, VY
+            Op::AddReg(x, y) => {
+                let res = self.registers[x] as usize + self.registers[y] as usize;
+                if res > 255 {
+                    // Carry to VF
+                    self.registers[0x0F] = 1;
+                } else {
+                    self.registers[0x0F] = 0;
                 }
-                // 6XNN - LD  VX, NN
-                0x6000 => self.registers[x] = nn as u8,
-                // 7XNN - ADD  VX, NN
-                0x7000 => self.registers[x] = (self.registers[x] as u16 + nn) as u8,
-                0x8000 => {
-                    match n {
-                        // 8XY0 - LD VX, VY
-                        0x00 => self.registers[x] = self.registers[y],
-                        // 8XY1 - OR VX, VY
-                        0x01 => self.registers[x] = self.registers[x] | self.registers[y],
-                        // 8XY2 - AND VX, VY
-                        0x02 => self.registers[x] = self.registers[x] & self.registers[y],
-                        // 8XY3 - XOR VX, VY
-                        0x03 => self.registers[x] = self.registers[x] ^ self.registers[y],
-                        // 8XY4 - ADD VX, VY
-                        0x04 => {
-                            let res = self.registers[x] as usize + self.registers[y] as usize;
-                            if res > 255 {
-                                // Carry to VF
-                                self.registers[0x0F] = 1;
-                            } else {
-                                self.registers[0x0F] = 0;
-                            }
-                            self.registers[x] = res as u8;
-                        }
-                        // 8XY5 - SUB VX, VY
-                        0x05 => {
-                            self.registers[0x0F] = if self.registers[x] > self.registers[y] {
-                                // Carry to VF
-                                1
-                            } else {
-                                0
-                            };
-                            self.registers[x] =
-                                (self.registers[x] as i32 - self.registers[y] as i32) as u8;
-                        }
-                        // 8XY6 - SHR VX {, VY}
-                        0x06 => {
-                            self.registers[0x0F] = self.registers[x] & 0x01;
-                            self.registers[x] /= 2;
-                        }
-                        // 8XY7 - SUBN VX, VY
-                        0x07 => {
-                            self.registers[0x0F] = if self.registers[y] > self.registers[x] {
-                                1
-                            } else {
-                                0
-                            };
-                            self.registers[x] = self.registers[y] - self.registers[x];
-                        }
-                        // 8XYE - SHL VX {, VY}
-                        0x0E => {
-                            self.registers[0x0F] = self.registers[x] & 0x80;
-                            self.registers[x] = (self.registers[x] as u16 * 2) as u8;
-                        }
-                        // Default
-                        _ => (),
-                    }
+                self.registers[x] = res as u8;
+            }
+            // 8XY5 - SUB VX, VY
+            Op::Sub(x, y) => {
+                self.registers[0x0F] = if self.registers[x] > self.registers[y] {
+                    // Carry to VF
+                    1
+                } else {
+                    0
+                };
+                self.registers[x] = (self.registers[x] as i32 - self.registers[y] as i32) as u8;
+            }
+            // 8XY6 - SHR VX {, VY}
+            Op::Shr(x, y) => {
+                if !self.quirks.shift {
+                    self.registers[x] = self.registers[y];
                 }
-                // 0x9XY0 - SNE VX, VY  (skip next instruction)
-                0x9000 => {
-                    if self.registers[x] != self.registers[y] {
-                        self.pc += 2;
-                    }
+                self.registers[0x0F] = self.registers[x] & 0x01;
+                self.registers[x] /= 2;
+            }
+            // 8XY7 - SUBN VX, VY
+            Op::Subn(x, y) => {
+                self.registers[0x0F] = if self.registers[y] > self.registers[x] {
+                    1
+                } else {
+                    0
+                };
+                self.registers[x] = self.registers[y].wrapping_sub(self.registers[x]);
+            }
+            // 8XYE - SHL VX {, VY}
+            Op::Shl(x, y) => {
+                if !self.quirks.shift {
+                    self.registers[x] = self.registers[y];
                 }
-                // ANNN - LD  I, NNN
-                0xA000 => self.index = nnn,
-                // BNNN - JMP  V0, NNN  (jump to nnn + V0)
-                0xB000 => self.pc = nnn as usize + self.registers[0] as usize,
-                // CXNN - RND VX, NN  (set VX = RANDOM_BYTE AND NN)
-                0xC000 => self.registers[x] = nn as u8 & random::<u8>(),
-                // DXYN - DRW  VX, VY, N
-                0xD000 => {
-                    self.registers[0x0F] = 0;
-                    let xpos: usize = self.registers[x] as usize % constants::DISPLAY_WIDTH;
-                    let ypos: usize = self.registers[y] as usize % constants::DISPLAY_HEIGHT;
-                    for row in 0..n {
-                        // Fetch bits
-                        let bits: u8 = self.ram[(self.index + row) as usize];
-                        // Current Y
-                        let cy = (ypos + row as usize) % constants::DISPLAY_HEIGHT;
-                        // Loop over bits
-                        for col in 0..8_usize {
-                            // Current X
-                            let cx = (xpos + col) % constants::DISPLAY_WIDTH;
-                            let current_color = self.display[cy * constants::DISPLAY_WIDTH + cx];
-                            let mask: u8 = 0x01 << 7 - col;
-                            let color = bits & mask;
-                            // XOR
-                            // 0 0 -> 0
-                            // 0 1 -> 1
-                            // 1 0 -> 1
-                            // 1 1 -> 0
-                            if color > 0 {
-                                // color is on
-                                if current_color > 0 {
-                                    // current color is on
-                                    self.display[cy * constants::DISPLAY_WIDTH + cx] = 0;
-                                    self.registers[0x0F] = 1;
-                                } else {
-                                    // current color is off
-                                    self.display[cy * constants::DISPLAY_WIDTH + cx] = 1;
-                                }
+                self.registers[0x0F] = self.registers[x] & 0x80;
+                self.registers[x] = (self.registers[x] as u16 * 2) as u8;
+            }
+            // 0x9XY0 - SNE VX, VY  (skip next instruction)
+            Op::SneReg(x, y) => {
+                if self.registers[x] != self.registers[y] {
+                    self.pc += 2;
+                }
+            }
+            // ANNN - LD  I, NNN
+            Op::LdI(nnn) => self.index = nnn,
+            // BNNN - JMP  V0, NNN  (jump to nnn + V0)
+            // With the jump quirk: BXNN - JMP VX, XNN
+            Op::JmpV0(x, nnn) => {
+                let reg = if self.quirks.jump { x } else { 0 };
+                self.pc = nnn as usize + self.registers[reg] as usize;
+            }
+            // CXNN - RND VX, NN  (set VX = RANDOM_BYTE AND NN)
+            Op::Rnd(x, nn) => self.registers[x] = nn & self.rng.gen::<u8>(),
+            // DXYN - DRW  VX, VY, N
+            Op::Drw(x, y, n) => {
+                self.registers[0x0F] = 0;
+                let xpos: usize = self.registers[x] as usize % constants::DISPLAY_WIDTH;
+                let ypos: usize = self.registers[y] as usize % constants::DISPLAY_HEIGHT;
+                for row in 0..n {
+                    // Fetch bits
+                    let bits: u8 = self.bus.read(self.index as usize + row as usize);
+                    // Current Y
+                    let cy = (ypos + row as usize) % constants::DISPLAY_HEIGHT;
+                    // Loop over bits
+                    for col in 0..8_usize {
+                        // Current X
+                        let cx = (xpos + col) % constants::DISPLAY_WIDTH;
+                        let current_color = self.display[cy * constants::DISPLAY_WIDTH + cx];
+                        let mask: u8 = 0x01 << 7 - col;
+                        let color = bits & mask;
+                        // XOR
+                        // 0 0 -> 0
+                        // 0 1 -> 1
+                        // 1 0 -> 1
+                        // 1 1 -> 0
+                        if color > 0 {
+                            // color is on
+                            if current_color > 0 {
+                                // current color is on
+                                self.display[cy * constants::DISPLAY_WIDTH + cx] = 0;
+                                self.registers[0x0F] = 1;
                             } else {
-                                // Bit is off
-                                // Do nothing
-                            }
-                            if cx == constants::DISPLAY_WIDTH - 1 {
-                                // Reached the right edge
-                                break;
+                                // current color is off
+                                self.display[cy * constants::DISPLAY_WIDTH + cx] = 1;
                             }
+                        } else {
+                            // Bit is off
+                            // Do nothing
                         }
-                        if cy == constants::DISPLAY_HEIGHT - 1 {
-                            // Reached the bottom edge
+                        if cx == constants::DISPLAY_WIDTH - 1 && !self.quirks.wrap {
+                            // Reached the right edge
                             break;
                         }
                     }
-                    self.display_update_flag = true;
-                }
-                0xE000 => {
-                    match nn {
-                        // EX9E - SKP VX  (skip next instr if key with val VX is pressed)
-                        0x9E => {
-                            if event_pump
-                                .keyboard_state()
-                                .is_scancode_pressed(keyboard::map(self.registers[x]))
-                            {
-                                self.pc += 2;
-                            }
-                        }
-                        // EXA1 - SKNP VX  (skip next instr if key with val VX is not pressed)
-                        0xA1 => {
-                            if !event_pump
-                                .keyboard_state()
-                                .is_scancode_pressed(keyboard::map(self.registers[x]))
-                            {
-                                self.pc += 2;
-                            }
-                        }
-                        _ => (),
+                    if cy == constants::DISPLAY_HEIGHT - 1 && !self.quirks.wrap {
+                        // Reached the bottom edge
+                        break;
                     }
                 }
-                0xF000 => {
-                    match nn {
-                        // FX07 - LD VX, DT  (set VX = delay timer)
-                        0x07 => self.registers[x] = self.dt,
-                        // FX0A - LD VX, N  (wait for key press, store key value in VX)
-                        0x0A => {
-                            let keycode: u8 = loop {
-                                let event = event_pump.wait_event();
-                                let code = match event {
-                                    Event::KeyDown {
-                                        keycode: Some(code),
-                                        ..
-                                    } => Some(code),
-                                    _ => None,
-                                };
-                                if code.is_some() {
-                                    let sc = Scancode::from_keycode(code.unwrap()).unwrap();
-                                    if sc == Scancode::Escape || sc == Scancode::CapsLock {
-                                        // Terminate
-                                        println!("Bye!");
-                                        process::exit(0);
-                                    }
-                                    let c = keyboard::unmap(sc);
-                                    if c.is_some() {
-                                        break c.unwrap();
-                                    }
-                                }
-                            };
-                            self.registers[x] = keycode;
-                        }
-                        // FX15 - LD DT, VX  (set delay timer = VX)
-                        0x15 => self.dt = self.registers[x],
-                        // FX18 - LD ST, VX  (set sound timer = VX)
-                        0x18 => self.st = self.registers[x],
-                        // FX1E - ADD I, VX
-                        0x1E => self.index = self.index + self.registers[x] as u16,
-                        // FX29 - LD F, VX  (set I to location of sprite for digit VX)
-                        0x29 => self.index = self.registers[x] as u16 * 0x05,
-                        // FX33 - LD B, VX  (store BCD representation of VX in I, I+1 and I+2)
-                        0x33 => {
-                            let num = self.registers[x];
-                            let h = num / 100;
-                            let t = (num - h * 100) / 10;
-                            let o = num - h * 100 - t * 10;
-                            let i = self.index as usize;
-                            self.ram[i] = h;
-                            self.ram[i + 1] = t;
-                            self.ram[i + 2] = o;
-                        }
-                        // FX55 - LD [I], VX  (set memory starting at I to values in V0 to VX)
-                        0x55 => {
-                            let n: usize = x;
-                            for reg in 0..n + 1 {
-                                self.ram[self.index as usize + reg] = self.registers[reg];
-                            }
+                self.display_update_flag = true;
+                // Wait for the next frame
+                if self.quirks.vblank {
+                    self.frame_cycles = self.cycles_per_frame;
+                }
+            }
+            // EX9E - SKP VX  (skip next instr if key with val VX is pressed)
+            Op::Skp(x) => {
+                if self.keys[self.registers[x] as usize & 0x0F] {
+                    self.pc += 2;
+                }
+            }
+            // EXA1 - SKNP VX  (skip next instr if key with val VX is not pressed)
+            Op::Sknp(x) => {
+                if !self.keys[self.registers[x] as usize & 0x0F] {
+                    self.pc += 2;
+                }
+            }
+            // FX07 - LD VX, DT  (set VX = delay timer)
+            Op::LdVxDt(x) => self.registers[x] = self.dt,
+            // FX0A - LD VX, N  (wait for key press and release, store key value in VX)
+            Op::LdVxK(x) => {
+                match self.key_wait {
+                    // Wait for the release of the pressed key
+                    Some(key) => {
+                        if self.keys[key as usize] {
+                            self.pc -= 2;
+                        } else {
+                            self.registers[x] = key;
+                            self.key_wait = None;
                         }
-                        // FX65 - LD VX, [I]  (set registers V0 to VX to memory starting at I)
-                        0x65 => {
-                            let n: usize = x;
-                            for reg in 0..n + 1 {
-                                self.registers[reg] = self.ram[self.index as usize + reg];
-                            }
+                    }
+                    // Wait for a key press
+                    None => {
+                        if let Some(key) = self.keys.iter().position(|k| *k) {
+                            self.key_wait = Some(key as u8);
                         }
-                        _ => (),
+                        self.pc -= 2;
                     }
                 }
-                // Default
-                _ => (),
-            };
+            }
+            // FX15 - LD DT, VX  (set delay timer = VX)
+            Op::LdDtVx(x) => self.dt = self.registers[x],
+            // FX18 - LD ST, VX  (set sound timer = VX)
+            Op::LdStVx(x) => {
+                self.st = self.registers[x];
+                self.update_sound(t);
+            }
+            // FX1E - ADD I, VX
+            Op::AddI(x) => self.index = self.index.wrapping_add(self.registers[x] as u16),
+            // FX29 - LD F, VX  (set I to location of sprite for digit VX)
+            Op::LdF(x) => self.index = self.registers[x] as u16 * 0x05,
+            // FX33 - LD B, VX  (store BCD representation of VX in I, I+1 and I+2)
+            Op::LdB(x) => {
+                let num = self.registers[x];
+                let h = num / 100;
+                let t = (num - h * 100) / 10;
+                let o = num - h * 100 - t * 10;
+                let i = self.index as usize;
+                self.bus.write(i, h);
+                self.bus.write(i + 1, t);
+                self.bus.write(i + 2, o);
+            }
+            // FX55 - LD [I], VX  (set memory starting at I to values in V0 to VX)
+            Op::Store(x) => {
+                for reg in 0..x + 1 {
+                    self.bus
+                        .write(self.index as usize + reg, self.registers[reg]);
+                }
+                self.memory_quirk(x);
+            }
+            // FX65 - LD VX, [I]  (set registers V0 to VX to memory starting at I)
+            Op::Load(x) => {
+                for reg in 0..x + 1 {
+                    self.registers[reg] = self.bus.read(self.index as usize + reg);
+                }
+                self.memory_quirk(x);
+            }
+            // 0NNN - SYS NNN, and unknown instructions
+            Op::Sys(_) | Op::Data(_) => (),
+        };
+        // The PC wraps around the end of the RAM, e.g. after a jump to NNN + V0
+        self.pc %= constants::RAM_SIZE;
+    }
+
+    // Resets VF after the logic instructions, with the logic quirk
+    fn logic_quirk(&mut self) {
+        if self.quirks.logic {
+            self.registers[0x0F] = 0;
+        }
+    }
+
+    // Increments I after FX55/FX65, unless the quirks leave it unchanged
+    fn memory_quirk(&mut self, x: usize) {
+        if self.quirks.memory_leave_i_unchanged {
+            return;
+        }
+        let increment = if self.quirks.memory_increment_by_x {
+            x
+        } else {
+            x + 1
+        };
+        self.index = self.index.wrapping_add(increment as u16);
+    }
+
+    // Records a sound event at time t [ns] if the sound timer turned the sound on or off
+    fn update_sound(&mut self, t: u128) {
+        let on = self.st > 0;
+        if on != self.sound_on {
+            self.sound_on = on;
+            self.sound_events.push((t, on));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            self.last_instruction_t = t;
+    // Runs the ROM for a number of frames with the coverage on, to also exercise the tools
+    fn run(rom: &[u8], frames: u32) -> Chip8 {
+        let mut chip8 = Chip8::new(rom.to_vec(), 0, 50, false);
+        chip8.coverage = Some(Coverage::new(rom.len()));
+        for _ in 0..frames {
+            chip8.run_frame();
         }
+        chip8
+    }
+
+    #[test]
+    fn stays_at_a_breakpoint_until_the_time_moves_on() {
+        // ADD V0, 1 looping, with a breakpoint on the second ADD
+        let rom = [0x70, 0x01, 0x70, 0x01, 0x12, 0x00];
+        let mut chip8 = Chip8::new(rom.to_vec(), 0, 10, false);
+        chip8
+            .breakpoints
+            .add_breakpoint("0x202", &Symbols::default())
+            .unwrap();
+        let t = constants::TIMER_PERIOD_NS / 2;
+        chip8.cycle(t);
+        assert!(matches!(
+            chip8.stop.take(),
+            Some(StopReason::Breakpoint(0x202))
+        ));
+        assert_eq!((chip8.pc, chip8.registers[0]), (0x202, 1));
+        // Paused: the time does not move, and the machine stays at the breakpoint
+        chip8.cycle(t);
+        assert_eq!((chip8.pc, chip8.registers[0]), (0x202, 1));
+        assert!(chip8.stop.is_none());
+        // Resumed: runs from the breakpoint, up to the next hit
+        chip8.cycle(t + constants::TIMER_PERIOD_NS);
+        assert!(matches!(
+            chip8.stop.take(),
+            Some(StopReason::Breakpoint(0x202))
+        ));
+        assert_eq!((chip8.pc, chip8.registers[0]), (0x202, 3));
+    }
+
+    #[test]
+    fn runaway_pc_wraps_around_the_memory() {
+        // JMP 0xFFF, odd and at the last byte; BNNN past the end
+        run(&[0x1F, 0xFF], 10);
+        let chip8 = run(&[0x60, 0xFF, 0xBF, 0xFF], 1);
+        assert!(chip8.pc < constants::RAM_SIZE);
+    }
+
+    #[test]
+    fn endless_recursion_wraps_the_stack() {
+        // CALL 0x200
+        let chip8 = run(&[0x22, 0x00], 10);
+        assert!(chip8.istack < constants::STACK_SIZE);
+    }
+
+    #[test]
+    fn memory_accesses_wrap_around_the_memory() {
+        let rom = [
+            0x60, 0xFF, // LD V0, 0xFF
+            0xAF, 0xFF, // LD I, 0xFFF
+            0xF0, 0x33, // LD B, V0
+            0xD0, 0x0F, // DRW V0, V0, 15
+            0xFF, 0x55, // LD [I], VF
+            0xFF, 0x65, // LD VF, [I]
+            0xF0, 0x1E, // ADD I, V0
+            0x80, 0x17, // SUBN V0, V1
+            0x12, 0x06, // JMP 0x206
+        ];
+        let mut chip8 = Chip8::new(rom.to_vec(), 0, 50, false);
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.bus.ram[0xFFF..], [2]);
+        assert_eq!(chip8.bus.ram[..2], [5, 5]);
+        run(&rom, 300);
+    }
+
+    #[test]
+    fn cycles_per_frame_are_rounded() {
+        assert_eq!(cycles_per_frame(1000), 17);
+        assert_eq!(cycles_per_frame(690), 12);
+        assert_eq!(cycles_per_frame(0), 1);
+    }
+
+    #[test]
+    fn state_round_trips_through_bytes() {
+        let mut chip8 = run(&[0xC0, 0xFF, 0x22, 0x00], 3);
+        chip8.key_wait = Some(7);
+        let state = chip8.save_state();
+        let bytes = state.to_bytes();
+        let restored = State::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.to_bytes(), bytes);
+
+        // The random number generator goes on the same
+        let mut other = Chip8::new(Vec::new(), 0, 50, false);
+        other.load_state(&restored);
+        chip8.run_frame();
+        other.run_frame();
+        assert_eq!(other.save_state().to_bytes(), chip8.save_state().to_bytes());
+    }
+
+    #[test]
+    fn invalid_state_bytes_are_rejected() {
+        let bytes = Chip8::new(Vec::new(), 0, 50, false).save_state().to_bytes();
+        assert!(State::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(State::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(State::from_bytes(b"RC8X").is_err());
+        let mut version = bytes.clone();
+        version[4] += 1;
+        assert!(State::from_bytes(&version).is_err());
     }
 }
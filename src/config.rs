@@ -0,0 +1,261 @@
+use crate::keyboard::{self, Hotkeys, Keymap};
+use crate::quirks::{self, Quirks};
+
+use clap::{ArgMatches, Command};
+use sdl2::keyboard::{Keycode, Scancode};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+// Project-local configuration file, in the working directory
+const LOCAL_FILE: &str = "rchip8.toml";
+// Prefix of the environment variables
+const ENV_PREFIX: &str = "RCHIP8_";
+
+// Type of the value of a setting
+#[derive(Clone, Copy)]
+enum Kind {
+    Int,
+    Float,
+    Str,
+}
+
+// Settings backed by a command line argument: key in the configuration file, argument
+// id and type. The environment variable of each one is its key in upper case with the
+// prefix, e.g. RCHIP8_AUDIO_FREQ for 'audio.freq'.
+const ARGS: [(&str, &str, Kind); 11] = [
+    ("scale", "scale", Kind::Int),
+    ("fgcol", "fgcol", Kind::Str),
+    ("bgcol", "bgcol", Kind::Str),
+    ("ips", "ips", Kind::Int),
+    ("cycles_per_frame", "cpf", Kind::Int),
+    ("ff_speed", "ffspeed", Kind::Int),
+    ("rom_db", "romdb", Kind::Str),
+    ("audio.waveform", "waveform", Kind::Str),
+    ("audio.freq", "freq", Kind::Float),
+    ("audio.volume", "volume", Kind::Float),
+    ("audio.duty", "duty", Kind::Float),
+];
+
+// Layered TOML configuration: the global file in the user configuration directory
+// (~/.config/rchip8/config.toml on Linux), then the local 'rchip8.toml' on top of it.
+// The settings of the arguments become their defaults, so the environment variables
+// and the command line override them.
+pub struct Config {
+    table: Table,
+}
+
+impl Config {
+    // Loads and merges the configuration files that exist
+    pub fn load() -> Config {
+        let mut table = Table::new();
+        let global = dirs::config_dir().map(|dir| dir.join("rchip8").join("config.toml"));
+        for path in global.into_iter().chain([PathBuf::from(LOCAL_FILE)]) {
+            if !path.is_file() {
+                continue;
+            }
+            match read(&path) {
+                Ok(file) => {
+                    merge(&mut table, file);
+                    eprintln!("Configuration loaded: {}", path.display());
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+        Config { table }
+    }
+
+    // Returns the value of the setting, 'section.name' for the ones in a section
+    fn get(&self, key: &str) -> Option<&Value> {
+        match key.split_once('.') {
+            Some((section, name)) => self.table.get(section)?.as_table()?.get(name),
+            None => self.table.get(key),
+        }
+    }
+
+    // Binds the arguments to their environment variables, and sets their configured
+    // values as their defaults
+    pub fn apply(&self, mut command: Command) -> Command {
+        for (key, id, kind) in ARGS {
+            command = command.mut_arg(id, |arg| arg.env(env_var(key)));
+            if let Some(value) = self.get(key) {
+                match arg_value(value, kind) {
+                    Some(text) => command = command.mut_arg(id, |arg| arg.default_value(text)),
+                    None => eprintln!(
+                        "Invalid value for '{}' in the configuration: {}",
+                        key, value
+                    ),
+                }
+            }
+        }
+        command
+    }
+
+    // Returns the default quirks with the configured ones on top
+    pub fn quirks(&self) -> Quirks {
+        self.quirks_over(Quirks::default())
+    }
+
+    // Returns the given quirks with the configured ones on top
+    pub fn quirks_over(&self, mut quirks: Quirks) -> Quirks {
+        for (name, value) in self.section("quirks") {
+            match value.as_bool() {
+                Some(value) => {
+                    if let Err(error) = quirks.set(name, value) {
+                        eprintln!("{}", error);
+                    }
+                }
+                None => eprintln!("Invalid value for quirk '{}': {}", name, value),
+            }
+        }
+        quirks
+    }
+
+    // Returns the keypad keys, the configured ones as 'KEY = "NAME"' with the CHIP-8
+    // key in hex and the SDL key name, e.g. 'A = "Z"'
+    pub fn keymap(&self) -> Keymap {
+        let mut keymap = keyboard::default_keymap();
+        for (key, name) in self.section("keymap") {
+            let code = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|c| (*c as usize) < keymap.len());
+            let scancode = name.as_str().and_then(Scancode::from_name);
+            match (code, scancode) {
+                (Some(code), Some(scancode)) => keymap[code as usize] = scancode,
+                _ => eprintln!("Invalid key binding: {} = {}", key, name),
+            }
+        }
+        keymap
+    }
+
+    // Returns the hotkeys, the configured ones as 'ACTION = "NAME"' or a list of names
+    pub fn hotkeys(&self) -> Hotkeys {
+        let mut hotkeys = Hotkeys::default();
+        for (action, names) in self.section("hotkeys") {
+            let names: Vec<&Value> = match names {
+                Value::Array(names) => names.iter().collect(),
+                name => vec![name],
+            };
+            let keycodes: Option<Vec<Keycode>> = names
+                .iter()
+                .map(|n| n.as_str().and_then(Keycode::from_name))
+                .collect();
+            match (hotkeys.binding(action), keycodes) {
+                (Ok(binding), Some(keycodes)) => *binding = keycodes,
+                (Err(error), _) => eprintln!("{}", error),
+                (_, None) => eprintln!("Invalid hotkey: {} = {:?}", action, names),
+            }
+        }
+        hotkeys
+    }
+
+    // Returns the entries of the section, empty if there is none
+    fn section(&self, name: &str) -> impl Iterator<Item = (&String, &Value)> {
+        self.table
+            .get(name)
+            .and_then(|s| s.as_table())
+            .into_iter()
+            .flatten()
+    }
+}
+
+// Returns the effective configuration as TOML, with the values of the arguments from
+// all the layers
+pub fn dump(matches: &ArgMatches, quirks: &Quirks, keymap: &Keymap, hotkeys: &Hotkeys) -> String {
+    let mut table = Table::new();
+    for (key, id, kind) in ARGS {
+        let raw = matches
+            .get_raw(id)
+            .and_then(|mut values| values.next())
+            .and_then(|value| value.to_str());
+        let value = match (raw, kind) {
+            (Some(raw), Kind::Int) => raw.parse().ok().map(Value::Integer),
+            (Some(raw), Kind::Float) => raw.parse().ok().map(Value::Float),
+            (Some(raw), Kind::Str) => Some(Value::String(raw.to_string())),
+            (None, _) => None,
+        };
+        if let Some(value) = value {
+            set(&mut table, key, value);
+        }
+    }
+    for name in quirks::NAMES {
+        set(
+            &mut table,
+            &format!("quirks.{}", name),
+            Value::Boolean(quirks.get(name).unwrap_or_default()),
+        );
+    }
+    for (code, scancode) in keymap.iter().enumerate() {
+        set(
+            &mut table,
+            &format!("keymap.{:X}", code),
+            Value::String(scancode.name().to_string()),
+        );
+    }
+    let mut hotkeys = hotkeys.clone();
+    for action in keyboard::HOTKEY_NAMES {
+        let names = hotkeys
+            .binding(action)
+            .map(|keys| keys.iter().map(|k| Value::String(k.name())).collect())
+            .unwrap_or_default();
+        set(
+            &mut table,
+            &format!("hotkeys.{}", action),
+            Value::Array(names),
+        );
+    }
+    toml::to_string(&table).unwrap_or_default()
+}
+
+// Reads a configuration file
+fn read(path: &Path) -> Result<Table, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading '{}': {}", path.display(), e))?;
+    text.parse::<Table>()
+        .map_err(|e| format!("Invalid configuration '{}': {}", path.display(), e))
+}
+
+// Merges the table into the base, replacing the values and merging the sections
+fn merge(base: &mut Table, table: Table) {
+    for (key, value) in table {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(table)) => merge(base, table),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// Sets the value of the setting, 'section.name' for the ones in a section
+fn set(table: &mut Table, key: &str, value: Value) {
+    match key.split_once('.') {
+        Some((section, name)) => {
+            if let Value::Table(section) = table
+                .entry(section)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                section.insert(name.to_string(), value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+// Returns the environment variable of the setting
+fn env_var(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('.', "_"))
+}
+
+// Returns the configured value as an argument value, if it has the right type
+fn arg_value(value: &Value, kind: Kind) -> Option<String> {
+    match (value, kind) {
+        (Value::Integer(i), Kind::Int) => Some(i.to_string()),
+        (Value::Integer(i), Kind::Float) => Some(i.to_string()),
+        (Value::Float(f), Kind::Float) => Some(f.to_string()),
+        (Value::String(s), Kind::Str) => Some(s.clone()),
+        _ => None,
+    }
+}
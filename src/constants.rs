@@ -1,3 +1,18 @@
+// Window title
+pub const WINDOW_TITLE: &str = "R-CHIP-8";
+
+// Period of the checks for changes of the ROM file in ns
+pub const WATCH_PERIOD_NS: u128 = 500_000_000;
+
+// Duration of the OSD messages in ns
+pub const OSD_MESSAGE_NS: u128 = 2_000_000_000;
+// Display scale divisor giving the OSD font pixel size
+pub const OSD_SCALE_DIV: u32 = 5;
+
+// Maximum number of cheat search results listed, and of bytes frozen at once
+pub const CHEAT_LIST_MAX: usize = 16;
+pub const CHEAT_FREEZE_MAX: usize = 8;
+
 // Starting address of user programs
 pub const PROGRAM_LOC: usize = 0x200;
 
@@ -11,6 +26,37 @@ pub const DEF_BG: (u8, u8, u8) = (16, 16, 32);
 pub const DEF_IPS_STR: &str = "1000";
 // Default screen scale factor
 pub const DEF_SCALE_STR: &str = "10";
+// Minimum and maximum number of instructions per frame
+pub const MIN_CYCLES_PER_FRAME: u32 = 1;
+pub const MAX_CYCLES_PER_FRAME: u32 = 100_000;
+// Default fast-forward speed factor, 0 for unthrottled
+pub const DEF_FF_SPEED_STR: &str = "0";
+// Default profiler heatmap file
+pub const DEF_PROFILE_FILE_STR: &str = "heatmap.txt";
+// Default beeper waveform
+pub const DEF_WAVEFORM_STR: &str = "square";
+// Default beeper frequency in Hz
+pub const DEF_FREQ_STR: &str = "440";
+// Default beeper volume in [0, 1]
+pub const DEF_VOLUME_STR: &str = "0.2";
+// Default square wave duty cycle in (0, 1)
+pub const DEF_DUTY_STR: &str = "0.5";
+
+// Audio sample rate in Hz
+pub const AUDIO_SAMPLE_RATE: u32 = 44100;
+// Maximum audio output latency in samples before the queue is resynced
+pub const AUDIO_MAX_LATENCY: u32 = 8192;
+// Silence queued at startup to absorb scheduling jitter, in samples
+pub const AUDIO_PREFILL: u32 = 2048;
+// Duration of the beep attack and release ramps in seconds
+pub const AUDIO_ENVELOPE_S: f32 = 0.005;
+
+// Frames per second, rate of the delay and sound timers
+pub const FRAME_RATE: u32 = 60;
+// Delay and sound timer period in ns (60 Hz)
+pub const TIMER_PERIOD_NS: u128 = 16_666_667;
+// Maximum number of late timer ticks caught up at once
+pub const TIMER_MAX_CATCHUP: u128 = 4;
 
 // RAM size in B
 pub const RAM_SIZE: usize = 4096;
@@ -18,6 +64,8 @@ pub const RAM_SIZE: usize = 4096;
 pub const STACK_SIZE: usize = 64;
 // Number of registers
 pub const N_REGISTERS: usize = 16;
+// Number of keys in the keypad
+pub const N_KEYS: usize = 16;
 // Display width in pixels
 pub const DISPLAY_WIDTH: usize = 64;
 // Display height in pixels
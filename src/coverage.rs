@@ -0,0 +1,202 @@
+use crate::bus::{Access, AccessKind};
+use crate::constants;
+use crate::opcode::{self, Op};
+use crate::symbols::Symbols;
+
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Coverage flags of a RAM byte
+pub const EXECUTED: u8 = 0x01;
+pub const SPRITE: u8 = 0x02;
+pub const READ: u8 = 0x04;
+pub const WRITTEN: u8 = 0x08;
+
+// Names of the flags, in the JSON export and the disassembly
+const FLAG_NAMES: [(u8, &str); 4] = [
+    (EXECUTED, "executed"),
+    (SPRITE, "sprite"),
+    (READ, "read"),
+    (WRITTEN, "written"),
+];
+// Maximum number of data bytes per line in the disassembly
+const DATA_LINE_BYTES: usize = 8;
+
+// Code and data coverage map of a session: which RAM bytes were executed as
+// instructions, read as sprites by DXYN, read as data or written
+pub struct Coverage {
+    // Flags per RAM byte
+    pub flags: Vec<u8>,
+    // End of the ROM in memory, exclusive
+    rom_end: usize,
+}
+
+impl Coverage {
+    pub fn new(rom_len: usize) -> Self {
+        Coverage {
+            flags: vec![0; constants::RAM_SIZE],
+            rom_end: (constants::PROGRAM_LOC + rom_len).min(constants::RAM_SIZE),
+        }
+    }
+
+    // Records the instruction that just ran at the given address and its data accesses
+    pub fn record(&mut self, pc: usize, instr: u16, accesses: &[Access]) {
+        self.flags[pc] |= EXECUTED;
+        self.flags[(pc + 1) % constants::RAM_SIZE] |= EXECUTED;
+        let draws = matches!(opcode::decode(instr), Op::Drw(..));
+        for access in accesses {
+            self.flags[access.addr] |= match access.kind {
+                AccessKind::Read if draws => SPRITE,
+                AccessKind::Read => READ,
+                AccessKind::Write => WRITTEN,
+            };
+        }
+    }
+
+    // Writes the map as JSON to the given path, and the annotated disassembly of the ROM
+    // next to it with the 'asm' extension
+    pub fn write(&self, path: &str, ram: &[u8], symbols: &Symbols) -> Result<(), String> {
+        let asm_path = Path::new(path).with_extension("asm");
+        let asm_path = asm_path.to_str().unwrap();
+        write_file(path, |out| self.write_json(out))?;
+        write_file(asm_path, |out| self.write_disassembly(out, ram, symbols))?;
+        println!("Coverage written to: {}, {}", path, asm_path);
+        Ok(())
+    }
+
+    // Writes the inclusive address ranges of each flag, and the ROM ranges that were
+    // never executed nor read
+    fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, &self.json())?;
+        writeln!(out)
+    }
+
+    // Returns the JSON export of the map
+    fn json(&self) -> Value {
+        let mut map = Map::new();
+        map.insert(
+            "rom".to_string(),
+            json!([constants::PROGRAM_LOC, self.rom_end.saturating_sub(1)]),
+        );
+        for (flag, name) in FLAG_NAMES {
+            let ranges = self.ranges(0, constants::RAM_SIZE, |f| f & flag != 0);
+            map.insert(name.to_string(), json!(ranges));
+        }
+        let unused = self.ranges(constants::PROGRAM_LOC, self.rom_end, |f| {
+            f & (EXECUTED | SPRITE | READ) == 0
+        });
+        map.insert("unused".to_string(), json!(unused));
+        Value::Object(map)
+    }
+
+    // Writes the ROM as instructions where it was executed and as data bytes elsewhere,
+    // annotated with the flags and labels
+    fn write_disassembly(
+        &self,
+        out: &mut dyn Write,
+        ram: &[u8],
+        symbols: &Symbols,
+    ) -> std::io::Result<()> {
+        let mut addr = constants::PROGRAM_LOC;
+        while addr < self.rom_end {
+            if let Some(name) = symbols.name(addr) {
+                writeln!(out, "{}:", name)?;
+            }
+            let flags = self.flags[addr];
+            if flags & EXECUTED != 0 && addr + 1 < constants::RAM_SIZE {
+                let instr = ((ram[addr] as u16) << 8) | ram[addr + 1] as u16;
+                let text = format!("{:04x}  {:<24}", instr, symbols.disassemble(instr));
+                writeln!(out, "{:04x}  {:<30} ; {}", addr, text, flag_names(flags))?;
+                addr += 2;
+            } else {
+                // Consecutive data bytes with the same flags
+                let mut end = addr + 1;
+                while end < self.rom_end
+                    && end - addr < DATA_LINE_BYTES
+                    && self.flags[end] == flags
+                    && symbols.name(end).is_none()
+                {
+                    end += 1;
+                }
+                let bytes: Vec<String> = ram[addr..end]
+                    .iter()
+                    .map(|b| format!("0x{:02x}", b))
+                    .collect();
+                let text = format!("DB {}", bytes.join(", "));
+                writeln!(out, "{:04x}  {:<30} ; {}", addr, text, flag_names(flags))?;
+                addr = end;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the inclusive ranges of the addresses in [start, end) whose flags match
+    fn ranges(
+        &self,
+        start: usize,
+        end: usize,
+        matches: impl Fn(u8) -> bool,
+    ) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for addr in start..end {
+            if matches(self.flags[addr]) {
+                match ranges.last_mut() {
+                    Some(last) if last.1 + 1 == addr => last.1 = addr,
+                    _ => ranges.push((addr, addr)),
+                }
+            }
+        }
+        ranges
+    }
+}
+
+// Returns the names of the flags, or 'unused'
+fn flag_names(flags: u8) -> String {
+    let names: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "unused".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+// Creates the file and writes it with the given function
+fn write_file(
+    path: &str,
+    write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("Error writing '{}': {}", path, e);
+    let file = File::create(path).map_err(error)?;
+    let mut out = BufWriter::new(file);
+    write(&mut out).map_err(error)?;
+    out.flush().map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lists_the_ranges_of_each_flag() {
+        let mut coverage = Coverage::new(8);
+        let sprite = Access {
+            addr: 0x206,
+            kind: AccessKind::Read,
+            value: 0xFF,
+        };
+        coverage.record(0x200, 0x6001, &[]);
+        coverage.record(0x202, 0xD001, &[sprite]);
+        let json = coverage.json();
+        assert_eq!(json["rom"], json!([0x200, 0x207]));
+        assert_eq!(json["executed"], json!([[0x200, 0x203]]));
+        assert_eq!(json["sprite"], json!([[0x206, 0x206]]));
+        assert_eq!(json["written"], json!([]));
+        assert_eq!(json["unused"], json!([[0x204, 0x205], [0x207, 0x207]]));
+    }
+}
@@ -1,21 +1,11 @@
 use crate::constants;
+use crate::opcode::{self, Op};
 
 use std::io::{stdin, stdout, Read, Write};
 
-pub fn debug(
-    pc: usize,
-    instr: u16,
-    code: u16,
-    x: usize,
-    y: usize,
-    n: u16,
-    nn: u16,
-    nnn: u16,
-    registers: [u8; constants::N_REGISTERS],
-    idx: u16,
-) {
+pub fn debug(pc: usize, instr: u16, registers: [u8; constants::N_REGISTERS], idx: u16) {
     println!("");
-    println!("L{:03x}:  {}", pc - 2, debug_instr(code, x, y, n, nn, nnn));
+    println!("L{:03x}:  {}", pc - 2, disassemble(instr));
     println!("instr:       0x{:04x}", instr);
     println!(
         "V0: 0x{:04x}  V1: 0x{:04x}  V2: 0x{:04x}",
@@ -41,48 +31,49 @@ pub fn debug(
         "VC: 0x{:04x}                I: 0x{:04x}",
         registers[15], idx
     );
-    // println!("code:        0x{:04x}", code);
-    // println!("x:           0x{:04x} ({})", x, x);
-    // println!("y:           0x{:04x} ({})", y, y);
-    // println!("n:           0x{:04x} ({})", n, n);
-    // println!("nn:          0x{:04x} ({})", nn, nn);
-    // println!("nnn:         0x{:04x} ({})", nnn, nnn);
     pause();
 }
 
-pub fn debug_instr(code: u16, x: usize, y: usize, n: u16, nn: u16, nnn: u16) -> String {
-    match code {
-        // 00E0 - clear screen
-        0x0000 => format!("CLS"),
-        // 1NNN - jump
-        0x1000 => format!("JMP 0x{:04x}", nnn),
-        // 6XNN - set register VX to NN
-        0x6000 => format!("LD V{}, 0x{:04x}", x, nn),
-        // 7XNN - add NN to register VX
-        0x7000 => format!("ADD V{}, 0x{:04x}", x, nn),
-        0x8000 => {
-            match n {
-                // 8XY0 - VX := VY
-                0 => format!("LD V{}, V{}", x, y),
-                // 8XY1 - VX := VX OR VY
-                1 => format!("OR V{}, V{}", x, y),
-                // 8XY2 - VX := VX AND VY
-                2 => format!("AND V{}, V{}", x, y),
-                // 8XY3 - VX := VX XOR VY
-                3 => format!("XOR V{}, V{}", x, y),
-                // 8XY4 - ADD VX, VY
-                4 => format!("ADD V{}, 0x{:04x}", x, y),
-                // Default
-                _ => format!(""),
-            }
-        }
-        // ANNN - set index register to NNN
-        0xA000 => format!("LD I, 0x{:04x}", nnn),
-        // DXYN - display/draw
-        0xD000 => format!("DRW V{}, V{}, 0x{:04x}", x, y, n),
-        _ => format!(""),
+// Decodes and disassembles the given instruction
+pub fn disassemble(instr: u16) -> String {
+    match opcode::decode(instr) {
+        Op::Cls => "CLS".to_string(),
+        Op::Ret => "RET".to_string(),
+        Op::Sys(nnn) => format!("SYS 0x{:04x}", nnn),
+        Op::Jmp(nnn) => format!("JMP 0x{:04x}", nnn),
+        Op::Call(nnn) => format!("CALL 0x{:04x}", nnn),
+        Op::SeByte(x, nn) => format!("SE V{:X}, 0x{:04x}", x, nn),
+        Op::SneByte(x, nn) => format!("SNE V{:X}, 0x{:04x}", x, nn),
+        Op::SeReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        Op::LdByte(x, nn) => format!("LD V{:X}, 0x{:04x}", x, nn),
+        Op::AddByte(x, nn) => format!("ADD V{:X}, 0x{:04x}", x, nn),
+        Op::LdReg(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Op::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        Op::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Op::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Op::AddReg(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Op::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        Op::Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        Op::Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        Op::Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        Op::SneReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        Op::LdI(nnn) => format!("LD I, 0x{:04x}", nnn),
+        Op::JmpV0(_, nnn) => format!("JMP V0, 0x{:04x}", nnn),
+        Op::Rnd(x, nn) => format!("RND V{:X}, 0x{:04x}", x, nn),
+        Op::Drw(x, y, n) => format!("DRW V{:X}, V{:X}, 0x{:04x}", x, y, n),
+        Op::Skp(x) => format!("SKP V{:X}", x),
+        Op::Sknp(x) => format!("SKNP V{:X}", x),
+        Op::LdVxDt(x) => format!("LD V{:X}, DT", x),
+        Op::LdVxK(x) => format!("LD V{:X}, K", x),
+        Op::LdDtVx(x) => format!("LD DT, V{:X}", x),
+        Op::LdStVx(x) => format!("LD ST, V{:X}", x),
+        Op::AddI(x) => format!("ADD I, V{:X}", x),
+        Op::LdF(x) => format!("LD F, V{:X}", x),
+        Op::LdB(x) => format!("LD B, V{:X}", x),
+        Op::Store(x) => format!("LD [I], V{:X}", x),
+        Op::Load(x) => format!("LD V{:X}, [I]", x),
+        Op::Data(instr) => format!("DATA 0x{:04x}", instr),
     }
-    .to_string()
 }
 
 fn pause() {
@@ -91,3 +82,19 @@ fn pause() {
     stdout.flush().unwrap();
     stdin().read(&mut [0]).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_like_the_interpreter_decodes() {
+        assert_eq!(disassemble(0x00E0), "CLS");
+        assert_eq!(disassemble(0x00EE), "RET");
+        assert_eq!(disassemble(0x0000), "SYS 0x0000");
+        assert_eq!(disassemble(0x00E1), "SYS 0x00e1");
+        assert_eq!(disassemble(0xD125), "DRW V1, V2, 0x0005");
+        assert_eq!(disassemble(0x8128), "DATA 0x8128");
+        assert_eq!(disassemble(0xF265), "LD V2, [I]");
+    }
+}
@@ -0,0 +1,238 @@
+use crate::chip8::Chip8;
+use crate::constants;
+use crate::font;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::Sdl;
+
+// Font pixel size
+const PX: u32 = 2;
+// Window size in characters
+const COLS: u32 = 80;
+const ROWS: u32 = 36;
+// Pane positions in characters
+const REGS_COL: i32 = 1;
+const DISASM_COL: i32 = 22;
+// Width of the call stack entries in characters
+const STACK_WIDTH: usize = 20;
+const COUNT_COL: i32 = 66;
+const MEM_ROW: i32 = 24;
+// Number of disassembled instructions before the PC
+const DISASM_BEFORE: usize = 10;
+// Number of disassembled lines
+const DISASM_LINES: usize = 22;
+// Number of bytes per row and rows in the memory viewer
+const MEM_ROW_BYTES: usize = 16;
+const MEM_ROWS: usize = 10;
+
+const BG_COL: Color = Color::RGB(16, 16, 32);
+const TEXT_COL: Color = Color::RGB(171, 174, 203);
+const TITLE_COL: Color = Color::RGB(255, 200, 80);
+const CURRENT_COL: Color = Color::RGB(50, 50, 100);
+const WRITTEN_COL: Color = Color::RGB(255, 90, 90);
+
+// Debugger window showing the registers, call stack, disassembly and memory of the machine
+pub struct Debugger {
+    canvas: Canvas<Window>,
+    // First address of the memory viewer, None to follow the index register
+    mem_addr: Option<usize>,
+}
+
+impl Debugger {
+    pub fn new(sdl_context: &Sdl) -> Self {
+        let video_subsystem = sdl_context.video().unwrap();
+        let window = video_subsystem
+            .window(
+                &format!("{} - Debugger", constants::WINDOW_TITLE),
+                COLS * font::ADVANCE_X * PX,
+                ROWS * font::ADVANCE_Y * PX,
+            )
+            .build()
+            .unwrap();
+        let canvas = window.into_canvas().build().unwrap();
+
+        Debugger {
+            canvas,
+            mem_addr: None,
+        }
+    }
+
+    // Returns the id of the debugger window
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    // Handles the keys pressed in the debugger window to scroll the memory viewer:
+    // Page up/down scroll, Home follows the index register again
+    pub fn handle_event(&mut self, event: &Event, index: u16) {
+        if let Event::KeyDown {
+            window_id,
+            keycode: Some(keycode),
+            ..
+        } = event
+        {
+            if *window_id != self.window_id() {
+                return;
+            }
+            let page = MEM_ROW_BYTES * MEM_ROWS;
+            let addr = self
+                .mem_addr
+                .unwrap_or(index as usize - index as usize % MEM_ROW_BYTES);
+            match *keycode {
+                Keycode::PageUp => self.mem_addr = Some(addr.saturating_sub(page)),
+                Keycode::PageDown => {
+                    self.mem_addr = Some((addr + page).min(constants::RAM_SIZE - page))
+                }
+                Keycode::Home => self.mem_addr = None,
+                _ => (),
+            }
+        }
+    }
+
+    // Renders the state of the machine and clears its written memory flags
+    pub fn render(&mut self, chip8: &mut Chip8) {
+        self.canvas.set_draw_color(BG_COL);
+        self.canvas.clear();
+
+        self.render_registers(chip8);
+        self.render_disassembly(chip8);
+        self.render_memory(chip8);
+
+        self.canvas.present();
+        chip8.bus.written.fill(false);
+    }
+
+    // Draws V0-VF, I, PC, SP, DT, ST and the call stack
+    fn render_registers(&mut self, chip8: &Chip8) {
+        self.text("REGISTERS", REGS_COL, 0, TITLE_COL);
+        for i in 0..8 {
+            let line = format!(
+                "V{:X} {:02x}   V{:X} {:02x}",
+                i,
+                chip8.registers[i],
+                i + 8,
+                chip8.registers[i + 8]
+            );
+            self.text(&line, REGS_COL, 1 + i as i32, TEXT_COL);
+        }
+        self.text(&format!("I  {:04x}", chip8.index), REGS_COL, 10, TEXT_COL);
+        self.text(&format!("PC {:04x}", chip8.pc), REGS_COL, 11, TEXT_COL);
+        self.text(&format!("SP {:02x}", chip8.istack), REGS_COL, 12, TEXT_COL);
+        self.text(&format!("DT {:02x}", chip8.dt), REGS_COL, 13, TEXT_COL);
+        self.text(&format!("ST {:02x}", chip8.st), REGS_COL, 14, TEXT_COL);
+
+        // Call stack, top first, with the labels of the return addresses
+        self.text("STACK", REGS_COL, 16, TITLE_COL);
+        let max_lines = (MEM_ROW - 18) as usize;
+        for (line, level) in (1..=chip8.istack).rev().take(max_lines).enumerate() {
+            let addr = chip8.stack[level] as usize;
+            let mut entry = format!("{:02x} {:04x}", level, addr);
+            if let Some(label) = chip8.symbols.locate(addr) {
+                entry.push(' ');
+                entry.push_str(&label);
+                entry = entry.chars().take(STACK_WIDTH).collect();
+            }
+            self.text(&entry, REGS_COL, 17 + line as i32, TEXT_COL);
+        }
+    }
+
+    // Draws the instructions around the PC, highlighting the current one, with their
+    // labels, and their execution counts if the profiler is enabled
+    fn render_disassembly(&mut self, chip8: &Chip8) {
+        self.text("DISASSEMBLY", DISASM_COL, 0, TITLE_COL);
+        let mut addr = chip8.pc.saturating_sub(DISASM_BEFORE * 2);
+        let mut row = 1;
+        while row <= DISASM_LINES as i32 && addr + 1 < constants::RAM_SIZE {
+            if let Some(name) = chip8.symbols.name(addr) {
+                self.text(&format!("{}:", name), DISASM_COL, row, TITLE_COL);
+                row += 1;
+                if row > DISASM_LINES as i32 {
+                    break;
+                }
+            }
+            if addr == chip8.pc {
+                self.bar(DISASM_COL - 1, row, COLS as i32 - DISASM_COL, CURRENT_COL);
+            }
+            let instr = chip8.bus.fetch(addr);
+            let text = format!(
+                "{:04x}  {:04x}  {}",
+                addr,
+                instr,
+                chip8.symbols.disassemble(instr)
+            );
+            self.text(&text, DISASM_COL, row, TEXT_COL);
+            // Execution counts when profiling
+            if let Some(profile) = chip8.profile.as_ref() {
+                let count = format!("{:>10}", profile.counts[addr]);
+                self.text(&count, COUNT_COL, row, TITLE_COL);
+            }
+            addr += 2;
+            row += 1;
+        }
+    }
+
+    // Draws a hex view of the memory, highlighting the bytes written since the last render
+    fn render_memory(&mut self, chip8: &Chip8) {
+        let page = MEM_ROW_BYTES * MEM_ROWS;
+        let first = match self.mem_addr {
+            Some(addr) => addr,
+            None => {
+                // Start one row above the index register
+                let row = chip8.index as usize / MEM_ROW_BYTES;
+                (row.saturating_sub(1) * MEM_ROW_BYTES).min(constants::RAM_SIZE - page)
+            }
+        };
+        let title = if self.mem_addr.is_some() {
+            "MEMORY (HOME: FOLLOW I)"
+        } else {
+            "MEMORY (PGUP/PGDN: SCROLL)"
+        };
+        self.text(title, REGS_COL, MEM_ROW, TITLE_COL);
+
+        for row in 0..MEM_ROWS {
+            let addr = first + row * MEM_ROW_BYTES;
+            let y = MEM_ROW + 1 + row as i32;
+            self.text(&format!("{:04x}", addr), REGS_COL, y, TITLE_COL);
+            for col in 0..MEM_ROW_BYTES {
+                let a = addr + col;
+                let col_x = REGS_COL + 6 + col as i32 * 3;
+                let color = if chip8.bus.written[a] {
+                    WRITTEN_COL
+                } else {
+                    TEXT_COL
+                };
+                self.text(&format!("{:02x}", chip8.bus.ram[a]), col_x, y, color);
+            }
+        }
+    }
+
+    // Draws text at the given character position
+    fn text(&mut self, text: &str, col: i32, row: i32, color: Color) {
+        font::draw_text(
+            &mut self.canvas,
+            text,
+            col * (font::ADVANCE_X * PX) as i32,
+            row * (font::ADVANCE_Y * PX) as i32 + PX as i32,
+            PX,
+            color,
+        );
+    }
+
+    // Draws a bar behind the text line at the given character position
+    fn bar(&mut self, col: i32, row: i32, width: i32, color: Color) {
+        self.canvas.set_draw_color(color);
+        self.canvas
+            .fill_rect(Rect::new(
+                col * (font::ADVANCE_X * PX) as i32,
+                row * (font::ADVANCE_Y * PX) as i32,
+                width as u32 * font::ADVANCE_X * PX,
+                font::ADVANCE_Y * PX,
+            ))
+            .unwrap();
+    }
+}
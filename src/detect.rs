@@ -0,0 +1,223 @@
+use crate::constants;
+
+use std::path::Path;
+
+// Platform guessed from the contents and file name of a ROM
+pub struct Detection {
+    // chip-8-database platform id, None for plain CHIP-8
+    pub platform: Option<&'static str>,
+    // Evidence found, in the order it was checked
+    pub reasons: Vec<String>,
+}
+
+// Largest ROM that fits in the 4 KB of memory of CHIP-8 and SCHIP (3.5 KB)
+const MAX_CHIP8_ROM: usize = 0x1000 - 0x200;
+// Minimum number of extended instructions in the reachable code to pick their
+// platform, so that a single one, e.g. a DXY0 that draws nothing on CHIP-8, is not enough
+const MIN_INSTRUCTIONS: u32 = 2;
+
+// Guesses the platform of a ROM that is not in the database. Only the code reachable
+// from the entry point is scanned, following the jumps, calls and skips, so that data
+// bytes are not taken as instructions. The platform is only picked on strong evidence:
+// its file extension, a size over the CHIP-8 memory, or enough extended instructions.
+// Every piece of evidence is reported so the guess can be judged.
+pub fn detect(rom: &[u8], path: &str) -> Detection {
+    let mut schip: Vec<String> = Vec::new();
+    let mut xochip: Vec<String> = Vec::new();
+    // Evidence from the file alone
+    let mut schip_file = false;
+    let mut xochip_file = false;
+
+    // File extension
+    match Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .as_deref()
+    {
+        Some("sc8") => {
+            schip.push("file extension .sc8".to_string());
+            schip_file = true;
+        }
+        Some("xo8") => {
+            xochip.push("file extension .xo8".to_string());
+            xochip_file = true;
+        }
+        _ => (),
+    }
+
+    // Size
+    if rom.len() > MAX_CHIP8_ROM {
+        xochip.push(format!(
+            "size of {} b, more than the {} b of CHIP-8 memory",
+            rom.len(),
+            MAX_CHIP8_ROM
+        ));
+        xochip_file = true;
+    }
+
+    // Instructions
+    let mut counts = Counts::default();
+    for i in reachable(rom) {
+        let instr = fetch(rom, i).unwrap_or(0);
+        match instr {
+            0x00FF => counts.hires += 1,
+            0x00FE => counts.lores += 1,
+            0x00FB | 0x00FC => counts.scroll += 1,
+            0x00C0..=0x00CF => counts.scroll += 1,
+            0xF000 => counts.long_load += 1,
+            _ => match instr & 0xF0FF {
+                0xF001 => counts.plane += 1,
+                0xF030 | 0xF075 | 0xF085 => counts.schip_misc += 1,
+                _ if instr & 0xF00F == 0xD000 => counts.big_sprite += 1,
+                _ => (),
+            },
+        }
+    }
+    let found = |list: &mut Vec<String>, count: u32, what: &str| {
+        if count > 0 {
+            list.push(format!("{} x {}", count, what));
+        }
+    };
+    found(&mut schip, counts.hires, "00FF (high resolution)");
+    found(&mut schip, counts.lores, "00FE (low resolution)");
+    found(&mut schip, counts.scroll, "00CN/00FB/00FC (scroll)");
+    found(&mut schip, counts.big_sprite, "DXY0 (16x16 sprite)");
+    found(&mut schip, counts.schip_misc, "FX30/FX75/FX85");
+    found(&mut xochip, counts.long_load, "F000 NNNN (long I load)");
+    found(&mut xochip, counts.plane, "FN01 (plane select)");
+
+    // XO-CHIP is a superset of SCHIP
+    let xochip_instructions = counts.long_load + counts.plane;
+    let schip_instructions = counts.hires
+        + counts.lores
+        + counts.scroll
+        + counts.big_sprite
+        + counts.schip_misc
+        + xochip_instructions;
+    let platform = if xochip_file || xochip_instructions >= MIN_INSTRUCTIONS {
+        Some("xochip")
+    } else if schip_file || schip_instructions >= MIN_INSTRUCTIONS {
+        Some("superchip")
+    } else {
+        None
+    };
+    let reasons = xochip
+        .into_iter()
+        .map(|r| format!("XO-CHIP: {}", r))
+        .chain(schip.into_iter().map(|r| format!("SCHIP: {}", r)))
+        .collect();
+    Detection { platform, reasons }
+}
+
+// Returns the offsets in the ROM of the instructions reachable from its start, in order
+fn reachable(rom: &[u8]) -> Vec<usize> {
+    let mut visited = vec![false; rom.len()];
+    let mut pending = vec![0];
+    // Offset of an address, if it is in the ROM
+    let offset = |addr: u16| {
+        (addr as usize)
+            .checked_sub(constants::PROGRAM_LOC)
+            .filter(|o| o + 1 < rom.len())
+    };
+    while let Some(i) = pending.pop() {
+        let Some(instr) = fetch(rom, i).filter(|_| !visited[i]) else {
+            continue;
+        };
+        visited[i] = true;
+        // Size of the instruction, XO-CHIP F000 NNNN takes 4 bytes
+        let size = |i: usize| if fetch(rom, i) == Some(0xF000) { 4 } else { 2 };
+        let next = i + size(i);
+        match instr & 0xF000 {
+            // Return, exit: the flow ends
+            _ if instr == 0x00EE || instr == 0x00FD => (),
+            // Jump
+            0x1000 => pending.extend(offset(instr & 0x0FFF)),
+            // Call, the flow goes on after the return
+            0x2000 => {
+                pending.extend(offset(instr & 0x0FFF));
+                pending.push(next);
+            }
+            // Skips, over the next instruction whatever its size
+            0x3000 | 0x4000 | 0x5000 | 0x9000 => pending.extend([next, next + size(next)]),
+            0xE000 if matches!(instr & 0x00FF, 0x9E | 0xA1) => {
+                pending.extend([next, next + size(next)])
+            }
+            // Computed jump, the targets are unknown
+            0xB000 => (),
+            _ => pending.push(next),
+        }
+    }
+    (0..rom.len()).filter(|i| visited[*i]).collect()
+}
+
+// Returns the instruction at the offset in the ROM, if it is in it
+fn fetch(rom: &[u8], i: usize) -> Option<u16> {
+    Some(((*rom.get(i)? as u16) << 8) | *rom.get(i + 1)? as u16)
+}
+
+// Occurrences of the instructions of the extended platforms
+#[derive(Default)]
+struct Counts {
+    hires: u32,
+    lores: u32,
+    scroll: u32,
+    big_sprite: u32,
+    schip_misc: u32,
+    long_load: u32,
+    plane: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_rom_is_chip8() {
+        // CLS; LD V0, 1; JMP 0x202
+        let rom = [0x00, 0xE0, 0x60, 0x01, 0x12, 0x02];
+        let detection = detect(&rom, "game.ch8");
+        assert_eq!(detection.platform, None);
+        assert!(detection.reasons.is_empty());
+    }
+
+    #[test]
+    fn data_is_not_scanned() {
+        // JMP 0x200, then sprite data looking like 00FF, 00FE and F000 NNNN
+        let rom = [0x12, 0x00, 0x00, 0xFF, 0x00, 0xFE, 0xF0, 0x00, 0x12, 0x34];
+        let detection = detect(&rom, "game.ch8");
+        assert_eq!(detection.platform, None);
+        assert!(detection.reasons.is_empty());
+    }
+
+    #[test]
+    fn single_instruction_is_not_enough() {
+        // DRW V0, V1, 0; JMP 0x200
+        let rom = [0xD0, 0x10, 0x12, 0x00];
+        let detection = detect(&rom, "game.ch8");
+        assert_eq!(detection.platform, None);
+        assert_eq!(detection.reasons, ["SCHIP: 1 x DXY0 (16x16 sprite)"]);
+    }
+
+    #[test]
+    fn reachable_instructions_pick_the_platform() {
+        // HIGH; CALL 0x206; JMP 0x204; DRW V0, V1, 0; RET
+        let rom = [0x00, 0xFF, 0x22, 0x06, 0x12, 0x04, 0xD0, 0x10, 0x00, 0xEE];
+        assert_eq!(detect(&rom, "game.ch8").platform, Some("superchip"));
+        // SE V0, 0 skipping over F000 NNNN; F000 NNNN; PLANE 1; JMP 0x20A
+        let rom = [
+            0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0xF1, 0x01, 0x12, 0x0A, 0x12, 0x0A,
+        ];
+        assert_eq!(detect(&rom, "game.ch8").platform, Some("xochip"));
+    }
+
+    #[test]
+    fn file_extension_and_size_pick_the_platform() {
+        let rom = [0x12, 0x00];
+        assert_eq!(detect(&rom, "game.sc8").platform, Some("superchip"));
+        assert_eq!(detect(&rom, "game.XO8").platform, Some("xochip"));
+        let mut rom = vec![0; MAX_CHIP8_ROM + 2];
+        rom[..2].copy_from_slice(&[0x12, 0x00]);
+        assert_eq!(detect(&rom, "game.ch8").platform, Some("xochip"));
+    }
+}
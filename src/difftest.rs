@@ -0,0 +1,252 @@
+use crate::chip8::Chip8;
+use crate::constants;
+use crate::debug;
+use crate::quirks::Quirks;
+
+use std::fs;
+
+// Machine state from a reference trace produced by another emulator.
+//
+// The trace is a text file with one line per instruction, holding the state of the
+// machine BEFORE the instruction runs, as space-separated KEY=VALUE fields in hex:
+//   PC=0204 OP=8014 V=05070000000000000000000000000000 I=0000 SP=00 DT=00 ST=00 FB=...
+// PC is required, the other fields are optional and only compared if present.
+// V holds V0-VF as 32 hex digits. FB is the framebuffer packed in 256 bytes (512 hex
+// digits), row by row, 8 pixels per byte with the leftmost pixel in the high bit.
+// Empty lines and lines starting with '#' are ignored.
+struct RefState {
+    // Line number in the file, from 1
+    line: usize,
+    pc: usize,
+    op: Option<u16>,
+    v: Option<Vec<u8>>,
+    i: Option<u16>,
+    sp: Option<u8>,
+    dt: Option<u8>,
+    st: Option<u8>,
+    fb: Option<Vec<u8>>,
+}
+
+impl RefState {
+    fn parse(line: usize, text: &str) -> Result<RefState, String> {
+        let error = |what: &str| format!("Line {}: {}", line, what);
+        let mut state = RefState {
+            line,
+            pc: usize::MAX,
+            op: None,
+            v: None,
+            i: None,
+            sp: None,
+            dt: None,
+            st: None,
+            fb: None,
+        };
+        for field in text.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| error(&format!("expected KEY=VALUE, found '{}'", field)))?;
+            let number = |bits: u32| {
+                u32::from_str_radix(value, 16)
+                    .ok()
+                    .filter(|n| (*n as u64) < 1 << bits)
+                    .ok_or_else(|| error(&format!("invalid {} '{}'", key, value)))
+            };
+            let bytes = |len: usize| {
+                hex::decode(value)
+                    .ok()
+                    .filter(|b| b.len() == len)
+                    .ok_or_else(|| error(&format!("{} must have {} hex digits", key, len * 2)))
+            };
+            match key.to_uppercase().as_str() {
+                "PC" => state.pc = number(16)? as usize,
+                "OP" => state.op = Some(number(16)? as u16),
+                "V" => state.v = Some(bytes(constants::N_REGISTERS)?),
+                "I" => state.i = Some(number(16)? as u16),
+                "SP" => state.sp = Some(number(8)? as u8),
+                "DT" => state.dt = Some(number(8)? as u8),
+                "ST" => state.st = Some(number(8)? as u8),
+                "FB" => state.fb = Some(bytes(constants::DISPLAY_LEN / 8)?),
+                _ => return Err(error(&format!("unknown field '{}'", key))),
+            }
+        }
+        if state.pc == usize::MAX {
+            return Err(error("missing PC"));
+        }
+        Ok(state)
+    }
+
+    // Returns the differences between the machine and this state
+    fn compare(&self, chip8: &Chip8) -> Vec<String> {
+        let mut diffs = Vec::new();
+        let mut check = |name: String, expected: u32, actual: u32, digits: usize| {
+            if expected != actual {
+                diffs.push(format!(
+                    "{}: expected 0x{:0w$x}, got 0x{:0w$x}",
+                    name,
+                    expected,
+                    actual,
+                    w = digits
+                ));
+            }
+        };
+        check("PC".to_string(), self.pc as u32, chip8.pc as u32, 4);
+        if let Some(op) = self.op {
+            if chip8.pc + 1 < constants::RAM_SIZE {
+                check(
+                    "OP".to_string(),
+                    op as u32,
+                    chip8.bus.fetch(chip8.pc) as u32,
+                    4,
+                );
+            }
+        }
+        if let Some(v) = &self.v {
+            for (x, (expected, actual)) in v.iter().zip(chip8.registers.iter()).enumerate() {
+                check(format!("V{:X}", x), *expected as u32, *actual as u32, 2);
+            }
+        }
+        if let Some(i) = self.i {
+            check("I".to_string(), i as u32, chip8.index as u32, 4);
+        }
+        if let Some(sp) = self.sp {
+            check("SP".to_string(), sp as u32, chip8.istack as u32, 2);
+        }
+        if let Some(dt) = self.dt {
+            check("DT".to_string(), dt as u32, chip8.dt as u32, 2);
+        }
+        if let Some(st) = self.st {
+            check("ST".to_string(), st as u32, chip8.st as u32, 2);
+        }
+        if let Some(fb) = &self.fb {
+            let differing = (0..constants::DISPLAY_LEN)
+                .filter(|p| {
+                    let expected = (fb[p / 8] >> (7 - p % 8)) & 1;
+                    expected != chip8.display[*p]
+                })
+                .count();
+            if differing > 0 {
+                diffs.push(format!("Framebuffer: {} pixels differ", differing));
+            }
+        }
+        diffs
+    }
+}
+
+// Runs the ROM in lockstep with the reference trace at the given path, without input.
+// Returns a report of the first divergence, or None if the whole trace matches.
+pub fn run(
+    rom: Vec<u8>,
+    cycles_per_frame: u32,
+    quirks: Quirks,
+    path: &str,
+) -> Result<Option<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Error reading '{}': {}", path, e))?;
+    let mut states = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            states.push(RefState::parse(n + 1, line)?);
+        }
+    }
+
+    let mut chip8 = Chip8::new(rom, 0, cycles_per_frame, false);
+    chip8.quirks = quirks;
+    // Last instruction run: address and opcode
+    let mut last: Option<(usize, u16)> = None;
+    for (count, state) in states.iter().enumerate() {
+        // The random generators differ, take the result of RND from the reference
+        if let (Some((_, instr)), Some(v)) = (last, &state.v) {
+            if instr & 0xF000 == 0xC000 {
+                let x = ((instr & 0x0F00) >> 8) as usize;
+                chip8.registers[x] = v[x];
+            }
+        }
+
+        let diffs = state.compare(&chip8);
+        if !diffs.is_empty() {
+            let mut report = format!(
+                "Divergence at instruction {} (line {} of the reference)",
+                count, state.line
+            );
+            if let Some((pc, instr)) = last {
+                report.push_str(&format!(
+                    ", after 0x{:04x}: {:04x} {}",
+                    pc,
+                    instr,
+                    debug::disassemble(instr)
+                ));
+            }
+            for diff in diffs {
+                report.push_str(&format!("\n  {}", diff));
+            }
+            return Ok(Some(report));
+        }
+
+        if chip8.pc + 1 >= constants::RAM_SIZE {
+            return Err(format!("PC out of memory: 0x{:04x}", chip8.pc));
+        }
+        last = Some((chip8.pc, chip8.bus.fetch(chip8.pc)));
+        chip8.step();
+    }
+    println!("No divergence in {} instructions", states.len());
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trace_lines() {
+        let state = RefState::parse(
+            3,
+            "pc=0204 OP=8014 V=05070000000000000000000000000000 I=0300 SP=01 DT=3c",
+        )
+        .unwrap();
+        assert_eq!(state.line, 3);
+        assert_eq!(state.pc, 0x204);
+        assert_eq!(state.op, Some(0x8014));
+        assert_eq!(
+            state.v.as_deref().map(|v| (v[0], v[1], v.len())),
+            Some((5, 7, 16))
+        );
+        assert_eq!(
+            (state.i, state.sp, state.dt, state.st),
+            (Some(0x300), Some(1), Some(0x3C), None)
+        );
+        assert!(state.fb.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_trace_lines() {
+        for text in [
+            "OP=8014",
+            "PC=0204 PC",
+            "PC=10000",
+            "PC=0204 SP=100",
+            "PC=0204 V=0507",
+            "PC=0204 FB=00",
+            "PC=0204 XX=00",
+        ] {
+            let error = RefState::parse(7, text).err().expect(text);
+            assert!(error.starts_with("Line 7: "), "{}", error);
+        }
+    }
+
+    #[test]
+    fn compares_the_machine() {
+        let chip8 = Chip8::new(vec![0x60, 0x05], 0, 1, false);
+        let mut fb = "00".repeat(constants::DISPLAY_LEN / 8);
+        fb.replace_range(..2, "80");
+        let state = RefState::parse(1, &format!("PC=0200 OP=6005 I=0000 FB={}", fb)).unwrap();
+        assert_eq!(state.compare(&chip8), ["Framebuffer: 1 pixels differ"]);
+        let state = RefState::parse(1, "PC=0202 DT=01").unwrap();
+        assert_eq!(
+            state.compare(&chip8),
+            [
+                "PC: expected 0x0202, got 0x0200",
+                "DT: expected 0x01, got 0x00"
+            ]
+        );
+    }
+}
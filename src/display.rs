@@ -1,4 +1,5 @@
 use crate::constants;
+use crate::osd::Osd;
 
 use sdl2::rect::Rect;
 use sdl2::Sdl;
@@ -11,6 +12,10 @@ pub struct Display {
     pub scale: u32,
     pub fgcol: Color,
     pub bgcol: Color,
+    // On-screen display drawn on top of the image
+    pub osd: Osd,
+    // Last rendered buffer
+    buffer: [u8; constants::DISPLAY_LEN],
 }
 
 impl Display {
@@ -41,18 +46,41 @@ impl Display {
             scale,
             fgcol: Color::RGB(fg_col.0, fg_col.1, fg_col.2),
             bgcol: Color::RGB(bg_col.0, bg_col.1, bg_col.2),
+            osd: Osd::new(),
+            buffer: [0; constants::DISPLAY_LEN],
         }
     }
 
+    // Sets the foreground and background colors
+    pub fn set_colors(&mut self, fg_col: (u8, u8, u8), bg_col: (u8, u8, u8)) {
+        self.fgcol = Color::RGB(fg_col.0, fg_col.1, fg_col.2);
+        self.bgcol = Color::RGB(bg_col.0, bg_col.1, bg_col.2);
+    }
+
+    // Sets the window title
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
     // Clears the display to black
     pub fn clear(&mut self) {
+        self.buffer = [0; constants::DISPLAY_LEN];
         self.canvas.set_draw_color(self.bgcol);
         self.canvas.clear();
+        self.osd.draw(&mut self.canvas, self.scale);
         self.canvas.present();
     }
 
+    // Redraws the last buffer if the OSD changed at time t [ns]
+    pub fn update_osd(&mut self, t: u128) {
+        if self.osd.update(t) {
+            self.render(self.buffer);
+        }
+    }
+
     // Renders the given buffer to the display
     pub fn render(&mut self, buffer: [u8; constants::DISPLAY_LEN]) {
+        self.buffer = buffer;
         // Fill with buffer
         let scl = self.scale as usize;
         for x in 0..constants::DISPLAY_WIDTH {
@@ -83,6 +111,7 @@ impl Display {
             }
         }
 
+        self.osd.draw(&mut self.canvas, self.scale);
         self.canvas.present();
     }
 }
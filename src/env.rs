@@ -0,0 +1,239 @@
+use crate::chip8::{Chip8, State};
+use crate::constants;
+use crate::expr::Expr;
+use crate::quirks::Quirks;
+
+// Observation of the machine after a step
+#[derive(Clone)]
+pub struct Observation {
+    // Display pixels row by row, 1 if on
+    pub display: [u8; constants::DISPLAY_LEN],
+    // RAM: 4 kB
+    pub ram: [u8; constants::RAM_SIZE],
+}
+
+// Outcome of a step
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Info {
+    // Change of the score during the step
+    pub reward: f64,
+    // Whether the done condition is true, ending the episode
+    pub done: bool,
+    // Score after the step
+    pub score: u32,
+    // Frames run, fewer than asked if the episode ended
+    pub frames: u32,
+    // Frames and instructions run since the reset
+    pub frame_count: u64,
+    pub instruction_count: u64,
+}
+
+// Reinforcement learning environment in the style of Gym, running a ROM without display
+// nor timing. reset() starts an episode, and step() holds the keys of the action for a
+// number of frames (frame skip). The reward of a step is the change of the score, an
+// expression over the RAM and registers, e.g. '[0x2F0] + [0x2F1]' (see Expr), and
+// the episode is done when the done expression is true, e.g. '[0x2F2] == 0'.
+pub struct Env {
+    chip8: Chip8,
+    // State with the ROM loaded, restored on reset
+    initial: State,
+    score: Option<Expr>,
+    done: Option<Expr>,
+    // Score after the last step
+    last_score: u32,
+}
+
+impl Env {
+    // Loads the ROM in a machine with the given quirks and speed in instructions per frame
+    pub fn new(rom: Vec<u8>, quirks: Quirks, cycles_per_frame: u32) -> Env {
+        let mut chip8 = Chip8::new(rom, 0, cycles_per_frame.max(1), false);
+        chip8.quirks = quirks;
+        let initial = chip8.save_state();
+        Env {
+            chip8,
+            initial,
+            score: None,
+            done: None,
+            last_score: 0,
+        }
+    }
+
+    // Sets the score expression, the reward is its change. Without it, the reward is 0.
+    pub fn set_score(&mut self, score: &str) -> Result<(), String> {
+        self.score = Some(Expr::parse(score, &self.chip8.symbols)?);
+        self.last_score = self.score();
+        Ok(())
+    }
+
+    // Sets the expression that ends the episode when it is true
+    pub fn set_done(&mut self, done: &str) -> Result<(), String> {
+        self.done = Some(Expr::parse(done, &self.chip8.symbols)?);
+        Ok(())
+    }
+
+    // Starts an episode: restarts the machine with the ROM loaded and seeds the random
+    // number generator, so that the same actions give the same episode
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        self.chip8.load_state(&self.initial);
+        self.chip8.seed(seed);
+        self.last_score = self.score();
+        self.observe()
+    }
+
+    // Runs the given number of frames with the keys of the action pressed, a mask with
+    // the bit N set for the key N. Stops early when the episode is done.
+    pub fn step(&mut self, action: u16, frames: u32) -> (Observation, Info) {
+        for (key, pressed) in self.chip8.keys.iter_mut().enumerate() {
+            *pressed = action & (1 << key) != 0;
+        }
+        let mut run = 0;
+        while run < frames && !self.is_done() {
+            self.chip8.run_frame();
+            run += 1;
+        }
+        // No audio output to drain them
+        self.chip8.sound_events.clear();
+
+        let score = self.score();
+        let info = Info {
+            reward: score as f64 - self.last_score as f64,
+            done: self.is_done(),
+            score,
+            frames: run,
+            frame_count: self.chip8.frame_count,
+            instruction_count: self.chip8.instruction_count,
+        };
+        self.last_score = score;
+        (self.observe(), info)
+    }
+
+    // Returns a copy of the machine state, e.g. to explore from it several times
+    pub fn clone_state(&self) -> State {
+        self.chip8.save_state()
+    }
+
+    // Restores a machine state returned by clone_state()
+    pub fn restore_state(&mut self, state: &State) {
+        self.chip8.load_state(state);
+        self.last_score = self.score();
+    }
+
+    // Returns the current observation
+    pub fn observe(&self) -> Observation {
+        Observation {
+            display: self.chip8.display,
+            ram: self.chip8.bus.ram,
+        }
+    }
+
+    // Returns the machine, e.g. to read its registers
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    fn score(&self) -> u32 {
+        self.score.as_ref().map_or(0, |s| s.eval(&self.chip8))
+    }
+
+    fn is_done(&self) -> bool {
+        self.done.as_ref().is_some_and(|d| d.is_true(&self.chip8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    // Stores a random byte at 0x300, and while the key 5 is pressed counts at 0x301
+    const ROM: [u8; 22] = [
+        0xC0, 0xFF, // RND V0, 0xFF
+        0xA3, 0x00, // LD I, 0x300
+        0xF0, 0x55, // LD [I], V0
+        0x62, 0x05, // LD V2, 5
+        0xE2, 0x9E, // SKP V2
+        0x12, 0x00, // JMP 0x200
+        0x71, 0x01, // ADD V1, 1
+        0x80, 0x10, // LD V0, V1
+        0xA3, 0x01, // LD I, 0x301
+        0xF0, 0x55, // LD [I], V0
+        0x12, 0x00, // JMP 0x200
+    ];
+    const KEY_5: u16 = 1 << 5;
+
+    fn env() -> Env {
+        let mut env = Env::new(ROM.to_vec(), Quirks::default(), 15);
+        env.set_score("[0x301]").unwrap();
+        env.set_done("[0x301] >= 10").unwrap();
+        env
+    }
+
+    // Runs an episode with the seed, returns the RAM after each step
+    fn episode(env: &mut Env, seed: u64) -> Vec<Vec<u8>> {
+        env.reset(seed);
+        (0..8)
+            .map(|i| {
+                let action = if i % 3 == 0 { KEY_5 } else { 0 };
+                env.step(action, 1).0.ram.to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_and_actions_give_the_same_episode() {
+        let (mut a, mut b) = (env(), env());
+        let first = episode(&mut a, 7);
+        assert_eq!(first, episode(&mut b, 7));
+        assert_eq!(first, episode(&mut a, 7));
+        assert_ne!(first, episode(&mut a, 8));
+    }
+
+    #[test]
+    fn reward_is_the_score_change_until_done() {
+        let mut env = env();
+        env.reset(0);
+        let (_, info) = env.step(0, 2);
+        assert_eq!((info.reward, info.score, info.done), (0.0, 0, false));
+        let (observation, info) = env.step(KEY_5, 100);
+        assert!(info.done);
+        assert!(info.frames < 100);
+        assert_eq!(info.score, observation.ram[0x301] as u32);
+        assert_eq!(info.reward, info.score as f64);
+        assert_eq!(info.frame_count, 2 + info.frames as u64);
+    }
+
+    #[test]
+    fn restored_state_replays_the_same_steps() {
+        let mut env = env();
+        env.reset(3);
+        env.step(KEY_5, 2);
+        let state = env.clone_state();
+        let (first, first_info) = env.step(KEY_5, 3);
+        env.restore_state(&state);
+        let (second, second_info) = env.step(KEY_5, 3);
+        assert_eq!(first.ram, second.ram);
+        assert_eq!(first.display, second.display);
+        assert_eq!(first_info.reward, second_info.reward);
+
+        // Also through the serialized state
+        let state = State::from_bytes(&state.to_bytes()).unwrap();
+        env.restore_state(&state);
+        assert_eq!(env.step(KEY_5, 3).0.ram, first.ram);
+    }
+
+    // Wall-clock benchmark, run in release: cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn runs_thousands_of_steps_per_second() {
+        let mut env = env();
+        env.reset(0);
+        let steps = 10_000;
+        let start = Instant::now();
+        for _ in 0..steps {
+            env.step(0, 1);
+        }
+        let rate = steps as f64 / start.elapsed().as_secs_f64();
+        assert!(rate > 1000.0, "{:.0} steps/s", rate);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::chip8::Chip8;
+use crate::constants;
+use crate::symbols::Symbols;
+
+use std::fmt;
+
+// Machine register usable in expressions and register watches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    // Parses a register name: V0-VF, I, PC, SP, DT or ST
+    pub fn parse(name: &str) -> Result<Register, String> {
+        let upper = name.to_uppercase();
+        match upper.as_str() {
+            "I" => Ok(Register::I),
+            "PC" => Ok(Register::Pc),
+            "SP" => Ok(Register::Sp),
+            "DT" => Ok(Register::Dt),
+            "ST" => Ok(Register::St),
+            _ => match upper.strip_prefix('V') {
+                Some(x) if x.len() == 1 => match usize::from_str_radix(x, 16) {
+                    Ok(x) => Ok(Register::V(x)),
+                    Err(_) => Err(format!("Unknown register '{}'", name)),
+                },
+                _ => Err(format!("Unknown register '{}'", name)),
+            },
+        }
+    }
+
+    // Returns the current value of the register
+    pub fn value(&self, chip8: &Chip8) -> u32 {
+        match self {
+            Register::V(x) => chip8.registers[*x] as u32,
+            Register::I => chip8.index as u32,
+            Register::Pc => chip8.pc as u32,
+            Register::Sp => chip8.istack as u32,
+            Register::Dt => chip8.dt as u32,
+            Register::St => chip8.st as u32,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+// Parses a number in hex with the 0x prefix, or in decimal
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{}'", text))
+}
+
+// Parses an address in hex with the 0x prefix, or in decimal
+pub fn parse_addr(text: &str) -> Result<usize, String> {
+    let addr = parse_number(text)? as usize;
+    if addr >= constants::RAM_SIZE {
+        return Err(format!("Address out of memory: '{}'", text));
+    }
+    Ok(addr)
+}
+
+// Boolean/arithmetic expression over the machine state, e.g. 'V3 == 0x10 && I > 0x300'.
+// Operands are numbers, labels, registers and memory bytes ([addr]). Binary operators, from
+// lowest to highest precedence: ||, &&, comparisons (== != < <= > >=), bitwise (& | ^)
+// and + -. The unary ! negates. Comparisons and logical operators evaluate to 0 or 1.
+#[derive(Clone, Debug)]
+pub struct Expr {
+    // Source text
+    pub source: String,
+    root: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(u32),
+    Reg(Register),
+    Mem(Box<Node>),
+    Not(Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+impl Expr {
+    pub fn parse(source: &str, symbols: &Symbols) -> Result<Expr, String> {
+        let tokens = tokenize(source, symbols)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let root = parser.binary(0)?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!(
+                "Unexpected '{}' in '{}'",
+                parser.tokens[parser.pos], source
+            ));
+        }
+        Ok(Expr {
+            source: source.trim().to_string(),
+            root,
+        })
+    }
+
+    // Evaluates the expression against the machine state
+    pub fn eval(&self, chip8: &Chip8) -> u32 {
+        eval(&self.root, chip8)
+    }
+
+    // Returns true if the expression evaluates to non-zero
+    pub fn is_true(&self, chip8: &Chip8) -> bool {
+        self.eval(chip8) != 0
+    }
+}
+
+fn eval(node: &Node, chip8: &Chip8) -> u32 {
+    match node {
+        Node::Num(n) => *n,
+        Node::Reg(r) => r.value(chip8),
+        Node::Mem(addr) => chip8.bus.ram[eval(addr, chip8) as usize % constants::RAM_SIZE] as u32,
+        Node::Not(a) => (eval(a, chip8) == 0) as u32,
+        Node::Binary(op, a, b) => {
+            let (a, b) = (eval(a, chip8), eval(b, chip8));
+            match *op {
+                "||" => (a != 0 || b != 0) as u32,
+                "&&" => (a != 0 && b != 0) as u32,
+                "==" => (a == b) as u32,
+                "!=" => (a != b) as u32,
+                "<" => (a < b) as u32,
+                "<=" => (a <= b) as u32,
+                ">" => (a > b) as u32,
+                ">=" => (a >= b) as u32,
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "&" => a & b,
+                "|" => a | b,
+                "^" => a ^ b,
+                _ => 0,
+            }
+        }
+    }
+}
+
+// Binary operators by precedence level, lowest first
+const LEVELS: [&[&str]; 5] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<=", ">=", "<", ">"],
+    &["|", "^", "&"],
+    &["+", "-"],
+];
+
+// Operators, longest first so that they are tokenized greedily
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "^", "&", "+", "-", "!", "(", ")", "[", "]",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(u32),
+    Reg(Register),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Reg(r) => write!(f, "{}", r),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+fn tokenize(source: &str, symbols: &Symbols) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        // Labels may contain dashes, take the longest one
+        let run = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(rest.len());
+        let label = rest[..run]
+            .match_indices('-')
+            .map(|(i, _)| i)
+            .chain(std::iter::once(run))
+            .rev()
+            .find_map(|len| Some((len, symbols.addr(&rest[..len])?)));
+
+        if let Some((len, addr)) = label {
+            tokens.push(Token::Num(addr as u32));
+            rest = &rest[len..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                return Err(format!("Unexpected character in '{}'", source));
+            }
+            let word = &rest[..len];
+            if word.starts_with(|c: char| c.is_ascii_digit()) {
+                tokens.push(Token::Num(parse_number(word)?));
+            } else {
+                let reg = Register::parse(word).map_err(|_| format!("Unknown name '{}'", word))?;
+                tokens.push(Token::Reg(reg));
+            }
+            rest = &rest[len..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Parses a chain of binary operators of the given precedence level and above
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            if !LEVELS[level].contains(op) {
+                break;
+            }
+            let op = *op;
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Node::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Node::Num(n)),
+            Some(Token::Reg(r)) => Ok(Node::Reg(r)),
+            Some(Token::Op("!")) => Ok(Node::Not(Box::new(self.unary()?))),
+            Some(Token::Op("(")) => {
+                let node = self.binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Some(Token::Op("[")) => {
+                let node = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Node::Mem(Box::new(node)))
+            }
+            Some(t) => Err(format!("Unexpected '{}'", t)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(o)) if *o == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(format!("Expected '{}'", op)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    // Labels, loaded once for the tests running in parallel
+    fn symbols() -> &'static Symbols {
+        static SYMBOLS: OnceLock<Symbols> = OnceLock::new();
+        SYMBOLS.get_or_init(|| {
+            let path = std::env::temp_dir().join(format!("rchip8-expr-{}.sym", std::process::id()));
+            std::fs::write(&path, "0x300 lives\n0x302 game-over").unwrap();
+            let symbols = Symbols::load(path.to_str().unwrap()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            symbols
+        })
+    }
+
+    fn eval(source: &str) -> Result<u32, String> {
+        let mut chip8 = Chip8::new(vec![], 0, 1, false);
+        chip8.registers[3] = 0x10;
+        chip8.index = 0x300;
+        chip8.bus.ram[0x300] = 3;
+        chip8.bus.ram[0x302] = 1;
+        Ok(Expr::parse(source, symbols())?.eval(&chip8))
+    }
+
+    #[test]
+    fn parses_registers_and_numbers() {
+        assert_eq!(Register::parse("vf"), Ok(Register::V(15)));
+        assert_eq!(Register::parse("PC"), Ok(Register::Pc));
+        assert!(Register::parse("V10").is_err());
+        assert_eq!(parse_number("0x1F"), Ok(31));
+        assert_eq!(parse_number(" 42 "), Ok(42));
+        assert!(parse_number("0xZ").is_err());
+        assert_eq!(parse_addr("0xFFF"), Ok(0xFFF));
+        assert!(parse_addr("4096").is_err());
+    }
+
+    #[test]
+    fn evaluates_by_precedence() {
+        assert_eq!(eval("V3 == 0x10 && I > 0x2FF"), Ok(1));
+        assert_eq!(eval("1 + 2 == 3"), Ok(1));
+        assert_eq!(eval("1 | 2 == 3"), Ok(1));
+        assert_eq!(eval("0 || 0 && 1"), Ok(0));
+        assert_eq!(eval("!(V3 - 0x10)"), Ok(1));
+        assert_eq!(eval("0 - 1"), Ok(u32::MAX));
+    }
+
+    #[test]
+    fn reads_memory_and_labels() {
+        assert_eq!(eval("[I]"), Ok(3));
+        assert_eq!(eval("[lives] + [game-over]"), Ok(4));
+        assert_eq!(eval("game-over-lives"), Ok(2));
+        // Addresses wrap around the memory
+        assert_eq!(eval("[0x1300]"), Ok(3));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for source in ["", "V3 ==", "(1", "[I", "1 2", "foo", "V3 $ 1"] {
+            assert!(eval(source).is_err(), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use crate::breakpoints::{StopReason, WatchKind, Watchpoint};
+use crate::bus::AccessKind;
+use crate::chip8::Chip8;
+use crate::constants;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Register layout exposed to GDB: V0-VF, I, PC, SP, DT, ST
+const N_GDB_REGS: usize = constants::N_REGISTERS + 5;
+const REG_I: usize = constants::N_REGISTERS;
+const REG_PC: usize = constants::N_REGISTERS + 1;
+const REG_SP: usize = constants::N_REGISTERS + 2;
+const REG_DT: usize = constants::N_REGISTERS + 3;
+const REG_ST: usize = constants::N_REGISTERS + 4;
+
+// Signals reported in the stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Requests from GDB to the main loop
+pub enum GdbAction {
+    // Stop the emulation (GDB attached or interrupted)
+    Halt,
+    // Resume the emulation (continue or detach)
+    Resume,
+}
+
+// GDB remote serial protocol server on a local TCP port.
+// Supports register and memory read/write, software breakpoints, watchpoints,
+// single-step and continue.
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    // Received bytes not processed yet
+    input: Vec<u8>,
+    // Whether GDB is waiting for a stop reply after a continue
+    running: bool,
+}
+
+impl GdbStub {
+    // Listens on the given port of the loopback interface
+    pub fn bind(port: u16) -> Result<Self, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| format!("Error listening on port {}: {}", port, e))?;
+        listener.set_nonblocking(true).unwrap();
+        Ok(GdbStub {
+            listener,
+            client: None,
+            input: Vec::new(),
+            running: false,
+        })
+    }
+
+    // Accepts connections and handles the pending packets, returns the requested action if any
+    pub fn poll(&mut self, chip8: &mut Chip8) -> Option<GdbAction> {
+        let mut action = None;
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    println!("GDB connected from {}", addr);
+                    stream.set_nonblocking(true).unwrap();
+                    stream.set_nodelay(true).unwrap();
+                    self.client = Some(stream);
+                    self.input.clear();
+                    self.running = false;
+                    // The target is stopped while GDB is attached
+                    action = Some(GdbAction::Halt);
+                }
+                Err(_) => return None,
+            }
+        }
+
+        // Read everything available
+        let mut buf = [0_u8; 4096];
+        loop {
+            let read = match self.client.as_mut() {
+                Some(client) => client.read(&mut buf),
+                None => return action,
+            };
+            match read {
+                Ok(0) => {
+                    println!("GDB disconnected");
+                    self.client = None;
+                    return Some(GdbAction::Resume);
+                }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("GDB connection error: {}", e);
+                    self.client = None;
+                    return Some(GdbAction::Resume);
+                }
+            }
+        }
+
+        // Process the complete packets
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        self.running = false;
+                        self.send(&format!("S{:02x}", SIGINT));
+                    }
+                    action = Some(GdbAction::Halt);
+                }
+                // Ask for a retransmission
+                Packet::Corrupt => self.send_raw(b"-"),
+                Packet::Command(cmd) => {
+                    self.send_raw(b"+");
+                    if let Some(a) = self.handle(&cmd, chip8) {
+                        action = Some(a);
+                    }
+                }
+            }
+        }
+        action
+    }
+
+    // Reports a breakpoint or watchpoint stop to GDB if it is waiting for one
+    pub fn stopped(&mut self, reason: &StopReason) {
+        if self.running {
+            self.running = false;
+            let reply = match reason {
+                StopReason::Watchpoint(access) => {
+                    let watch = match access.kind {
+                        AccessKind::Read => "rwatch",
+                        AccessKind::Write => "watch",
+                    };
+                    format!("T{:02x}{}:{:x};", SIGTRAP, watch, access.addr)
+                }
+                _ => format!("S{:02x}", SIGTRAP),
+            };
+            self.send(&reply);
+        }
+    }
+
+    // Extracts the next interrupt or packet from the input, skipping acknowledgements
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.input.first() {
+                None => return None,
+                Some(0x03) => {
+                    self.input.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                Some(b'$') => {
+                    // Wait for the '#' and the two checksum digits
+                    let end = self.input.iter().position(|b| *b == b'#')?;
+                    if self.input.len() < end + 3 {
+                        return None;
+                    }
+                    let data = &self.input[1..end];
+                    let checksum = std::str::from_utf8(&self.input[end + 1..end + 3])
+                        .ok()
+                        .and_then(|c| u8::from_str_radix(c, 16).ok());
+                    let packet = if checksum == Some(checksum_of(data)) {
+                        Packet::Command(String::from_utf8_lossy(data).to_string())
+                    } else {
+                        Packet::Corrupt
+                    };
+                    self.input.drain(..end + 3);
+                    return Some(packet);
+                }
+                // Acknowledgements and noise
+                Some(_) => {
+                    self.input.remove(0);
+                }
+            }
+        }
+    }
+
+    // Handles a command packet, returns the requested action if any
+    fn handle(&mut self, cmd: &str, chip8: &mut Chip8) -> Option<GdbAction> {
+        let (kind, args) = cmd.split_at(1.min(cmd.len()));
+        match kind {
+            // Stop reason
+            "?" => self.send(&format!("S{:02x}", SIGTRAP)),
+            // Read all registers
+            "g" => {
+                let regs: String = (0..N_GDB_REGS).map(|r| read_reg(chip8, r)).collect();
+                self.send(&regs);
+            }
+            // Write all registers
+            "G" => {
+                let bytes = hex::decode(args).unwrap_or_default();
+                let mut pos = 0;
+                let mut valid = true;
+                for r in 0..N_GDB_REGS {
+                    let size = reg_size(r);
+                    if pos + size > bytes.len() {
+                        break;
+                    }
+                    valid &= write_reg(chip8, r, &bytes[pos..pos + size]);
+                    pos += size;
+                }
+                self.send(if valid { "OK" } else { "E01" });
+            }
+            // Read one register
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(r) if r < N_GDB_REGS => self.send(&read_reg(chip8, r)),
+                _ => self.send("E01"),
+            },
+            // Write one register
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, v)| {
+                    Some((usize::from_str_radix(r, 16).ok()?, hex::decode(v).ok()?))
+                });
+                match parsed {
+                    Some((r, bytes))
+                        if r < N_GDB_REGS
+                            && bytes.len() == reg_size(r)
+                            && write_reg(chip8, r, &bytes) =>
+                    {
+                        self.send("OK");
+                    }
+                    _ => self.send("E01"),
+                }
+            }
+            // Read memory
+            "m" => match parse_range(args) {
+                Some((addr, len)) if in_ram(addr, len) => {
+                    self.send(&hex::encode(&chip8.bus.ram[addr..addr + len]));
+                }
+                _ => self.send("E01"),
+            },
+            // Write memory
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, hex::decode(data).ok()?)));
+                match parsed {
+                    Some(((addr, len), bytes)) if bytes.len() == len && in_ram(addr, len) => {
+                        chip8.bus.ram[addr..addr + len].copy_from_slice(&bytes);
+                        self.send("OK");
+                    }
+                    _ => self.send("E01"),
+                }
+            }
+            // Insert/remove software breakpoints (type 0) and watchpoints (types 2-4)
+            "Z" | "z" => {
+                let parsed = args
+                    .split_once(',')
+                    .and_then(|(t, range)| Some((t, parse_range(range)?)));
+                let insert = kind == "Z";
+                match parsed {
+                    Some(("0", (addr, _))) => {
+                        if insert {
+                            chip8.breakpoints.pc.insert(addr, None);
+                        } else {
+                            chip8.breakpoints.pc.remove(&addr);
+                        }
+                        self.send("OK");
+                    }
+                    Some((t @ ("2" | "3" | "4"), (addr, len))) if len > 0 => {
+                        let kind = match t {
+                            "2" => WatchKind::Write,
+                            "3" => WatchKind::Read,
+                            _ => WatchKind::Access,
+                        };
+                        let watchpoints = &mut chip8.breakpoints.watchpoints;
+                        let Some(end) = addr.checked_add(len - 1) else {
+                            self.send("E01");
+                            return None;
+                        };
+                        if insert {
+                            watchpoints.push(Watchpoint {
+                                start: addr,
+                                end,
+                                kind,
+                            });
+                        } else {
+                            watchpoints
+                                .retain(|w| w.start != addr || w.end != end || w.kind != kind);
+                        }
+                        self.send("OK");
+                    }
+                    // Other breakpoint types are not supported
+                    _ => self.send(""),
+                }
+            }
+            // Single step, optionally from the given address
+            "s" => {
+                if !resume_at(chip8, args) {
+                    self.send("E01");
+                    return None;
+                }
+                chip8.step();
+                self.send(&format!("S{:02x}", SIGTRAP));
+            }
+            // Continue, optionally from the given address
+            "c" => {
+                if !resume_at(chip8, args) {
+                    self.send("E01");
+                    return None;
+                }
+                self.running = true;
+                return Some(GdbAction::Resume);
+            }
+            // Detach
+            "D" => {
+                self.send("OK");
+                println!("GDB detached");
+                self.client = None;
+                return Some(GdbAction::Resume);
+            }
+            // Kill, resume the emulation without GDB
+            "k" => {
+                self.client = None;
+                return Some(GdbAction::Resume);
+            }
+            // Thread selection, there is only one thread
+            "H" => self.send("OK"),
+            "q" => self.handle_query(args),
+            _ => self.send(""),
+        }
+        None
+    }
+
+    // Handles the general query packets
+    fn handle_query(&mut self, query: &str) {
+        if query.starts_with("Supported") {
+            self.send("PacketSize=4000;qXfer:features:read+");
+        } else if query == "Attached" {
+            self.send("1");
+        } else if query == "C" {
+            self.send("QC1");
+        } else if query == "fThreadInfo" {
+            self.send("m1");
+        } else if query == "sThreadInfo" {
+            self.send("l");
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = offset.saturating_add(len).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    self.send(&format!("{}{}", prefix, &xml[start..end]));
+                }
+                None => self.send("E01"),
+            }
+        } else {
+            self.send("");
+        }
+    }
+
+    // Sends a packet with its checksum
+    fn send(&mut self, data: &str) {
+        let checksum = checksum_of(data.as_bytes());
+        self.send_raw(format!("${}#{:02x}", data, checksum).as_bytes());
+    }
+
+    // Sends raw bytes, waiting for the socket to accept them
+    fn send_raw(&mut self, mut data: &[u8]) {
+        if let Some(client) = self.client.as_mut() {
+            while !data.is_empty() {
+                match client.write(data) {
+                    Ok(n) => data = &data[n..],
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::yield_now(),
+                    Err(e) => {
+                        println!("GDB connection error: {}", e);
+                        self.client = None;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Packet {
+    Interrupt,
+    // Packet whose checksum does not match its data
+    Corrupt,
+    Command(String),
+}
+
+// Returns the modulo 256 sum of the packet data
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0_u8, |acc, b| acc.wrapping_add(*b))
+}
+
+// Returns whether the len bytes from addr are in the RAM
+fn in_ram(addr: usize, len: usize) -> bool {
+    addr.checked_add(len)
+        .is_some_and(|end| end <= constants::RAM_SIZE)
+}
+
+// Moves the PC to the optional resume address of 's' and 'c', returns false if it is
+// out of the RAM
+fn resume_at(chip8: &mut Chip8, args: &str) -> bool {
+    if args.is_empty() {
+        return true;
+    }
+    match usize::from_str_radix(args, 16) {
+        Ok(addr) if addr < constants::RAM_SIZE => {
+            chip8.pc = addr;
+            true
+        }
+        _ => false,
+    }
+}
+
+// Returns the size in bytes of the given register
+fn reg_size(reg: usize) -> usize {
+    match reg {
+        REG_I | REG_PC => 2,
+        _ => 1,
+    }
+}
+
+// Returns the value of the register as little-endian hex
+fn read_reg(chip8: &Chip8, reg: usize) -> String {
+    match reg {
+        REG_I => hex::encode(chip8.index.to_le_bytes()),
+        REG_PC => hex::encode((chip8.pc as u16).to_le_bytes()),
+        REG_SP => hex::encode([chip8.istack as u8]),
+        REG_DT => hex::encode([chip8.dt]),
+        REG_ST => hex::encode([chip8.st]),
+        _ => hex::encode([chip8.registers[reg]]),
+    }
+}
+
+// Sets the register from its little-endian bytes, returns false if the PC or I would
+// point out of the RAM
+fn write_reg(chip8: &mut Chip8, reg: usize, bytes: &[u8]) -> bool {
+    match reg {
+        REG_I | REG_PC => {
+            let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+            if value as usize >= constants::RAM_SIZE {
+                return false;
+            }
+            if reg == REG_I {
+                chip8.index = value;
+            } else {
+                chip8.pc = value as usize;
+            }
+        }
+        REG_SP => chip8.istack = (bytes[0] as usize).min(constants::STACK_SIZE - 1),
+        REG_DT => chip8.dt = bytes[0],
+        REG_ST => chip8.st = bytes[0],
+        _ => chip8.registers[reg] = bytes[0],
+    }
+    true
+}
+
+// Returns the target description with the register layout
+fn target_xml() -> String {
+    let mut regs = String::new();
+    for r in 0..constants::N_REGISTERS {
+        regs.push_str(&format!(
+            "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>",
+            r
+        ));
+    }
+    regs.push_str("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>");
+    regs.push_str("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>");
+    for name in ["sp", "dt", "st"] {
+        regs.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>",
+            name
+        ));
+    }
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.rchip8.core\">{}</feature></target>",
+        regs
+    )
+}
+
+// Parses an 'addr,length' pair in hex
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    // Polls the stub until the client received a complete reply, returns it with its
+    // acknowledgement if any
+    fn reply(stub: &mut GdbStub, chip8: &mut Chip8, client: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            stub.poll(chip8);
+            let mut buf = [0_u8; 4096];
+            match client.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("{}", e),
+            }
+            let text = String::from_utf8_lossy(&received).to_string();
+            let complete = match text.find('#') {
+                Some(end) => text.len() >= end + 3,
+                None => text == "-",
+            };
+            if complete {
+                return text;
+            }
+            assert!(Instant::now() < deadline, "No reply, received '{}'", text);
+        }
+    }
+
+    // Sends the command and returns the data of the reply, checking its checksum
+    fn command(stub: &mut GdbStub, chip8: &mut Chip8, client: &mut TcpStream, cmd: &str) -> String {
+        let packet = format!("${}#{:02x}", cmd, checksum_of(cmd.as_bytes()));
+        client.write_all(packet.as_bytes()).unwrap();
+        let text = reply(stub, chip8, client);
+        let data = text
+            .strip_prefix("+$")
+            .and_then(|t| t.split_once('#'))
+            .unwrap_or_else(|| panic!("Invalid reply '{}'", text));
+        assert_eq!(data.1, format!("{:02x}", checksum_of(data.0.as_bytes())));
+        data.0.to_string()
+    }
+
+    #[test]
+    fn serves_a_client_over_loopback() {
+        // LD V0, 5; ADD V0, 1; JMP 0x202
+        let rom = vec![0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+        let mut chip8 = Chip8::new(rom, 0, 10, false);
+        let mut stub = GdbStub::bind(0).unwrap();
+        let port = stub.listener.local_addr().unwrap().port();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !matches!(stub.poll(&mut chip8), Some(GdbAction::Halt)) {
+            assert!(Instant::now() < deadline, "Not connected");
+        }
+
+        let regs = command(&mut stub, &mut chip8, &mut client, "g");
+        let expected = "00".repeat(constants::N_REGISTERS) + "0000" + "0002" + "000000";
+        assert_eq!(regs, expected);
+
+        // Memory write and read, out of the RAM and overflowing ranges
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "M300,2:abcd"),
+            "OK"
+        );
+        assert_eq!(chip8.bus.ram[0x300..0x302], [0xab, 0xcd]);
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "m300,2"),
+            "abcd"
+        );
+        assert_eq!(command(&mut stub, &mut chip8, &mut client, "mfff,2"), "E01");
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "m1,ffffffffffffffff"),
+            "E01"
+        );
+        assert_eq!(command(&mut stub, &mut chip8, &mut client, "Z2,1,0"), "");
+
+        // PC and I out of the RAM
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "P11=0010"),
+            "E01"
+        );
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "P10=0010"),
+            "E01"
+        );
+        assert_eq!(chip8.pc, 0x200);
+        assert_eq!(command(&mut stub, &mut chip8, &mut client, "s1000"), "E01");
+
+        // Single step
+        assert_eq!(command(&mut stub, &mut chip8, &mut client, "s"), "S05");
+        assert_eq!((chip8.pc, chip8.registers[0]), (0x202, 5));
+
+        // Continue up to a breakpoint
+        assert_eq!(
+            command(&mut stub, &mut chip8, &mut client, "Z0,204,2"),
+            "OK"
+        );
+        assert!(chip8.breakpoints.pc.contains_key(&0x204));
+        let packet = format!("$c#{:02x}", checksum_of(b"c"));
+        client.write_all(packet.as_bytes()).unwrap();
+        while !stub.running {
+            stub.poll(&mut chip8);
+        }
+        chip8.cycle(constants::TIMER_PERIOD_NS);
+        let reason = chip8.stop.take().expect("No breakpoint hit");
+        stub.stopped(&reason);
+        assert_eq!(reply(&mut stub, &mut chip8, &mut client), "+$S05#b8");
+        assert_eq!((chip8.pc, chip8.registers[0]), (0x204, 6));
+
+        // Corrupt packets are rejected
+        client.write_all(b"$g#00").unwrap();
+        assert_eq!(reply(&mut stub, &mut chip8, &mut client), "-");
+    }
+}
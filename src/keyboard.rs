@@ -1,4 +1,97 @@
-use sdl2::keyboard::Scancode;
+use crate::constants;
+
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::EventPump;
+
+// Keys of the keypad, indexed by CHIP-8 key code
+pub type Keymap = [Scancode; constants::N_KEYS];
+
+// Names of the hotkey actions
+pub const HOTKEY_NAMES: [&str; 14] = [
+    "quit",
+    "pause",
+    "frame_advance",
+    "fast_forward",
+    "reset",
+    "speed_up",
+    "speed_down",
+    "cheat_search",
+    "cheat_changed",
+    "cheat_unchanged",
+    "cheat_increased",
+    "cheat_decreased",
+    "cheat_freeze",
+    "cheat_toggle",
+];
+
+// Keys bound to the emulator actions
+#[derive(Clone)]
+pub struct Hotkeys {
+    pub quit: Vec<Keycode>,
+    pub pause: Vec<Keycode>,
+    pub frame_advance: Vec<Keycode>,
+    pub fast_forward: Vec<Keycode>,
+    pub reset: Vec<Keycode>,
+    pub speed_up: Vec<Keycode>,
+    pub speed_down: Vec<Keycode>,
+    // Cheat memory search: start, keep the changed/unchanged/increased/decreased bytes
+    pub cheat_search: Vec<Keycode>,
+    pub cheat_changed: Vec<Keycode>,
+    pub cheat_unchanged: Vec<Keycode>,
+    pub cheat_increased: Vec<Keycode>,
+    pub cheat_decreased: Vec<Keycode>,
+    // Freeze the bytes found, toggle the cheats
+    pub cheat_freeze: Vec<Keycode>,
+    pub cheat_toggle: Vec<Keycode>,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Hotkeys {
+            quit: vec![Keycode::Escape, Keycode::CapsLock],
+            pause: vec![Keycode::P],
+            frame_advance: vec![Keycode::N],
+            fast_forward: vec![Keycode::Tab],
+            reset: vec![Keycode::Backspace],
+            speed_up: vec![Keycode::Plus, Keycode::Equals, Keycode::KpPlus],
+            speed_down: vec![Keycode::Minus, Keycode::KpMinus],
+            cheat_search: vec![Keycode::F5],
+            cheat_changed: vec![Keycode::F6],
+            cheat_unchanged: vec![Keycode::F7],
+            cheat_increased: vec![Keycode::F8],
+            cheat_decreased: vec![Keycode::F9],
+            cheat_freeze: vec![Keycode::F10],
+            cheat_toggle: vec![Keycode::F11],
+        }
+    }
+}
+
+impl Hotkeys {
+    // Returns the keys bound to the action with the given name
+    pub fn binding(&mut self, name: &str) -> Result<&mut Vec<Keycode>, String> {
+        match name {
+            "quit" => Ok(&mut self.quit),
+            "pause" => Ok(&mut self.pause),
+            "frame_advance" => Ok(&mut self.frame_advance),
+            "fast_forward" => Ok(&mut self.fast_forward),
+            "reset" => Ok(&mut self.reset),
+            "speed_up" => Ok(&mut self.speed_up),
+            "speed_down" => Ok(&mut self.speed_down),
+            "cheat_search" => Ok(&mut self.cheat_search),
+            "cheat_changed" => Ok(&mut self.cheat_changed),
+            "cheat_unchanged" => Ok(&mut self.cheat_unchanged),
+            "cheat_increased" => Ok(&mut self.cheat_increased),
+            "cheat_decreased" => Ok(&mut self.cheat_decreased),
+            "cheat_freeze" => Ok(&mut self.cheat_freeze),
+            "cheat_toggle" => Ok(&mut self.cheat_toggle),
+            _ => Err(format!(
+                "Unknown hotkey '{}', expected one of: {}",
+                name,
+                HOTKEY_NAMES.join(", ")
+            )),
+        }
+    }
+}
 
 // Converts bytes into scan codes
 // The mapping is done with the following keys:
@@ -28,24 +121,39 @@ pub fn map(code: u8) -> Scancode {
     }
 }
 
-pub fn unmap(scancode: Scancode) -> Option<u8> {
-    match scancode {
-        Scancode::X => Some(0x00),
-        Scancode::Num1 => Some(0x01),
-        Scancode::Num2 => Some(0x02),
-        Scancode::Num3 => Some(0x03),
-        Scancode::Q => Some(0x04),
-        Scancode::W => Some(0x05),
-        Scancode::E => Some(0x06),
-        Scancode::A => Some(0x07),
-        Scancode::S => Some(0x08),
-        Scancode::D => Some(0x09),
-        Scancode::Z => Some(0x0A),
-        Scancode::C => Some(0x0B),
-        Scancode::Num4 => Some(0x0C),
-        Scancode::R => Some(0x0D),
-        Scancode::F => Some(0x0E),
-        Scancode::V => Some(0x0F),
+// Returns the default keymap, see map()
+pub fn default_keymap() -> Keymap {
+    std::array::from_fn(|code| map(code as u8))
+}
+
+// Returns the key of a game button of the ROM database:
+// the arrows for up, down, left and right, Space for A and Left Shift for B
+pub fn button(name: &str) -> Option<Scancode> {
+    match name {
+        "up" => Some(Scancode::Up),
+        "down" => Some(Scancode::Down),
+        "left" => Some(Scancode::Left),
+        "right" => Some(Scancode::Right),
+        "a" => Some(Scancode::Space),
+        "b" => Some(Scancode::LShift),
         _ => None,
     }
 }
+
+// Reads the current state of the keypad keys, and of the extra keys bound to them
+pub fn read(
+    event_pump: &EventPump,
+    keys: &mut [bool; constants::N_KEYS],
+    keymap: &Keymap,
+    extra: &[(Scancode, u8)],
+) {
+    let state = event_pump.keyboard_state();
+    for (pressed, scancode) in keys.iter_mut().zip(keymap) {
+        *pressed = state.is_scancode_pressed(*scancode);
+    }
+    for (scancode, code) in extra {
+        if state.is_scancode_pressed(*scancode) {
+            keys[*code as usize & 0x0F] = true;
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use crate::constants;
+use crate::display::Display;
+use crate::font;
+use crate::keyboard::Hotkeys;
+use crate::rom;
+use crate::romdb::{self, RomDb};
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Maximum number of ROMs in the history
+const HISTORY_SIZE: usize = 100;
+// Colors of the selected row and of the secondary text
+const SELECTION_COL: Color = Color::RGB(50, 50, 90);
+const DIM_COL: Color = Color::RGB(120, 120, 140);
+// Width of the list, in fraction of the window width
+const LIST_WIDTH: f32 = 0.6;
+
+// A ROM of the launcher directory
+struct Entry {
+    path: String,
+    hash: String,
+    // Database title, or file name
+    title: String,
+    // Authors and platform from the database
+    details: Vec<String>,
+}
+
+// A ROM in the history, with its last frame
+struct Played {
+    hash: String,
+    // Last time it was played [s since the epoch]
+    time: u64,
+    frame: Option<[u8; constants::DISPLAY_LEN]>,
+}
+
+// Recently played ROMs, saved in the data directory
+pub struct History {
+    played: Vec<Played>,
+}
+
+impl History {
+    // Loads the history, empty if there is none
+    pub fn load() -> History {
+        let mut history = History { played: Vec::new() };
+        let Some(path) = history_path() else {
+            return history;
+        };
+        let Ok(text) = fs::read_to_string(&path) else {
+            return history;
+        };
+        let json: Value = match serde_json::from_str(&text) {
+            Ok(json) => json,
+            Err(error) => {
+                println!("Invalid history '{}': {}", path.display(), error);
+                return history;
+            }
+        };
+        for entry in json.as_array().into_iter().flatten() {
+            let (Some(hash), Some(time)) = (
+                entry.get("sha1").and_then(|h| h.as_str()),
+                entry.get("time").and_then(|t| t.as_u64()),
+            ) else {
+                continue;
+            };
+            let frame = entry
+                .get("frame")
+                .and_then(|f| f.as_str())
+                .and_then(|f| hex::decode(f).ok())
+                .and_then(|f| unpack(&f));
+            history.played.push(Played {
+                hash: hash.to_string(),
+                time,
+                frame,
+            });
+        }
+        history
+    }
+
+    // Records that the ROM with the given SHA-1 was just played and ended on the frame
+    pub fn record(&mut self, hash: &str, frame: &[u8; constants::DISPLAY_LEN]) {
+        self.played.retain(|p| p.hash != hash);
+        self.played.insert(
+            0,
+            Played {
+                hash: hash.to_string(),
+                time: now(),
+                frame: Some(*frame),
+            },
+        );
+        self.played.truncate(HISTORY_SIZE);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = history_path().ok_or("No data directory for the history")?;
+        let error = |e: std::io::Error| format!("Error writing '{}': {}", path.display(), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        let entries: Vec<Value> = self
+            .played
+            .iter()
+            .map(|p| {
+                json!({
+                    "sha1": p.hash,
+                    "time": p.time,
+                    "frame": p.frame.as_ref().map(|f| hex::encode(pack(f))),
+                })
+            })
+            .collect();
+        fs::write(&path, Value::Array(entries).to_string()).map_err(error)
+    }
+
+    fn find(&self, hash: &str) -> Option<&Played> {
+        self.played.iter().find(|p| p.hash == hash)
+    }
+}
+
+// In-window ROM browser listing the ROMs of a directory, the recently played first,
+// with their database metadata and their last frame as preview
+pub struct Launcher {
+    dir: String,
+    entries: Vec<Entry>,
+    history: History,
+    selected: usize,
+    // First visible row
+    scroll: usize,
+}
+
+impl Launcher {
+    // Lists the ROMs of the directory or zip archive
+    pub fn new(dir: &str, db: &RomDb) -> Result<Launcher, String> {
+        let paths: Vec<String> = if rom::is_archive(dir) {
+            rom::archive_roms(dir)?
+                .iter()
+                .map(|name| format!("{}/{}", dir, name))
+                .collect()
+        } else {
+            let files = fs::read_dir(dir).map_err(|e| format!("Error reading '{}': {}", dir, e))?;
+            files
+                .filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .filter(|p| p.is_file())
+                .map(|p| p.to_string_lossy().to_string())
+                .filter(|p| rom::is_rom(p))
+                .collect()
+        };
+        let mut entries = Vec::new();
+        for path in paths {
+            let Ok(rom) = rom::read(&path) else {
+                continue;
+            };
+            let hash = romdb::sha1(&rom);
+            let file_name = Path::new(&path)
+                .file_name()
+                .map_or(path.clone(), |n| n.to_string_lossy().to_string());
+            let entry = match db.lookup(&hash) {
+                Some(info) => {
+                    let mut details = vec![file_name];
+                    if !info.authors.is_empty() {
+                        details.push(format!("By {}", info.authors.join(", ")));
+                    }
+                    details.extend(info.platform);
+                    Entry {
+                        path,
+                        hash,
+                        title: info.title,
+                        details,
+                    }
+                }
+                None => Entry {
+                    path,
+                    hash,
+                    title: file_name,
+                    details: Vec::new(),
+                },
+            };
+            entries.push(entry);
+        }
+        if entries.is_empty() {
+            return Err(format!("No ROMs found in '{}'", dir));
+        }
+        println!("Launcher: {} ROMs in {}", entries.len(), dir);
+        Ok(Launcher {
+            dir: dir.to_string(),
+            entries,
+            history: History::load(),
+            selected: 0,
+            scroll: 0,
+        })
+    }
+
+    // Shows the list until a ROM is chosen with Enter or dropped on the window and
+    // returns its path, or None if the window is closed or the quit hotkey pressed
+    pub fn choose(&mut self, display: &mut Display, hotkeys: &Hotkeys) -> Option<String> {
+        // The recently played first, then by title
+        self.history = History::load();
+        let history = &self.history;
+        self.entries.sort_by_cached_key(|e| {
+            let time = history.find(&e.hash).map_or(0, |p| p.time);
+            (std::cmp::Reverse(time), e.title.to_lowercase())
+        });
+        self.selected = 0;
+        self.scroll = 0;
+        display.set_title(&format!("{} - {}", constants::WINDOW_TITLE, self.dir));
+
+        let mut dirty = true;
+        loop {
+            let events: Vec<Event> = display.event_pump.poll_iter().collect();
+            for event in events {
+                let last = self.entries.len() - 1;
+                let page = self.rows(display);
+                let selected = match event {
+                    Event::Quit { .. }
+                    | Event::Window {
+                        win_event: WindowEvent::Close,
+                        ..
+                    } => return None,
+                    Event::Window { .. } => self.selected,
+                    Event::DropFile { filename, .. } => return Some(filename),
+                    Event::KeyDown {
+                        keycode: Some(key),
+                        repeat: false,
+                        ..
+                    } if hotkeys.quit.contains(&key) => return None,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Return | Keycode::KpEnter),
+                        ..
+                    } => return Some(self.entries[self.selected].path.clone()),
+                    Event::KeyDown {
+                        keycode: Some(key), ..
+                    } => match key {
+                        Keycode::Up => self.selected.saturating_sub(1),
+                        Keycode::Down => (self.selected + 1).min(last),
+                        Keycode::PageUp => self.selected.saturating_sub(page),
+                        Keycode::PageDown => (self.selected + page).min(last),
+                        Keycode::Home => 0,
+                        Keycode::End => last,
+                        _ => self.selected,
+                    },
+                    // Jump to the next title starting with the typed character
+                    Event::TextInput { text, .. } => self.find_next(&text),
+                    _ => continue,
+                };
+                self.selected = selected;
+                dirty = true;
+            }
+            if dirty {
+                self.draw(display);
+                dirty = false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Returns the index of the next entry after the selection whose title starts with
+    // the text, or the selection if none does
+    fn find_next(&self, text: &str) -> usize {
+        let text = text.to_lowercase();
+        let n = self.entries.len();
+        (1..n)
+            .map(|i| (self.selected + i) % n)
+            .find(|i| self.entries[*i].title.to_lowercase().starts_with(&text))
+            .unwrap_or(self.selected)
+    }
+
+    // Returns the font pixel size, the line height and the margin for the display
+    fn metrics(display: &Display) -> (u32, i32, i32) {
+        let px = (display.scale / constants::OSD_SCALE_DIV).max(1);
+        (px, (font::ADVANCE_Y * px) as i32, (2 * px) as i32)
+    }
+
+    // Returns the number of visible list rows, below the header
+    fn rows(&self, display: &Display) -> usize {
+        let (_, line, margin) = Self::metrics(display);
+        let (_, height) = display.canvas.output_size().unwrap();
+        ((height as i32 - 2 * margin) / line - 1).max(1) as usize
+    }
+
+    fn draw(&mut self, display: &mut Display) {
+        let (px, line, margin) = Self::metrics(display);
+        let rows = self.rows(display);
+        let (fgcol, bgcol) = (display.fgcol, display.bgcol);
+        let canvas = &mut display.canvas;
+        let (width, _) = canvas.output_size().unwrap();
+        let list_width = (width as f32 * LIST_WIDTH) as i32;
+        let chars = |w: i32| (w.max(0) as u32 / (font::ADVANCE_X * px)) as usize;
+
+        canvas.set_draw_color(bgcol);
+        canvas.clear();
+
+        // Header
+        let header = format!("{} ROMS - ENTER TO PLAY", self.entries.len());
+        font::draw_text(canvas, &header, margin, margin, px, DIM_COL);
+
+        // List, scrolled to show the selection
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+        for (row, i) in (self.scroll..self.entries.len()).take(rows).enumerate() {
+            let y = margin + line * (row as i32 + 1);
+            if i == self.selected {
+                canvas.set_draw_color(SELECTION_COL);
+                canvas
+                    .fill_rect(Rect::new(0, y - px as i32, list_width as u32, line as u32))
+                    .unwrap();
+            }
+            let title = truncate(&self.entries[i].title, chars(list_width - 2 * margin));
+            font::draw_text(canvas, &title, margin, y, px, fgcol);
+        }
+
+        // Preview of the selection, its last frame if it was played
+        let entry = &self.entries[self.selected];
+        let played = self.history.find(&entry.hash);
+        let x = list_width + margin;
+        let preview_px = ((width as i32 - x - margin) / constants::DISPLAY_WIDTH as i32).max(1);
+        let preview = Rect::new(
+            x,
+            margin,
+            (constants::DISPLAY_WIDTH as i32 * preview_px) as u32,
+            (constants::DISPLAY_HEIGHT as i32 * preview_px) as u32,
+        );
+        match played.and_then(|p| p.frame.as_ref()) {
+            Some(frame) => {
+                let pixels: Vec<Rect> = (0..constants::DISPLAY_LEN)
+                    .filter(|i| frame[*i] > 0)
+                    .map(|i| {
+                        Rect::new(
+                            x + (i % constants::DISPLAY_WIDTH) as i32 * preview_px,
+                            margin + (i / constants::DISPLAY_WIDTH) as i32 * preview_px,
+                            preview_px as u32,
+                            preview_px as u32,
+                        )
+                    })
+                    .collect();
+                canvas.set_draw_color(fgcol);
+                canvas.fill_rects(&pixels).unwrap();
+                canvas.set_draw_color(DIM_COL);
+                canvas.draw_rect(preview).unwrap();
+            }
+            None => {
+                canvas.set_draw_color(DIM_COL);
+                canvas.draw_rect(preview).unwrap();
+                let text = "NO PREVIEW";
+                let w = font::text_width(text, px) as i32;
+                let h = (font::GLYPH_HEIGHT * px) as i32;
+                let (cx, cy) = preview.center().into();
+                font::draw_text(canvas, text, cx - w / 2, cy - h / 2, px, DIM_COL);
+            }
+        }
+
+        // Details of the selection
+        let mut details = entry.details.clone();
+        if let Some(played) = played {
+            details.push(format!("Played {}", ago(now().saturating_sub(played.time))));
+        }
+        let columns = chars(width as i32 - x - margin);
+        let mut y = preview.bottom() + margin;
+        for detail in details {
+            font::draw_text(canvas, &truncate(&detail, columns), x, y, px, DIM_COL);
+            y += line;
+        }
+        canvas.present();
+    }
+}
+
+// Returns the text cut to the given number of characters
+fn truncate(text: &str, chars: usize) -> String {
+    if text.chars().count() <= chars {
+        text.to_string()
+    } else {
+        let mut text: String = text.chars().take(chars.saturating_sub(1)).collect();
+        text.push('.');
+        text
+    }
+}
+
+// Returns the elapsed time in words, e.g. '3 h ago'
+fn ago(seconds: u64) -> String {
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", seconds / 60),
+        3600..=86399 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
+
+// Returns the current time in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn history_path() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("rchip8").join("history.json"))
+}
+
+// Packs the frame into bits, the leftmost pixel in the most significant bit
+fn pack(frame: &[u8; constants::DISPLAY_LEN]) -> Vec<u8> {
+    frame
+        .chunks(8)
+        .map(|pixels| {
+            pixels
+                .iter()
+                .fold(0, |byte, p| (byte << 1) | (*p > 0) as u8)
+        })
+        .collect()
+}
+
+// Unpacks a frame packed by pack()
+fn unpack(bytes: &[u8]) -> Option<[u8; constants::DISPLAY_LEN]> {
+    if bytes.len() * 8 != constants::DISPLAY_LEN {
+        return None;
+    }
+    let mut frame = [0; constants::DISPLAY_LEN];
+    for (i, pixel) in frame.iter_mut().enumerate() {
+        *pixel = (bytes[i / 8] >> (7 - i % 8)) & 1;
+    }
+    Some(frame)
+}
@@ -0,0 +1,29 @@
+// Core of the emulator as a library, without display nor audio device: the machine, its
+// debugging tools, the cheats, the WAV recorder and the reinforcement learning environment
+pub mod breakpoints;
+pub mod bus;
+pub mod cheat;
+pub mod chip8;
+pub mod constants;
+pub mod coverage;
+pub mod debug;
+pub mod detect;
+pub mod env;
+pub mod expr;
+pub mod gdb;
+#[cfg(feature = "libretro")]
+mod libretro;
+pub mod opcode;
+pub mod profile;
+#[cfg(feature = "python")]
+mod python;
+pub mod quirks;
+pub mod romdb;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod symbols;
+pub mod tone;
+pub mod trace;
+pub mod wav;
+#[cfg(feature = "wasm")]
+pub mod wasm;
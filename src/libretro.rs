@@ -0,0 +1,572 @@
+use crate::cheat::Cheat;
+use crate::chip8::{self, Chip8, State};
+use crate::constants;
+use crate::detect;
+use crate::quirks::Quirks;
+use crate::tone::{Oscillator, Tone, Waveform};
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard};
+
+// libretro core, built into the library with the 'libretro' feature, to run in RetroArch
+// and other libretro frontends. It implements the retro_* functions of libretro.h over
+// the machine, with the XRGB8888 pixel format, stereo 16 bit audio in batches, the keypad
+// on the joypad and the keyboard, and serialization of the machine state.
+// The entry points catch the panics, which must not unwind into the frontend, and the
+// frontend is only called back without holding the lock of the core.
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS: c_uint = 11;
+const RETRO_ENVIRONMENT_GET_LOG_INTERFACE: c_uint = 27;
+const RETRO_LOG_ERROR: c_uint = 3;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_DEVICE_KEYBOARD: c_uint = 3;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+// Joypad buttons (RETRO_DEVICE_ID_JOYPAD_*), the CHIP-8 key they press and its
+// description. The D-pad is the 2 4 6 8 cross of the keypad.
+const JOYPAD: [(c_uint, u8, &CStr); constants::N_KEYS] = [
+    (4, 0x2, c"Key 2 (up)"),
+    (5, 0x8, c"Key 8 (down)"),
+    (6, 0x4, c"Key 4 (left)"),
+    (7, 0x6, c"Key 6 (right)"),
+    (8, 0x5, c"Key 5"),
+    (0, 0x0, c"Key 0"),
+    (9, 0x3, c"Key 3"),
+    (1, 0x1, c"Key 1"),
+    (10, 0x7, c"Key 7"),
+    (11, 0x9, c"Key 9"),
+    (12, 0xA, c"Key A"),
+    (13, 0xB, c"Key B"),
+    (2, 0xC, c"Key C"),
+    (3, 0xD, c"Key D"),
+    (14, 0xE, c"Key E"),
+    (15, 0xF, c"Key F"),
+];
+// Keyboard keys (RETROK_*, their ASCII code) of the CHIP-8 keys 0-F, see keyboard::map()
+const KEYBOARD: &[u8; constants::N_KEYS] = b"x123qweasdzc4rfv";
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct InputDescriptor {
+    port: c_uint,
+    device: c_uint,
+    index: c_uint,
+    id: c_uint,
+    description: *const c_char,
+}
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn =
+    unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn =
+    unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+type LogPrintfFn = unsafe extern "C" fn(level: c_uint, fmt: *const c_char, ...);
+
+#[repr(C)]
+struct LogCallback {
+    log: Option<LogPrintfFn>,
+}
+
+// Callbacks of the frontend
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+    log: Option<LogPrintfFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+    log: None,
+});
+
+// Loaded game
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+struct Core {
+    chip8: Chip8,
+    // State with the ROM loaded, restored on reset
+    initial: State,
+    oscillator: Oscillator,
+    // Audio samples generated since the start of the machine
+    generated: u128,
+    // Mono samples of the frame
+    samples: Vec<f32>,
+    // Cheats of the frontend by index
+    cheats: BTreeMap<c_uint, Vec<Cheat>>,
+}
+
+impl Core {
+    fn new(rom: Vec<u8>, path: &str) -> Core {
+        let platform = detect::detect(&rom, path).platform;
+        let ips: u32 = constants::DEF_IPS_STR.parse().unwrap_or(1000);
+        let mut chip8 = Chip8::new(rom, 0, chip8::cycles_per_frame(ips), false);
+        chip8.quirks = platform.and_then(Quirks::platform).unwrap_or_default();
+        let tone = Tone {
+            waveform: Waveform::Square,
+            freq: constants::DEF_FREQ_STR.parse().unwrap_or(440.0),
+            volume: constants::DEF_VOLUME_STR.parse().unwrap_or(0.2),
+            duty: constants::DEF_DUTY_STR.parse().unwrap_or(0.5),
+        };
+        Core {
+            initial: chip8.save_state(),
+            chip8,
+            oscillator: Oscillator::new(tone, constants::AUDIO_SAMPLE_RATE as f32),
+            generated: 0,
+            samples: Vec::new(),
+            cheats: BTreeMap::new(),
+        }
+    }
+
+    // Runs a frame with the given keys pressed, if the frontend reads the input, and
+    // returns its stereo 16 bit audio samples and its XRGB8888 video
+    fn run(&mut self, keys: Option<[bool; constants::N_KEYS]>) -> (Vec<i16>, Vec<u32>) {
+        if let Some(keys) = keys {
+            self.chip8.keys = keys;
+        }
+
+        // Audio from the start of the frame, also after a reset or a loaded state
+        self.generated = self.target(self.frame_t());
+        self.samples.clear();
+        self.chip8.run_frame();
+        let events: Vec<(u128, bool)> = self.chip8.sound_events.drain(..).collect();
+        for (t, on) in events {
+            self.generate(t);
+            self.oscillator.gate = on;
+        }
+        self.generate(self.frame_t());
+        let audio = self
+            .samples
+            .iter()
+            .flat_map(|sample| {
+                let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                [sample, sample]
+            })
+            .collect();
+
+        let (fg, bg) = (xrgb(constants::DEF_FG), xrgb(constants::DEF_BG));
+        let video = self
+            .chip8
+            .display
+            .iter()
+            .map(|on| if *on > 0 { fg } else { bg })
+            .collect();
+        (audio, video)
+    }
+
+    // Returns the time [ns] of the start of the current frame
+    fn frame_t(&self) -> u128 {
+        self.chip8.frame_count as u128 * constants::TIMER_PERIOD_NS
+    }
+
+    // Returns the number of samples from the start up to time t [ns]
+    fn target(&self, t: u128) -> u128 {
+        t * constants::AUDIO_SAMPLE_RATE as u128 / 1_000_000_000
+    }
+
+    // Generates the samples from the last generated one up to time t [ns]
+    fn generate(&mut self, t: u128) {
+        let target = self.target(t);
+        if target > self.generated {
+            let start = self.samples.len();
+            self.samples
+                .resize(start + (target - self.generated) as usize, 0.0);
+            self.oscillator.render(&mut self.samples[start..]);
+            self.generated = target;
+        }
+    }
+}
+
+// Returns the color in XRGB8888
+fn xrgb((r, g, b): (u8, u8, u8)) -> u32 {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+// Returns the keys pressed on the joypad and the keyboard
+fn read_keys(input_state: InputStateFn) -> [bool; constants::N_KEYS] {
+    let pressed = |device, id| unsafe { input_state(0, device, 0, id) } != 0;
+    let mut keys = [false; constants::N_KEYS];
+    for (id, key, _) in JOYPAD {
+        keys[key as usize] |= pressed(RETRO_DEVICE_JOYPAD, id);
+    }
+    for (key, code) in KEYBOARD.iter().enumerate() {
+        keys[key] |= pressed(RETRO_DEVICE_KEYBOARD, *code as c_uint);
+    }
+    keys
+}
+
+// Runs the body of an entry point, returning the default value if it panics. The
+// panic message is printed by the panic hook.
+fn guard<T>(default: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or(default)
+}
+
+// Locks the loaded game, still usable after a panic while it was locked
+fn core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn callbacks() -> MutexGuard<'static, Callbacks> {
+    CALLBACKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Reports the error to the log of the frontend, or to stderr if it has none
+fn log_error(message: &str) {
+    let log = callbacks().log;
+    match (log, CString::new(message)) {
+        (Some(log), Ok(message)) => unsafe {
+            log(RETRO_LOG_ERROR, c"%s\n".as_ptr(), message.as_ptr())
+        },
+        _ => eprintln!("{}", message),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: Option<EnvironmentFn>) {
+    guard((), || {
+        callbacks().environment = callback;
+        let mut log = LogCallback { log: None };
+        let supported = callback.is_some_and(|environment| unsafe {
+            environment(
+                RETRO_ENVIRONMENT_GET_LOG_INTERFACE,
+                &mut log as *mut LogCallback as *mut c_void,
+            )
+        });
+        callbacks().log = log.log.filter(|_| supported);
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: Option<VideoRefreshFn>) {
+    guard((), || callbacks().video_refresh = callback)
+}
+
+// The audio goes in batches, not sample by sample
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: Option<AudioSampleFn>) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: Option<AudioSampleBatchFn>) {
+    guard((), || callbacks().audio_sample_batch = callback)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: Option<InputPollFn>) {
+    guard((), || callbacks().input_poll = callback)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: Option<InputStateFn>) {
+    guard((), || callbacks().input_state = callback)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    guard((), || *core() = None)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemInfo {
+            library_name: c"rchip8".as_ptr(),
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: c"ch8|c8|sc8|xo8|hc8|rom|bin".as_ptr(),
+            need_fullpath: false,
+            block_extract: false,
+        };
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: constants::DISPLAY_WIDTH as c_uint,
+                base_height: constants::DISPLAY_HEIGHT as c_uint,
+                max_width: constants::DISPLAY_WIDTH as c_uint,
+                max_height: constants::DISPLAY_HEIGHT as c_uint,
+                aspect_ratio: constants::DISPLAY_WIDTH as f32 / constants::DISPLAY_HEIGHT as f32,
+            },
+            timing: SystemTiming {
+                fps: constants::FRAME_RATE as f64,
+                sample_rate: constants::AUDIO_SAMPLE_RATE as f64,
+            },
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    guard((), || {
+        if let Some(core) = core().as_mut() {
+            core.chip8.load_state(&core.initial);
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    guard((), || {
+        let callbacks = *callbacks();
+        if let Some(input_poll) = callbacks.input_poll {
+            unsafe { input_poll() };
+        }
+        let keys = callbacks.input_state.map(read_keys);
+        let Some((audio, video)) = core().as_mut().map(|core| core.run(keys)) else {
+            return;
+        };
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            unsafe { audio_sample_batch(audio.as_ptr(), audio.len() / 2) };
+        }
+        if let Some(video_refresh) = callbacks.video_refresh {
+            unsafe {
+                video_refresh(
+                    video.as_ptr() as *const c_void,
+                    constants::DISPLAY_WIDTH as c_uint,
+                    constants::DISPLAY_HEIGHT as c_uint,
+                    constants::DISPLAY_WIDTH * std::mem::size_of::<u32>(),
+                )
+            };
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    guard(0, || {
+        core()
+            .as_ref()
+            .map_or(0, |core| core.chip8.save_state().to_bytes().len())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    guard(false, || {
+        let Some(state) = core().as_ref().map(|c| c.chip8.save_state()) else {
+            return false;
+        };
+        let bytes = state.to_bytes();
+        if data.is_null() || size < bytes.len() {
+            return false;
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), data as *mut u8, bytes.len());
+        true
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    guard(false, || {
+        if data.is_null() {
+            return false;
+        }
+        let bytes = std::slice::from_raw_parts(data as *const u8, size);
+        let state = match State::from_bytes(bytes) {
+            Ok(state) => state,
+            Err(error) => {
+                log_error(&error);
+                return false;
+            }
+        };
+        match core().as_mut() {
+            Some(core) => {
+                core.chip8.load_state(&state);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    guard((), || {
+        if let Some(core) = core().as_mut() {
+            core.cheats.clear();
+            core.chip8.cheats.list.clear();
+        }
+    })
+}
+
+// Cheat of the frontend, 'ADDR=VALUE' pokes joined with '+', e.g. '0x2F0=3+0x2F1=0'
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    guard((), || {
+        if code.is_null() {
+            return;
+        }
+        let code = CStr::from_ptr(code).to_string_lossy();
+        // Logged once the core is unlocked
+        let result = match core().as_mut() {
+            Some(core) => code
+                .split('+')
+                .map(|spec| Cheat::parse(spec, &core.chip8.symbols))
+                .map(|cheat| cheat.map(|cheat| Cheat { enabled, ..cheat }))
+                .collect::<Result<Vec<Cheat>, String>>()
+                .map(|cheats| {
+                    core.cheats.insert(index, cheats);
+                    core.chip8.cheats.list = core.cheats.values().flatten().copied().collect();
+                }),
+            None => Ok(()),
+        };
+        if let Err(error) = result {
+            log_error(&error);
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    guard(false, || {
+        let Some(game) = game.as_ref() else {
+            return false;
+        };
+        if game.data.is_null() {
+            return false;
+        }
+        let Some(environment) = callbacks().environment else {
+            return false;
+        };
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut c_uint as *mut c_void,
+        ) {
+            log_error("The frontend does not support the XRGB8888 pixel format");
+            return false;
+        }
+        let mut descriptors: Vec<InputDescriptor> = JOYPAD
+            .iter()
+            .map(|(id, _, description)| InputDescriptor {
+                port: 0,
+                device: RETRO_DEVICE_JOYPAD,
+                index: 0,
+                id: *id,
+                description: description.as_ptr(),
+            })
+            .collect();
+        descriptors.push(InputDescriptor {
+            port: 0,
+            device: 0,
+            index: 0,
+            id: 0,
+            description: std::ptr::null(),
+        });
+        environment(
+            RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS,
+            descriptors.as_mut_ptr() as *mut c_void,
+        );
+
+        let rom = std::slice::from_raw_parts(game.data as *const u8, game.size).to_vec();
+        let path = if game.path.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(game.path).to_string_lossy().to_string()
+        };
+        *core() = Some(Core::new(rom, &path));
+        true
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const GameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    guard((), || *core() = None)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+// The RAM, for the cheats and achievements of the frontend
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    guard(std::ptr::null_mut(), || match (id, core().as_mut()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.chip8.bus.ram.as_mut_ptr() as *mut c_void,
+        _ => std::ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    guard(0, || match (id, core().as_ref()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(_)) => constants::RAM_SIZE,
+        _ => 0,
+    })
+}
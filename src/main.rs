@@ -1,30 +1,58 @@
 mod audio;
-mod chip8;
-mod constants;
-mod debug;
+mod config;
+mod debugger;
+mod difftest;
 mod display;
+mod font;
 mod keyboard;
+mod launcher;
+mod osd;
+mod rom;
 mod time;
 
+use rchip8::{
+    breakpoints, cheat, chip8, constants, coverage, debug, detect, expr, gdb, profile,
+    quirks, romdb, script, symbols, tone, trace, wav,
+};
+
 extern crate clap;
 extern crate hex;
 extern crate sdl2;
 
+use clap::parser::ValueSource;
 use clap::{Arg, Command};
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use std::fs::File;
-use std::io::prelude::*;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::Sdl;
+use std::path::Path;
 
 use audio::Beep;
+use cheat::{Cheat, Cheats, Compare, Search};
 use chip8::Chip8;
+use config::Config;
+use coverage::Coverage;
+use debugger::Debugger;
 use display::Display;
+use expr::Register;
+use gdb::{GdbAction, GdbStub};
+use keyboard::{Hotkeys, Keymap};
+use launcher::{History, Launcher};
+use profile::Profile;
+use quirks::Quirks;
+use romdb::RomDb;
+use script::Script;
+use symbols::Symbols;
+use time::RateMeter;
+use tone::{Tone, Waveform};
+use trace::Trace;
+use wav::Recorder;
 
 // Simple CHIP8 emulator
 // MIT license
 
 fn main() {
-    let matches = Command::new("R-CHIP-8")
+    let config = Config::load();
+    let command = Command::new("R-CHIP-8")
         .version("0.1.0")
         .author("Toni Sagrsità Sellés <me@tonisagrista.com>")
         .about("CHIP-8 emulator")
@@ -32,7 +60,17 @@ fn main() {
             Arg::new("input")
                 .required(true)
                 .index(1)
-                .help("ROM file to load and run.")
+                .help("ROM file to load and run: a plain file, a file in a zip archive as 'ARCHIVE.zip/NAME', or '-' for the standard input. A directory or archive of several ROMs opens the launcher.")
+        )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("config")
+                .about("Configuration files.")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("dump")
+                        .about("Print the effective configuration, with the values of the environment and the command line.")
+                )
         )
         .arg(
             Arg::new("debug")
@@ -41,6 +79,107 @@ fn main() {
                 .action(clap::ArgAction::SetTrue)
                 .help("Run in debug mode. Pauses after each instruction, prints info to stdout.")
         )
+        .arg(
+            Arg::new("debugger")
+                .short('g')
+                .long("debugger")
+                .action(clap::ArgAction::SetTrue)
+                .help("Open the debugger window with the registers, stack, disassembly and memory.")
+        )
+        .arg(
+            Arg::new("gdb")
+                .long("gdb")
+                .value_parser(clap::value_parser!(u16))
+                .help("Listen for GDB remote protocol connections on the given local TCP port.")
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .action(clap::ArgAction::SetTrue)
+                .help("Reload the ROM and reset the machine when the ROM file changes, and the symbol file with it.")
+        )
+        .arg(
+            Arg::new("symbols")
+                .long("symbols")
+                .help("Load the labels of the ROM from the given symbol file: Octo JSON or 'ADDR NAME' lines.")
+        )
+        .arg(
+            Arg::new("break")
+                .long("break")
+                .action(clap::ArgAction::Append)
+                .help("Stop before the instruction at ADDR, optionally only if the condition holds: 'ADDR' or 'ADDR if EXPR'.")
+        )
+        .arg(
+            Arg::new("breakif")
+                .long("break-if")
+                .action(clap::ArgAction::Append)
+                .help("Stop when the condition becomes true, e.g. 'V3 == 0x10 && [0x300] > 4'.")
+        )
+        .arg(
+            Arg::new("watchmem")
+                .long("watch-mem")
+                .action(clap::ArgAction::Append)
+                .help("Stop when the memory range is accessed: 'ADDR[-END][:r|w|rw]'.")
+        )
+        .arg(
+            Arg::new("watchreg")
+                .long("watch-reg")
+                .action(clap::ArgAction::Append)
+                .help("Stop when the register changes: V0-VF, I, PC, SP, DT or ST.")
+        )
+        .arg(
+            Arg::new("trace")
+                .long("trace")
+                .help("Write a line per executed instruction to the given file.")
+        )
+        .arg(
+            Arg::new("tracerange")
+                .long("trace-range")
+                .action(clap::ArgAction::Append)
+                .requires("trace")
+                .help("Only trace the instructions in the address range 'ADDR[-END]'.")
+        )
+        .arg(
+            Arg::new("tracering")
+                .long("trace-ring")
+                .value_parser(clap::value_parser!(usize))
+                .requires("trace")
+                .help("Only keep the last N traced instructions, written when stopping at a breakpoint or on errors.")
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile-rom")
+                .num_args(0..=1)
+                .default_missing_value(constants::DEF_PROFILE_FILE_STR)
+                .help("Profile the ROM: print the hotspots and subroutines on exit and write a heatmap to the given file.")
+        )
+        .arg(
+            Arg::new("coverage")
+                .long("coverage")
+                .help("Write the code and data coverage map to the given JSON file on exit, and the annotated disassembly next to it.")
+        )
+        .arg(
+            Arg::new("cheat")
+                .long("cheat")
+                .action(clap::ArgAction::Append)
+                .help("Poke the byte at ADDR with VALUE at the end of every frame: 'ADDR=VALUE'. Saved with the cheats of the ROM.")
+        )
+        .arg(
+            Arg::new("script")
+                .long("script")
+                .help("Run the given Rhai automation script on the machine events, and exit with an error if its assertions fail.")
+        )
+        .arg(
+            Arg::new("headless")
+                .long("headless")
+                .value_parser(clap::value_parser!(u32))
+                .help("Run the given number of frames without a window nor audio device, as fast as possible, then exit. The audio is still dumped with --wav.")
+        )
+        .arg(
+            Arg::new("difftrace")
+                .long("diff-trace")
+                .help("Run without a window in lockstep with the given reference trace, report the first divergence and exit.")
+        )
         .arg(
             Arg::new("scale")
                 .short('s')
@@ -57,6 +196,33 @@ fn main() {
                 .default_value(constants::DEF_IPS_STR)
                 .help(&format!("Emulation speed in instructions per second."))
         )
+        .arg(
+            Arg::new("cpf")
+                .long("cycles-per-frame")
+                .value_parser(clap::value_parser!(u32))
+                .help("Emulation speed in instructions per frame (60 frames per second), alternative to --ips.")
+        )
+        .arg(
+            Arg::new("quirk")
+                .long("quirk")
+                .action(clap::ArgAction::Append)
+                .value_delimiter(',')
+                .env("RCHIP8_QUIRKS")
+                .help(format!("Override a quirk: 'NAME=true|false', NAME being one of: {}.", quirks::NAMES.join(", ")))
+        )
+        .arg(
+            Arg::new("romdb")
+                .long("rom-db")
+                .help("Load the ROM database from the given chip-8-database directory or programs.json file, instead of the built-in one.")
+        )
+        .arg(
+            Arg::new("ffspeed")
+                .short('f')
+                .long("ff-speed")
+                .value_parser(clap::value_parser!(u32))
+                .default_value(constants::DEF_FF_SPEED_STR)
+                .help("Speed factor while fast-forwarding (hold Tab), 0 for unthrottled.")
+        )
         .arg(
             Arg::new("fgcol")
                 .short('c')
@@ -72,24 +238,265 @@ fn main() {
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .help(&format!("Background (off) color as a hex code."))
         )
-        .get_matches();
+        .arg(
+            Arg::new("waveform")
+                .long("waveform")
+                .value_parser(["square", "sine", "triangle", "noise"])
+                .default_value(constants::DEF_WAVEFORM_STR)
+                .help("Beeper waveform.")
+        )
+        .arg(
+            Arg::new("freq")
+                .long("freq")
+                .value_parser(clap::value_parser!(f32))
+                .default_value(constants::DEF_FREQ_STR)
+                .help("Beeper frequency in Hz.")
+        )
+        .arg(
+            Arg::new("volume")
+                .long("volume")
+                .value_parser(clap::value_parser!(f32))
+                .default_value(constants::DEF_VOLUME_STR)
+                .help("Beeper volume, in [0, 1].")
+        )
+        .arg(
+            Arg::new("duty")
+                .long("duty")
+                .value_parser(clap::value_parser!(f32))
+                .default_value(constants::DEF_DUTY_STR)
+                .help("Duty cycle of the square waveform, in (0, 1).")
+        )
+        .arg(
+            Arg::new("wav")
+                .long("wav")
+                .help("Dump the emitted audio to the given WAV file, in emulated time.")
+        );
+    let matches = config.apply(command).get_matches();
+    let keymap = config.keymap();
+    let hotkeys = config.hotkeys();
+
+    // Print the configuration
+    if let Some(("config", _)) = matches.subcommand() {
+        let mut quirks = config.quirks();
+        override_quirks(&mut quirks, &matches);
+        print!("{}", config::dump(&matches, &quirks, &keymap, &hotkeys));
+        return;
+    }
+
+    // ROM database
+    let db = match RomDb::load(matches.get_one::<String>("romdb").map(|p| p.as_str())) {
+        Ok(db) => db,
+        Err(error) => {
+            println!("{}", error);
+            RomDb::builtin()
+        }
+    };
+    println!("ROM database loaded: {}", db.summary());
 
-    let filename = matches.get_one::<String>("input").unwrap();
+    let input = matches.get_one::<String>("input").unwrap();
+    let color = |id: &str, default| {
+        hex_to_col(matches.get_one::<String>(id).unwrap()).unwrap_or(default)
+    };
+    let fgcol = color("fgcol", constants::DEF_FG);
+    let bgcol = color("bgcol", constants::DEF_BG);
+    let scale: u32 = *matches.get_one("scale").expect("required");
+
+    // Run the input, then the ROMs dropped on the window. Directories and archives of
+    // several ROMs are browsed in the launcher, and quitting a ROM goes back to it.
+    let mut screen: Option<(Sdl, Display)> = None;
+    let mut launcher: Option<Launcher> = None;
+    let mut next = Some(input.clone());
+    loop {
+        let path = match (next.take(), launcher.as_mut()) {
+            (Some(path), _) => path,
+            (None, Some(launcher)) => {
+                let (_, display) = screen.get_or_insert_with(|| open_screen(scale, fgcol, bgcol));
+                display.set_colors(fgcol, bgcol);
+                match launcher.choose(display, &hotkeys) {
+                    Some(path) => path,
+                    None => break,
+                }
+            }
+            (None, None) => break,
+        };
+        if is_browsable(&path) {
+            match Launcher::new(&path, &db) {
+                Ok(new_launcher) => launcher = Some(new_launcher),
+                Err(error) => {
+                    println!("{}", error);
+                    if launcher.is_none() {
+                        std::process::exit(1);
+                    }
+                }
+            }
+            continue;
+        }
+        match run(&matches, &config, &keymap, &hotkeys, &db, &path, &mut screen) {
+            Exit::Closed(true) => std::process::exit(1),
+            Exit::Closed(false) => break,
+            Exit::Quit | Exit::Error if launcher.is_some() => (),
+            Exit::Quit => break,
+            Exit::Error => std::process::exit(1),
+            Exit::Load(path) => next = Some(path),
+        }
+    }
+    println!("Bye!");
+}
+
+// How the emulation of a ROM ended
+enum Exit {
+    // The window was closed, and whether the script failed
+    Closed(bool),
+    // The quit hotkey was pressed
+    Quit,
+    // A ROM file, directory or archive was dropped on the window
+    Load(String),
+    // The ROM could not be read
+    Error,
+}
+
+// Returns whether the path is browsed in the launcher: a directory, or an archive
+// without a single ROM
+fn is_browsable(path: &str) -> bool {
+    Path::new(path).is_dir()
+        || (rom::is_archive(path) && rom::archive_roms(path).map_or(true, |r| r.len() != 1))
+}
+
+// Inits SDL2 and creates the display
+fn open_screen(scale: u32, fgcol: (u8, u8, u8), bgcol: (u8, u8, u8)) -> (Sdl, Display) {
+    let sdl_context = sdl2::init().unwrap();
+    let display = Display::new(&sdl_context, constants::WINDOW_TITLE, scale, fgcol, bgcol);
+    (sdl_context, display)
+}
+
+// Loads and runs the ROM until the window is closed, the quit hotkey is pressed or
+// another ROM is dropped on the window, in the window of the launcher if any
+fn run(
+    matches: &clap::ArgMatches,
+    config: &Config,
+    keymap: &Keymap,
+    hotkeys: &Hotkeys,
+    db: &RomDb,
+    filename: &str,
+    screen: &mut Option<(Sdl, Display)>,
+) -> Exit {
     // Read ROM
     println!("Reading ROM file: {}", filename);
-    let mut f = File::open(filename).unwrap();
-    let mut rom = Vec::new();
-    f.read_to_end(&mut rom).unwrap();
+    let mut rom = match rom::read(filename) {
+        Ok(rom) => rom,
+        Err(error) => {
+            println!("{}", error);
+            return Exit::Error;
+        }
+    };
+
+    // Look up the ROM in the database
+    let mut hash = romdb::sha1(&rom);
+    println!("ROM SHA-1: {}", hash);
+    let rom_info = db.lookup(&hash);
+    let (platform, platform_quirks) = match rom_info.as_ref() {
+        Some(info) => {
+            println!("ROM: {} by {}", info.title, info.authors.join(", "));
+            (info.platform.clone(), info.quirks)
+        }
+        None => {
+            // Guess the platform of unknown ROMs
+            let detection = detect::detect(&rom, filename);
+            println!("ROM not in the database, detecting the platform:");
+            for reason in detection.reasons.iter() {
+                println!("  {}", reason);
+            }
+            if detection.reasons.is_empty() {
+                println!("  No SCHIP nor XO-CHIP features found");
+            } else if detection.platform.is_none() {
+                println!("  Not enough evidence of SCHIP nor XO-CHIP");
+            }
+            let platform = detection.platform.unwrap_or("chip8");
+            // The configured quirks override the guessed ones
+            (
+                Some(platform.to_string()),
+                detection
+                    .platform
+                    .and_then(Quirks::platform)
+                    .map(|q| config.quirks_over(q)),
+            )
+        }
+    };
+    if let Some(platform) = platform.as_ref() {
+        println!("Platform: {}", platform);
+        if platform != "chip8" && !romdb::SUPPORTED_PLATFORMS.contains(&platform.as_str()) {
+            if platform_quirks.is_some() {
+                println!(
+                    "Platform {} is not supported: its quirks are used, but only the CHIP-8 instructions are implemented",
+                    platform
+                );
+            } else {
+                println!("Platform {} is not supported, running as CHIP-8", platform);
+            }
+        }
+    }
+    // Settings given in the environment or the command line override the database,
+    // which overrides the configuration files
+    let explicit = |id: &str| matches.value_source(id) > Some(ValueSource::DefaultValue);
+
+    // Quirks
+    let mut quirks = platform_quirks.unwrap_or_else(|| config.quirks());
+    override_quirks(&mut quirks, matches);
+    println!("Quirks: {}", quirks.summary());
 
     // Scaling
     let scale: u32 = *matches.get_one("scale").expect("required");
 
-    // Emulation speed
-    let ips: u32 = *matches.get_one("ips").expect("required");
-    let instruction_time_ns: u128 = (1e9 as u128 / ips as u128) as u128;
+    // Emulation speed: the most explicit of the cycles per frame and the IPS, then the
+    // database, then the configured cycles per frame
+    let ips_cycles = || {
+        let ips: u32 = *matches.get_one("ips").expect("required");
+        chip8::cycles_per_frame(ips)
+    };
+    let cpf = matches.get_one::<u32>("cpf").copied();
+    let tickrate = rom_info.as_ref().and_then(|i| i.tickrate);
+    let cycles_per_frame: u32 = match (cpf, tickrate) {
+        (Some(cpf), _)
+            if explicit("cpf") && matches.value_source("cpf") >= matches.value_source("ips") =>
+        {
+            cpf
+        }
+        _ if explicit("ips") => ips_cycles(),
+        (_, Some(tickrate)) => tickrate,
+        (Some(cpf), None) => cpf,
+        (None, None) => ips_cycles(),
+    }
+    .clamp(constants::MIN_CYCLES_PER_FRAME, constants::MAX_CYCLES_PER_FRAME);
+    println!(
+        "Speed: {} cycles per frame ({} IPS)",
+        cycles_per_frame,
+        cycles_per_frame * constants::FRAME_RATE
+    );
+
+    // Differential testing against a reference trace
+    if let Some(path) = matches.get_one::<String>("difftrace") {
+        match difftest::run(rom, cycles_per_frame, quirks, path) {
+            Ok(None) => std::process::exit(0),
+            Ok(Some(report)) => println!("{}", report),
+            Err(error) => println!("{}", error),
+        }
+        std::process::exit(1);
+    }
+
+    // Fast-forward speed
+    let ff_speed: u32 = *matches.get_one("ffspeed").expect("required");
+
+    // Colors of the database
+    let db_colors = rom_info
+        .as_ref()
+        .and_then(|i| i.colors.clone())
+        .filter(|_| !explicit("fgcol") && !explicit("bgcol"));
 
     // Foreground color
-    let fg_str = matches.get_one::<String>("fgcol").unwrap();
+    let fg_str = match db_colors.as_ref() {
+        Some((_, fg)) => fg,
+        None => matches.get_one::<String>("fgcol").unwrap(),
+    };
     let fg = hex_to_col(fg_str);
     let fgcol = match fg {
         Ok(fgcol) => fgcol,
@@ -100,7 +507,10 @@ fn main() {
     };
 
     // Background color
-    let bg_str = matches.get_one::<String>("bgcol").unwrap();
+    let bg_str = match db_colors.as_ref() {
+        Some((bg, _)) => bg,
+        None => matches.get_one::<String>("bgcol").unwrap(),
+    };
     let bg = hex_to_col(bg_str);
     let bgcol = match bg {
         Ok(bgcol) => bgcol,
@@ -110,47 +520,398 @@ fn main() {
         }
     };
 
+    // Keys bound to the game buttons of the database
+    let db_keymap: Vec<(Scancode, u8)> = rom_info
+        .as_ref()
+        .map(|i| {
+            i.keys
+                .iter()
+                .filter_map(|(name, key)| Some((keyboard::button(name)?, *key)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Beeper tone
+    let waveform_str = matches.get_one::<String>("waveform").unwrap();
+    let tone = Tone {
+        waveform: Waveform::parse(waveform_str).unwrap_or(Waveform::Square),
+        freq: *matches.get_one::<f32>("freq").unwrap(),
+        volume: matches.get_one::<f32>("volume").unwrap().clamp(0.0, 1.0),
+        duty: matches.get_one::<f32>("duty").unwrap().clamp(0.01, 0.99),
+    };
+
     // Start time
     let start: u128 = time::time_nanos();
 
     println!("R-CHIP-8 starting");
 
-    // Init SDL2
-    let sdl_context = sdl2::init().unwrap();
-
-    // Create the display
-    let mut display = Display::new(&sdl_context, "R-CHIP-8", scale, fgcol, bgcol);
-
-    // Create audio beep
-    let beep = Beep::new(&sdl_context);
+    // Audio dump, rendered in emulated time
+    let mut wav = match matches.get_one::<String>("wav") {
+        Some(path) => match Recorder::create(path, tone, start) {
+            Ok(wav) => {
+                println!("Dumping audio to: {}", path);
+                Some(wav)
+            }
+            Err(error) => {
+                println!("{}", error);
+                None
+            }
+        },
+        None => None,
+    };
 
     // Create the machine
     let debug_mode: &bool = matches.get_one("debug").unwrap();
     println!("Debug: {}", debug_mode);
-    let mut chip8 = Chip8::new(rom, start, instruction_time_ns, *debug_mode);
+    let mut chip8 = Chip8::new(rom.clone(), start, cycles_per_frame, *debug_mode);
+    chip8.quirks = quirks;
+    print_memory(&rom);
+
+    // Symbols
+    if let Some(path) = matches.get_one::<String>("symbols") {
+        match Symbols::load(path) {
+            Ok(symbols) => chip8.symbols = symbols,
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    // Breakpoints and watchpoints
+    for spec in matches.get_many::<String>("break").unwrap_or_default() {
+        if let Err(error) = chip8.breakpoints.add_breakpoint(spec, &chip8.symbols) {
+            println!("{}", error);
+        }
+    }
+    for source in matches.get_many::<String>("breakif").unwrap_or_default() {
+        match expr::Expr::parse(source, &chip8.symbols) {
+            Ok(condition) => chip8.breakpoints.conditions.push(condition),
+            Err(error) => println!("{}", error),
+        }
+    }
+    for spec in matches.get_many::<String>("watchmem").unwrap_or_default() {
+        match breakpoints::Watchpoint::parse(spec, &chip8.symbols) {
+            Ok(watchpoint) => chip8.breakpoints.watchpoints.push(watchpoint),
+            Err(error) => println!("{}", error),
+        }
+    }
+    for name in matches.get_many::<String>("watchreg").unwrap_or_default() {
+        match Register::parse(name) {
+            Ok(reg) => chip8.breakpoints.registers.push(reg),
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    // Execution trace
+    if let Some(path) = matches.get_one::<String>("trace") {
+        let mut ranges = Vec::new();
+        for range in matches.get_many::<String>("tracerange").unwrap_or_default() {
+            match chip8.symbols.parse_range(range) {
+                Ok(range) => ranges.push(range),
+                Err(error) => println!("{}", error),
+            }
+        }
+        let ring_size = *matches.get_one::<usize>("tracering").unwrap_or(&0);
+        match Trace::create(path, ranges, ring_size) {
+            Ok(trace) => {
+                println!("Tracing to: {}", path);
+                chip8.trace = Some(trace);
+            }
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    // ROM profiler
+    let profile_path = matches.get_one::<String>("profile");
+    if profile_path.is_some() {
+        chip8.profile = Some(Profile::new());
+    }
+
+    // Coverage map
+    let coverage_path = matches.get_one::<String>("coverage");
+    if coverage_path.is_some() {
+        chip8.coverage = Some(Coverage::new(rom.len()));
+    }
+
+    // Cheats of the ROM, with those of the command line added and saved
+    chip8.cheats = load_cheats(&hash);
+    let cheat_specs: Vec<&String> = matches.get_many("cheat").unwrap_or_default().collect();
+    for spec in &cheat_specs {
+        match Cheat::parse(spec, &chip8.symbols) {
+            Ok(cheat) => chip8.cheats.add(cheat),
+            Err(error) => println!("{}", error),
+        }
+    }
+    if !cheat_specs.is_empty() {
+        if let Err(error) = chip8.cheats.save(&hash) {
+            println!("{}", error);
+        }
+    }
+    if !chip8.cheats.list.is_empty() {
+        println!("Cheats: {}", chip8.cheats.list.len());
+    }
+    let mut search: Option<Search> = None;
+
+    // Automation script
+    if let Some(path) = matches.get_one::<String>("script") {
+        match Script::load(path, &mut chip8) {
+            Ok(script) => {
+                println!("Script loaded: {}", path);
+                chip8.script = Some(script);
+            }
+            Err(error) => {
+                println!("{}", error);
+                return Exit::Error;
+            }
+        }
+    }
+
+    // Run without a window
+    if let Some(frames) = matches.get_one::<u32>("headless") {
+        let exit = headless(&mut chip8, *frames, wav.as_mut());
+        report(&chip8, profile_path, coverage_path);
+        return exit;
+    }
+
+    // Init SDL2 and create the display, unless the launcher did
+    let (sdl_context, display) = screen.get_or_insert_with(|| open_screen(scale, fgcol, bgcol));
+    display.set_colors(fgcol, bgcol);
+
+    // Create audio beep
+    let mut beep = Beep::new(sdl_context, tone, start);
+
+    // Emulated time [ns], stops while paused and runs faster while fast-forwarding
+    let mut emu_t: u128 = start;
+    let mut last_t: u128 = start;
+    let mut paused = false;
+    let mut fast_forward = false;
+    let mut meter = RateMeter::new(start, 0, 0);
+
+    // Create the debugger window
+    let mut debugger: Option<Debugger> = if matches.get_flag("debugger") {
+        Some(Debugger::new(sdl_context))
+    } else {
+        None
+    };
+    let mut last_debugger_t: u128 = start;
+
+    // Start the GDB server
+    let mut gdb: Option<GdbStub> = match matches.get_one::<u16>("gdb") {
+        Some(port) => match GdbStub::bind(*port) {
+            Ok(stub) => {
+                println!("Waiting for GDB connections on port {}", port);
+                Some(stub)
+            }
+            Err(error) => {
+                println!("{}", error);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // ROM file modification time, polled to reload it
+    let watch = matches.get_flag("watch");
+    let mut rom_modified = watch.then(|| modified(rom::file(filename))).flatten();
+    let mut last_watch_t: u128 = start;
 
     // Main loop
+    let mut exit = Exit::Quit;
     'mainloop: loop {
         let t: u128 = time::time_nanos();
+        let mut frame_advance = false;
+        // Message of the reset to do, if any
+        let mut reset: Option<&str> = None;
 
         // Event loop
-        for event in display.event_pump.poll_iter() {
+        let events: Vec<Event> = display.event_pump.poll_iter().collect();
+        for event in events {
+            if let Some(dbg) = debugger.as_mut() {
+                dbg.handle_event(&event, chip8.index);
+            }
             match event {
+                // Closing the debugger window only closes the debugger
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } if debugger.as_ref().is_some_and(|d| d.window_id() == window_id) => {
+                    debugger = None;
+                }
                 Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
+                | Event::Window {
+                    win_event: WindowEvent::Close,
                     ..
+                } => {
+                    exit = Exit::Closed(false);
+                    break 'mainloop;
                 }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::CapsLock),
+                // Load the ROM dropped on the window into a fresh machine
+                Event::DropFile { filename, .. } => {
+                    if Path::new(rom::file(&filename)).exists() {
+                        exit = Exit::Load(filename);
+                        break 'mainloop;
+                    }
+                    println!("Not a ROM file: {}", filename);
+                }
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if hotkeys.quit.contains(&key) => break 'mainloop,
+                // Pause/resume
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } if hotkeys.pause.contains(&key) => {
+                    paused = !paused;
+                    display.osd.show(if paused { "Paused" } else { "Resumed" }, t);
+                    show_state(display, paused, fast_forward, &meter);
+                }
+                // Reset the machine with the same ROM
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } if hotkeys.reset.contains(&key) => reset = Some("Reset"),
+                // Fast-forward while held
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
                     ..
-                } => break 'mainloop,
+                } if hotkeys.fast_forward.contains(&key) => {
+                    fast_forward = true;
+                    show_state(display, paused, fast_forward, &meter);
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } if hotkeys.fast_forward.contains(&key) => {
+                    fast_forward = false;
+                    show_state(display, paused, fast_forward, &meter);
+                }
+                // Advance one frame while paused
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if hotkeys.frame_advance.contains(&key) => frame_advance = paused,
+                // Speed up/down
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if hotkeys.speed_up.contains(&key) => display.osd.show(&change_speed(&mut chip8, true), t),
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if hotkeys.speed_down.contains(&key) => display.osd.show(&change_speed(&mut chip8, false), t),
+                // Cheat memory search, freeze and toggle
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(message) = cheat_hotkey(key, hotkeys, &mut search, &mut chip8, &hash) {
+                        println!("{}", message);
+                        display.osd.show(&message, t);
+                    }
+                }
                 _ => {}
             }
         }
 
+        // Reload the ROM when the file changes
+        if watch && t - last_watch_t >= constants::WATCH_PERIOD_NS {
+            last_watch_t = t;
+            let file_modified = modified(rom::file(filename));
+            if file_modified != rom_modified {
+                rom_modified = file_modified;
+                match rom::read(filename) {
+                    Ok(new_rom) if !new_rom.is_empty() => {
+                        println!("ROM changed, reloading: {}", filename);
+                        rom = new_rom;
+                        print_memory(&rom);
+                        hash = romdb::sha1(&rom);
+                        chip8.cheats = load_cheats(&hash);
+                        if let Some(path) = matches.get_one::<String>("symbols") {
+                            match Symbols::load(path) {
+                                Ok(symbols) => chip8.symbols = symbols,
+                                Err(error) => println!("{}", error),
+                            }
+                        }
+                        reset = Some("Reloaded");
+                    }
+                    Ok(_) => (),
+                    Err(error) => println!("{}", error),
+                }
+            }
+        }
+
+        // Reset the machine with the current ROM
+        if let Some(message) = reset {
+            display.osd.show(message, t);
+            chip8.reset(rom.clone(), emu_t);
+            meter = RateMeter::new(t, 0, 0);
+            display.clear();
+            // Silence the sound of the previous machine
+            beep.update(emu_t, std::iter::once((emu_t, false)));
+        }
+
+        // Advance the emulated time
+        let elapsed = t - last_t;
+        last_t = t;
+        if paused {
+            if frame_advance {
+                emu_t += constants::TIMER_PERIOD_NS;
+            }
+        } else if fast_forward {
+            emu_t += if ff_speed == 0 {
+                // Unthrottled, one frame per iteration
+                constants::TIMER_PERIOD_NS
+            } else {
+                elapsed * ff_speed as u128
+            };
+        } else {
+            emu_t += elapsed;
+        }
+
         // Run the machine
-        chip8.cycle(t, &mut display.event_pump);
+        keyboard::read(&display.event_pump, &mut chip8.keys, keymap, &db_keymap);
+        chip8.cycle(emu_t);
+
+        // Stop at breakpoints and watchpoints
+        if let Some(reason) = chip8.stop.take() {
+            paused = true;
+            let description = reason.describe(&chip8.symbols);
+            println!("{}", description);
+            display.osd.show(&description, t);
+            show_state(display, paused, fast_forward, &meter);
+            if let Some(stub) = gdb.as_mut() {
+                stub.stopped(&reason);
+            }
+        }
+
+        // End the run when the script quits, as an error if it failed
+        if let Some(script) = chip8.script.as_ref().filter(|s| s.quit) {
+            exit = if script.failures > 0 {
+                Exit::Error
+            } else {
+                Exit::Quit
+            };
+            break 'mainloop;
+        }
+
+        // Serve the GDB client
+        if let Some(stub) = gdb.as_mut() {
+            match stub.poll(&mut chip8) {
+                Some(GdbAction::Halt) => {
+                    paused = true;
+                    show_state(display, paused, fast_forward, &meter);
+                }
+                Some(GdbAction::Resume) => {
+                    paused = false;
+                    show_state(display, paused, fast_forward, &meter);
+                }
+                None => {}
+            }
+        }
+
+        // Refresh the measured speed
+        if meter.update(t, chip8.instruction_count, chip8.frame_count) {
+            show_state(display, paused, fast_forward, &meter);
+        }
 
         // Clear/update display if needed
         if chip8.display_clear_flag {
@@ -160,14 +921,235 @@ fn main() {
             display.render(chip8.display);
         }
 
-        // Play/pause the beep
-        if chip8.beep_flag {
-            beep.play();
+        display.update_osd(t);
+
+        // Refresh the debugger once per frame
+        if let Some(dbg) = debugger.as_mut() {
+            if t - last_debugger_t >= constants::TIMER_PERIOD_NS {
+                dbg.render(&mut chip8);
+                last_debugger_t = t;
+            }
+        }
+
+        // Record the audio up to now, and play it only when running at normal speed
+        let events: Vec<(u128, bool)> = chip8.sound_events.drain(..).collect();
+        if let Some(wav) = wav.as_mut() {
+            wav.update(emu_t, events.iter().copied());
+        }
+        if paused || fast_forward {
+            beep.skip(emu_t, events.into_iter());
         } else {
-            beep.pause();
+            beep.update(emu_t, events.into_iter());
         }
     }
-    println!("Bye!");
+
+    report(&chip8, profile_path, coverage_path);
+
+    // A failed script fails the run however it ended
+    if chip8.script.as_ref().is_some_and(|s| s.failures > 0) {
+        exit = match exit {
+            Exit::Closed(_) => Exit::Closed(true),
+            Exit::Quit => Exit::Error,
+            exit => exit,
+        };
+    }
+
+    // Recently played history, with the last frame as preview in the launcher
+    let mut history = History::load();
+    history.record(&hash, &chip8.display);
+    if let Err(error) = history.save() {
+        println!("{}", error);
+    }
+    exit
+}
+
+// Runs the frames as fast as possible without a window nor an audio device, recording
+// the audio if asked, and ignoring the breakpoints. Ends early when the script quits.
+fn headless(chip8: &mut Chip8, frames: u32, mut wav: Option<&mut Recorder>) -> Exit {
+    println!("Running {} frames without a window", frames);
+    for _ in 0..frames {
+        chip8.run_frame();
+        if let Some(wav) = wav.as_mut() {
+            wav.update(chip8.time(), chip8.sound_events.drain(..));
+        }
+        chip8.sound_events.clear();
+        if chip8.script.as_ref().is_some_and(|s| s.quit) {
+            break;
+        }
+    }
+    if chip8.script.as_ref().is_some_and(|s| s.failures > 0) {
+        Exit::Error
+    } else {
+        Exit::Quit
+    }
+}
+
+// Writes the profiler report and the coverage map, and prints the outcome of the script
+fn report(chip8: &Chip8, profile_path: Option<&String>, coverage_path: Option<&String>) {
+    if let (Some(profile), Some(path)) = (chip8.profile.as_ref(), profile_path) {
+        print!("{}", profile.report(&chip8.bus.ram, &chip8.symbols));
+        match profile.write_heatmap(path, &chip8.bus.ram, &chip8.symbols) {
+            Ok(()) => println!("Heatmap written to: {}", path),
+            Err(error) => println!("{}", error),
+        }
+    }
+    if let (Some(coverage), Some(path)) = (chip8.coverage.as_ref(), coverage_path) {
+        if let Err(error) = coverage.write(path, &chip8.bus.ram, &chip8.symbols) {
+            println!("{}", error);
+        }
+    }
+    if let Some(script) = chip8.script.as_ref().filter(|s| s.failures > 0) {
+        println!("Script failures: {}", script.failures);
+    }
+}
+
+// Returns the modification time of the file
+fn modified(path: &str) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Applies the quirk overrides of the environment and the command line
+fn override_quirks(quirks: &mut Quirks, matches: &clap::ArgMatches) {
+    for spec in matches.get_many::<String>("quirk").unwrap_or_default() {
+        if let Err(error) = quirks.apply(spec) {
+            eprintln!("{}", error);
+        }
+    }
+}
+
+// Shows the emulation state and measured speed in the window title and the OSD status
+fn show_state(display: &mut Display, paused: bool, fast_forward: bool, meter: &RateMeter) {
+    let state = if paused {
+        "Paused"
+    } else if fast_forward {
+        "Fast-forward"
+    } else {
+        ""
+    };
+    let title = format!(
+        "{} - {} IPS, {} FPS",
+        constants::WINDOW_TITLE,
+        meter.ips,
+        meter.fps
+    );
+    if state.is_empty() {
+        display.set_title(&title);
+    } else {
+        display.set_title(&format!("{} [{}]", title, state));
+    }
+    display.osd.set_status(state);
+}
+
+// Increases or decreases the number of instructions per frame by about 10%, returns a message with the new speed
+fn change_speed(chip8: &mut Chip8, faster: bool) -> String {
+    let step = (chip8.cycles_per_frame / 10).max(1);
+    chip8.cycles_per_frame = if faster {
+        chip8.cycles_per_frame + step
+    } else {
+        chip8.cycles_per_frame.saturating_sub(step)
+    }
+    .clamp(constants::MIN_CYCLES_PER_FRAME, constants::MAX_CYCLES_PER_FRAME);
+    format!(
+        "Speed {} IPS ({} cycles/frame)",
+        chip8.cycles_per_frame * constants::FRAME_RATE,
+        chip8.cycles_per_frame
+    )
+}
+
+// Prints where the machine loads the fonts and the ROM in its memory
+fn print_memory(rom: &[u8]) {
+    println!(
+        "Fonts loaded into memory: {} b [0x{:04x}..0x{:04x}]",
+        80, 0, 80
+    );
+    let bytes = rom.len().min(constants::RAM_SIZE - constants::PROGRAM_LOC);
+    if bytes < rom.len() {
+        println!(
+            "ROM too large for the memory, {} b not loaded",
+            rom.len() - bytes
+        );
+    }
+    println!(
+        "ROM loaded into memory: {} b [0x{:04x}..0x{:04x}]",
+        bytes,
+        constants::PROGRAM_LOC,
+        constants::PROGRAM_LOC + bytes
+    );
+}
+
+// Loads the cheats of the ROM with the given SHA-1, none if they can't be read
+fn load_cheats(hash: &str) -> Cheats {
+    Cheats::load(hash).unwrap_or_else(|error| {
+        println!("{}", error);
+        Cheats::default()
+    })
+}
+
+// Runs the cheat action of the hotkey, if any: starts or narrows down the memory search,
+// freezes the bytes found or toggles the cheats. Returns the message to show.
+fn cheat_hotkey(
+    key: Keycode,
+    hotkeys: &Hotkeys,
+    search: &mut Option<Search>,
+    chip8: &mut Chip8,
+    hash: &str,
+) -> Option<String> {
+    let ram = &chip8.bus.ram;
+    if hotkeys.cheat_search.contains(&key) {
+        let started = search.insert(Search::new(ram));
+        return Some(format!("Cheat search: {} bytes", started.candidates().len()));
+    }
+
+    let compare = [
+        (&hotkeys.cheat_changed, Compare::Changed),
+        (&hotkeys.cheat_unchanged, Compare::Unchanged),
+        (&hotkeys.cheat_increased, Compare::Increased),
+        (&hotkeys.cheat_decreased, Compare::Decreased),
+    ]
+    .into_iter()
+    .find_map(|(keys, compare)| keys.contains(&key).then_some(compare));
+    if let Some(compare) = compare {
+        let Some(search) = search.as_mut() else {
+            return Some("No cheat search started".to_string());
+        };
+        let count = search.filter(ram, compare);
+        if count <= constants::CHEAT_LIST_MAX {
+            for &addr in search.candidates() {
+                println!("  {} = 0x{:02x}", chip8.symbols.describe(addr), ram[addr]);
+            }
+        }
+        return Some(format!("{:?}: {} bytes", compare, count));
+    }
+
+    let message = if hotkeys.cheat_freeze.contains(&key) {
+        let candidates = search.as_ref().map_or(&[][..], |s| s.candidates());
+        if candidates.is_empty() {
+            return Some("No bytes to freeze".to_string());
+        }
+        if candidates.len() > constants::CHEAT_FREEZE_MAX {
+            return Some(format!("Too many bytes to freeze: {}", candidates.len()));
+        }
+        for &addr in candidates {
+            chip8.cheats.add(Cheat {
+                addr,
+                value: ram[addr],
+                enabled: true,
+            });
+        }
+        format!("Frozen: {} bytes", candidates.len())
+    } else if hotkeys.cheat_toggle.contains(&key) {
+        if chip8.cheats.list.is_empty() {
+            return Some("No cheats".to_string());
+        }
+        let enabled = chip8.cheats.toggle();
+        format!("Cheats {}", if enabled { "on" } else { "off" })
+    } else {
+        return None;
+    };
+    if let Err(error) = chip8.cheats.save(hash) {
+        println!("{}", error);
+    }
+    Some(message)
 }
 
 fn hex_to_col(hexcol: &str) -> Result<(u8, u8, u8), String> {
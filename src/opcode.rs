@@ -0,0 +1,168 @@
+// Decoded instruction, shared by the interpreter and the disassembler so that both read
+// an opcode the same way. X and Y are register indexes, N/NN/NNN immediate values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    // 00E0 - clear screen
+    Cls,
+    // 00EE - return from subroutine
+    Ret,
+    // 0NNN - machine code routine, ignored
+    Sys(u16),
+    // 1NNN - jump
+    Jmp(u16),
+    // 2NNN - call subroutine
+    Call(u16),
+    // 3XNN - skip if VX == NN
+    SeByte(usize, u8),
+    // 4XNN - skip if VX != NN
+    SneByte(usize, u8),
+    // 5XY0 - skip if VX == VY
+    SeReg(usize, usize),
+    // 6XNN - VX := NN
+    LdByte(usize, u8),
+    // 7XNN - VX := VX + NN
+    AddByte(usize, u8),
+    // 8XY0 - VX := VY
+    LdReg(usize, usize),
+    // 8XY1 - VX := VX OR VY
+    Or(usize, usize),
+    // 8XY2 - VX := VX AND VY
+    And(usize, usize),
+    // 8XY3 - VX := VX XOR VY
+    Xor(usize, usize),
+    // 8XY4 - VX := VX + VY, VF := carry
+    AddReg(usize, usize),
+    // 8XY5 - VX := VX - VY, VF := not borrow
+    Sub(usize, usize),
+    // 8XY6 - VX := VY >> 1 (VX >> 1 with the shift quirk)
+    Shr(usize, usize),
+    // 8XY7 - VX := VY - VX, VF := not borrow
+    Subn(usize, usize),
+    // 8XYE - VX := VY << 1 (VX << 1 with the shift quirk)
+    Shl(usize, usize),
+    // 9XY0 - skip if VX != VY
+    SneReg(usize, usize),
+    // ANNN - I := NNN
+    LdI(u16),
+    // BNNN - jump to NNN + V0 (XNN + VX with the jump quirk)
+    JmpV0(usize, u16),
+    // CXNN - VX := random byte AND NN
+    Rnd(usize, u8),
+    // DXYN - draw the N rows sprite at I at VX, VY
+    Drw(usize, usize, u8),
+    // EX9E - skip if key VX pressed
+    Skp(usize),
+    // EXA1 - skip if key VX not pressed
+    Sknp(usize),
+    // FX07 - VX := DT
+    LdVxDt(usize),
+    // FX0A - wait for a key press and release, VX := key
+    LdVxK(usize),
+    // FX15 - DT := VX
+    LdDtVx(usize),
+    // FX18 - ST := VX
+    LdStVx(usize),
+    // FX1E - I := I + VX
+    AddI(usize),
+    // FX29 - I := font sprite for VX
+    LdF(usize),
+    // FX33 - BCD of VX at I
+    LdB(usize),
+    // FX55 - store V0..VX at I
+    Store(usize),
+    // FX65 - load V0..VX from I
+    Load(usize),
+    // Anything else
+    Data(u16),
+}
+
+// Decodes the given instruction
+pub fn decode(instr: u16) -> Op {
+    // INSTRUCTION: 0xIXYN with 0x000N, 0x00NN, 0x0NNN
+    let x = ((instr & 0x0F00) >> 8) as usize;
+    let y = ((instr & 0x00F0) >> 4) as usize;
+    let n = (instr & 0x000F) as u8;
+    let nn = (instr & 0x00FF) as u8;
+    let nnn = instr & 0x0FFF;
+    match instr & 0xF000 {
+        0x0000 => match nnn {
+            0x00E0 => Op::Cls,
+            0x00EE => Op::Ret,
+            _ => Op::Sys(nnn),
+        },
+        0x1000 => Op::Jmp(nnn),
+        0x2000 => Op::Call(nnn),
+        0x3000 => Op::SeByte(x, nn),
+        0x4000 => Op::SneByte(x, nn),
+        0x5000 if n == 0 => Op::SeReg(x, y),
+        0x6000 => Op::LdByte(x, nn),
+        0x7000 => Op::AddByte(x, nn),
+        0x8000 => match n {
+            0x0 => Op::LdReg(x, y),
+            0x1 => Op::Or(x, y),
+            0x2 => Op::And(x, y),
+            0x3 => Op::Xor(x, y),
+            0x4 => Op::AddReg(x, y),
+            0x5 => Op::Sub(x, y),
+            0x6 => Op::Shr(x, y),
+            0x7 => Op::Subn(x, y),
+            0xE => Op::Shl(x, y),
+            _ => Op::Data(instr),
+        },
+        0x9000 if n == 0 => Op::SneReg(x, y),
+        0xA000 => Op::LdI(nnn),
+        0xB000 => Op::JmpV0(x, nnn),
+        0xC000 => Op::Rnd(x, nn),
+        0xD000 => Op::Drw(x, y, n),
+        0xE000 => match nn {
+            0x9E => Op::Skp(x),
+            0xA1 => Op::Sknp(x),
+            _ => Op::Data(instr),
+        },
+        0xF000 => match nn {
+            0x07 => Op::LdVxDt(x),
+            0x0A => Op::LdVxK(x),
+            0x15 => Op::LdDtVx(x),
+            0x18 => Op::LdStVx(x),
+            0x1E => Op::AddI(x),
+            0x29 => Op::LdF(x),
+            0x33 => Op::LdB(x),
+            0x55 => Op::Store(x),
+            0x65 => Op::Load(x),
+            _ => Op::Data(instr),
+        },
+        _ => Op::Data(instr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_system_instructions_exactly() {
+        assert_eq!(decode(0x00E0), Op::Cls);
+        assert_eq!(decode(0x00EE), Op::Ret);
+        assert_eq!(decode(0x0000), Op::Sys(0x000));
+        assert_eq!(decode(0x00E1), Op::Sys(0x0E1));
+        assert_eq!(decode(0x012E), Op::Sys(0x12E));
+    }
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(decode(0x1234), Op::Jmp(0x234));
+        assert_eq!(decode(0x3A7F), Op::SeByte(0xA, 0x7F));
+        assert_eq!(decode(0x8AB4), Op::AddReg(0xA, 0xB));
+        assert_eq!(decode(0x8ABE), Op::Shl(0xA, 0xB));
+        assert_eq!(decode(0xB2FF), Op::JmpV0(0x2, 0x2FF));
+        assert_eq!(decode(0xD12F), Op::Drw(0x1, 0x2, 0xF));
+        assert_eq!(decode(0xF355), Op::Store(0x3));
+    }
+
+    #[test]
+    fn decodes_unknown_instructions_as_data() {
+        for instr in [0x5121, 0x8128, 0x9121, 0xE19F, 0xF100, 0xF01E + 1] {
+            assert_eq!(decode(instr), Op::Data(instr));
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::constants;
+use crate::font;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+
+// On-screen display with a transient message and a persistent status,
+// drawn on top of the CHIP-8 image
+pub struct Osd {
+    // Transient message and its expiry time [ns]
+    message: Option<(String, u128)>,
+    // Persistent status (e.g. paused), empty if none
+    status: String,
+    // Whether the OSD changed since it was last drawn
+    dirty: bool,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            message: None,
+            status: String::new(),
+            dirty: false,
+        }
+    }
+
+    // Shows the message from time t [ns] for a few seconds
+    pub fn show(&mut self, text: &str, t: u128) {
+        self.message = Some((text.to_string(), t + constants::OSD_MESSAGE_NS));
+        self.dirty = true;
+    }
+
+    // Sets the persistent status, empty to hide it
+    pub fn set_status(&mut self, text: &str) {
+        if self.status != text {
+            self.status = text.to_string();
+            self.dirty = true;
+        }
+    }
+
+    // Expires the message at time t [ns], returns true if the OSD needs to be redrawn
+    pub fn update(&mut self, t: u128) -> bool {
+        if let Some((_, expiry)) = self.message {
+            if t >= expiry {
+                self.message = None;
+                self.dirty = true;
+            }
+        }
+        self.dirty
+    }
+
+    // Draws the status at the top-right and the message at the bottom-left corner
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, scale: u32) {
+        let px = (scale / constants::OSD_SCALE_DIV).max(1);
+        let (width, height) = canvas.output_size().unwrap();
+        let margin = (2 * px) as i32;
+
+        if !self.status.is_empty() {
+            let w = font::text_width(&self.status, px) as i32;
+            draw_label(canvas, &self.status, width as i32 - w - margin, margin, px);
+        }
+        if let Some((text, _)) = &self.message {
+            let h = (font::GLYPH_HEIGHT * px) as i32;
+            draw_label(canvas, text, margin, height as i32 - h - margin, px);
+        }
+        self.dirty = false;
+    }
+}
+
+// Draws the text over a translucent box
+fn draw_label(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, px: u32) {
+    let pad = px as i32;
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas
+        .fill_rect(Rect::new(
+            x - pad,
+            y - pad,
+            font::text_width(text, px) + px,
+            font::GLYPH_HEIGHT * px + 2 * px,
+        ))
+        .unwrap();
+    canvas.set_blend_mode(BlendMode::None);
+    font::draw_text(canvas, text, x, y, px, Color::RGB(255, 255, 255));
+}
@@ -0,0 +1,211 @@
+use crate::constants;
+use crate::opcode::{self, Op};
+use crate::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Number of entries in each ranking of the report
+const REPORT_LINES: usize = 20;
+// Width of the bars in the heatmap
+const BAR_WIDTH: usize = 20;
+
+// Instruction counts of a subroutine
+#[derive(Default)]
+struct Subroutine {
+    calls: u64,
+    // Instructions run between the calls and their returns, including nested calls
+    inclusive: u64,
+    // Instructions run in the subroutine itself
+    exclusive: u64,
+}
+
+// ROM profiler counting the executions per address and per subroutine.
+// Subroutines are tracked with CALL and RET. The code outside any subroutine is
+// accounted to the program start address.
+pub struct Profile {
+    // Executions per address
+    pub counts: Vec<u64>,
+    // Total instructions run
+    pub total: u64,
+    subroutines: HashMap<usize, Subroutine>,
+    // Active calls: subroutine address and total at the call
+    calls: Vec<(usize, u64)>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            counts: vec![0; constants::RAM_SIZE],
+            total: 0,
+            subroutines: HashMap::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    // Records the instruction at the given address, before it runs
+    pub fn record(&mut self, pc: usize, instr: u16) {
+        self.counts[pc] += 1;
+        self.total += 1;
+        let current = self.calls.last().map_or(constants::PROGRAM_LOC, |c| c.0);
+        self.subroutines.entry(current).or_default().exclusive += 1;
+
+        match opcode::decode(instr) {
+            Op::Call(addr) => {
+                let addr = addr as usize;
+                self.subroutines.entry(addr).or_default().calls += 1;
+                self.calls.push((addr, self.total));
+                // Calls that never return would grow the list forever
+                if self.calls.len() > constants::STACK_SIZE {
+                    self.calls.remove(0);
+                }
+            }
+            Op::Ret => {
+                // Counting recursive calls once
+                if let Some((addr, start)) = self.calls.pop() {
+                    if !self.calls.iter().any(|c| c.0 == addr) {
+                        self.subroutines.entry(addr).or_default().inclusive += self.total - start;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Forgets the active calls, when the machine is reset
+    pub fn restart(&mut self) {
+        self.calls.clear();
+    }
+
+    // Returns the ranked hotspot and subroutine report, with the labels of the addresses
+    pub fn report(&self, ram: &[u8], symbols: &Symbols) -> String {
+        let mut report = format!("Profile: {} instructions\n", self.total);
+
+        let mut addrs: Vec<usize> = (0..self.counts.len())
+            .filter(|a| self.counts[*a] > 0)
+            .collect();
+        addrs.sort_by_key(|a| std::cmp::Reverse(self.counts[*a]));
+        report
+            .push_str("Hotspots:\n  ADDR        COUNT       %  INSTRUCTION               LABEL\n");
+        for addr in addrs.iter().take(REPORT_LINES) {
+            let count = self.counts[*addr];
+            let instr = fetch(ram, *addr);
+            report.push_str(&format!(
+                "  0x{:04x} {:>10} {:>6.2}%  {:04x} {:<20} {}\n",
+                addr,
+                count,
+                self.percent(count),
+                instr,
+                symbols.disassemble(instr),
+                symbols.locate(*addr).unwrap_or_default()
+            ));
+        }
+
+        let mut subs: Vec<(&usize, &Subroutine)> = self.subroutines.iter().collect();
+        subs.sort_by_key(|(addr, s)| (std::cmp::Reverse(self.inclusive(**addr, s)), **addr));
+        report.push_str(
+            "Subroutines:\n  ADDR        CALLS   INCLUSIVE       %   EXCLUSIVE       %  LABEL\n",
+        );
+        for (addr, s) in subs.iter().take(REPORT_LINES) {
+            let inclusive = self.inclusive(**addr, s);
+            report.push_str(&format!(
+                "  0x{:04x} {:>10} {:>11} {:>6.2}% {:>11} {:>6.2}%  {}\n",
+                addr,
+                s.calls,
+                inclusive,
+                self.percent(inclusive),
+                s.exclusive,
+                self.percent(s.exclusive),
+                symbols.name(**addr).unwrap_or_default()
+            ));
+        }
+        report
+    }
+
+    // Writes the heatmap: the disassembly of the executed addresses annotated with
+    // their execution counts, and their labels
+    pub fn write_heatmap(&self, path: &str, ram: &[u8], symbols: &Symbols) -> Result<(), String> {
+        let error = |e: std::io::Error| format!("Error writing '{}': {}", path, e);
+        let file = File::create(path).map_err(error)?;
+        let mut out = BufWriter::new(file);
+        let max = self.counts.iter().max().copied().unwrap_or(0).max(1);
+        writeln!(out, "# ADDR  COUNT  PERCENT  HEAT  OPCODE  INSTRUCTION").map_err(error)?;
+        for (addr, count) in self.counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            if let Some(name) = symbols.name(addr) {
+                writeln!(out, "{}:", name).map_err(error)?;
+            }
+            let bar = "#".repeat(((*count * BAR_WIDTH as u64).div_ceil(max)) as usize);
+            let instr = fetch(ram, addr);
+            writeln!(
+                out,
+                "{:04x} {:>10} {:>6.2}% {:<w$} {:04x} {}",
+                addr,
+                count,
+                self.percent(*count),
+                bar,
+                instr,
+                symbols.disassemble(instr),
+                w = BAR_WIDTH
+            )
+            .map_err(error)?;
+        }
+        out.flush().map_err(error)
+    }
+
+    // Returns the inclusive count of the subroutine, everything for the program start
+    fn inclusive(&self, addr: usize, s: &Subroutine) -> u64 {
+        if addr == constants::PROGRAM_LOC && s.calls == 0 {
+            self.total
+        } else {
+            s.inclusive
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        100.0 * count as f64 / self.total.max(1) as f64
+    }
+}
+
+// Returns the instruction at the given address, zero past the end of the memory
+fn fetch(ram: &[u8], addr: usize) -> u16 {
+    match ram.get(addr..addr + 2) {
+        Some(b) => ((b[0] as u16) << 8) | b[1] as u16,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_subroutines_by_call_and_return() {
+        let mut profile = Profile::new();
+        // CALL 0x300; at 0x300: ADD V0, 1; a SYS 0x0EF that is not a RET; RET
+        for (pc, instr) in [
+            (0x200, 0x2300),
+            (0x300, 0x7001),
+            (0x302, 0x00EF),
+            (0x304, 0x00EE),
+        ] {
+            profile.record(pc, instr);
+        }
+        let subroutine = &profile.subroutines[&0x300];
+        assert_eq!(
+            (subroutine.calls, subroutine.inclusive, subroutine.exclusive),
+            (1, 3, 3)
+        );
+        assert!(profile.calls.is_empty());
+        assert_eq!((profile.total, profile.counts[0x300]), (4, 1));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::chip8::{self, Chip8};
+use crate::constants;
+use crate::quirks::Quirks;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::collections::HashMap;
+
+// Python extension module 'rchip8', built with the 'python' feature, e.g. with maturin.
+// It drives the machine without display nor timing:
+//
+//     import numpy as np
+//     import rchip8
+//     chip8 = rchip8.Chip8(open("game.ch8", "rb").read(), cycles_per_frame=15)
+//     chip8.set_keys(1 << 5)
+//     chip8.frame(4)
+//     screen = np.frombuffer(chip8.framebuffer(), np.uint8).reshape(rchip8.HEIGHT, rchip8.WIDTH)
+#[pymodule]
+fn rchip8(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyChip8>()?;
+    m.add_class::<PyState>()?;
+    m.add("WIDTH", constants::DISPLAY_WIDTH)?;
+    m.add("HEIGHT", constants::DISPLAY_HEIGHT)?;
+    m.add("RAM_SIZE", constants::RAM_SIZE)?;
+    m.add("QUIRKS", crate::quirks::NAMES.to_vec())?;
+    Ok(())
+}
+
+// Machine state returned by Chip8.save_state()
+#[pyclass(name = "State", module = "rchip8")]
+#[derive(Clone)]
+struct PyState(chip8::State);
+
+// CHIP-8 machine running a ROM
+#[pyclass(name = "Chip8", module = "rchip8")]
+struct PyChip8 {
+    chip8: Chip8,
+}
+
+#[pymethods]
+impl PyChip8 {
+    // Loads the ROM with the speed in instructions per frame, the quirks of the platform
+    // ('chip8', 'superchip', 'xochip'...) and the given quirks on top of them
+    #[new]
+    #[pyo3(signature = (rom, cycles_per_frame=15, platform=None, quirks=None, seed=None))]
+    fn new(
+        rom: Vec<u8>,
+        cycles_per_frame: u32,
+        platform: Option<&str>,
+        quirks: Option<HashMap<String, bool>>,
+        seed: Option<u64>,
+    ) -> PyResult<Self> {
+        let mut chip8 = Chip8::new(rom, 0, cycles_per_frame.max(1), false);
+        if let Some(platform) = platform {
+            chip8.quirks = Quirks::platform(platform)
+                .ok_or_else(|| error(format!("Unknown platform '{}'", platform)))?;
+        }
+        for (name, value) in quirks.unwrap_or_default() {
+            chip8.quirks.set(&name, value).map_err(error)?;
+        }
+        if let Some(seed) = seed {
+            chip8.seed(seed);
+        }
+        Ok(PyChip8 { chip8 })
+    }
+
+    // Restarts the machine with a new ROM, keeping the speed and the quirks
+    fn load(&mut self, rom: Vec<u8>) {
+        self.chip8.reset(rom, 0);
+    }
+
+    // Runs one instruction
+    fn step(&mut self) {
+        self.chip8.step();
+    }
+
+    // Runs the given number of frames, ticking the timers at the end of each one
+    #[pyo3(signature = (frames=1))]
+    fn frame(&mut self, frames: u32) {
+        for _ in 0..frames {
+            self.chip8.run_frame();
+        }
+        // No audio output to drain them
+        self.chip8.sound_events.clear();
+    }
+
+    // Seeds the random number generator of RND
+    fn seed(&mut self, seed: u64) {
+        self.chip8.seed(seed);
+    }
+
+    // Returns the display as WIDTH * HEIGHT bytes row by row, 1 if the pixel is on
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.chip8.display)
+    }
+
+    // Presses or releases the key 0x0-0xF
+    fn set_key(&mut self, key: usize, pressed: bool) -> PyResult<()> {
+        let state = self
+            .chip8
+            .keys
+            .get_mut(key)
+            .ok_or_else(|| error(format!("Invalid key {}", key)))?;
+        *state = pressed;
+        Ok(())
+    }
+
+    // Sets the pressed keys from a mask with the bit N set for the key N
+    fn set_keys(&mut self, mask: u16) {
+        for (key, pressed) in self.chip8.keys.iter_mut().enumerate() {
+            *pressed = mask & (1 << key) != 0;
+        }
+    }
+
+    fn save_state(&self) -> PyState {
+        PyState(self.chip8.save_state())
+    }
+
+    fn load_state(&mut self, state: &PyState) {
+        self.chip8.load_state(&state.0);
+    }
+
+    // Reads the given number of bytes of the RAM at the address
+    #[pyo3(signature = (addr, len=1))]
+    fn peek<'py>(&self, py: Python<'py>, addr: usize, len: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let bytes = self
+            .chip8
+            .bus
+            .ram
+            .get(addr..addr.saturating_add(len))
+            .ok_or_else(|| error(format!("Out of memory: 0x{:04x}+{}", addr, len)))?;
+        Ok(PyBytes::new(py, bytes))
+    }
+
+    // Writes the bytes to the RAM at the address
+    fn poke(&mut self, addr: usize, data: Vec<u8>) -> PyResult<()> {
+        let bytes = self
+            .chip8
+            .bus
+            .ram
+            .get_mut(addr..addr.saturating_add(data.len()))
+            .ok_or_else(|| error(format!("Out of memory: 0x{:04x}+{}", addr, data.len())))?;
+        bytes.copy_from_slice(&data);
+        Ok(())
+    }
+
+    #[getter]
+    fn registers(&self) -> Vec<u8> {
+        self.chip8.registers.to_vec()
+    }
+
+    #[getter]
+    fn index(&self) -> u16 {
+        self.chip8.index
+    }
+
+    #[getter]
+    fn pc(&self) -> usize {
+        self.chip8.pc
+    }
+
+    #[getter]
+    fn dt(&self) -> u8 {
+        self.chip8.dt
+    }
+
+    #[getter]
+    fn st(&self) -> u8 {
+        self.chip8.st
+    }
+
+    #[getter]
+    fn frame_count(&self) -> u64 {
+        self.chip8.frame_count
+    }
+
+    #[getter]
+    fn instruction_count(&self) -> u64 {
+        self.chip8.instruction_count
+    }
+}
+
+fn error(message: String) -> PyErr {
+    PyValueError::new_err(message)
+}
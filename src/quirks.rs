@@ -0,0 +1,126 @@
+// Behavior differences between the CHIP-8 interpreters, named as in the chip-8-database
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place instead of VY into VX
+    pub shift: bool,
+    // FX55/FX65 increment I by X instead of X + 1
+    pub memory_increment_by_x: bool,
+    // FX55/FX65 leave I unchanged
+    pub memory_leave_i_unchanged: bool,
+    // DXYN wraps the sprites around the edges instead of clipping them
+    pub wrap: bool,
+    // BNNN jumps to NNN + VX instead of NNN + V0
+    pub jump: bool,
+    // DXYN waits for the next frame
+    pub vblank: bool,
+    // 8XY1/8XY2/8XY3 reset VF
+    pub logic: bool,
+}
+
+// Names of the quirks
+pub const NAMES: [&str; 7] = [
+    "shift",
+    "memoryIncrementByX",
+    "memoryLeaveIUnchanged",
+    "wrap",
+    "jump",
+    "vblank",
+    "logic",
+];
+
+impl Default for Quirks {
+    // Behavior of this emulator
+    fn default() -> Self {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+impl Quirks {
+    // Returns the flag of the quirk with the given name
+    fn flag(&mut self, name: &str) -> Result<&mut bool, String> {
+        match name {
+            "shift" => Ok(&mut self.shift),
+            "memoryIncrementByX" => Ok(&mut self.memory_increment_by_x),
+            "memoryLeaveIUnchanged" => Ok(&mut self.memory_leave_i_unchanged),
+            "wrap" => Ok(&mut self.wrap),
+            "jump" => Ok(&mut self.jump),
+            "vblank" => Ok(&mut self.vblank),
+            "logic" => Ok(&mut self.logic),
+            _ => Err(format!(
+                "Unknown quirk '{}', expected one of: {}",
+                name,
+                NAMES.join(", ")
+            )),
+        }
+    }
+
+    // Sets the quirk with the given name
+    pub fn set(&mut self, name: &str, value: bool) -> Result<(), String> {
+        *self.flag(name)? = value;
+        Ok(())
+    }
+
+    // Returns the value of the quirk with the given name
+    pub fn get(&self, name: &str) -> Result<bool, String> {
+        let mut quirks = *self;
+        quirks.flag(name).map(|f| *f)
+    }
+
+    // Parses and sets a quirk override 'NAME=true|false'
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        let (name, value) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=true|false, found '{}'", spec))?;
+        let value = match value.trim() {
+            "true" | "1" | "on" => true,
+            "false" | "0" | "off" => false,
+            _ => return Err(format!("Invalid value for quirk '{}': '{}'", name, value)),
+        };
+        self.set(name.trim(), value)
+    }
+
+    // Returns the quirks of the given chip-8-database platform id
+    pub fn platform(id: &str) -> Option<Quirks> {
+        let (shift, inc_x, leave_i, wrap, jump, vblank, logic) = match id {
+            "originalChip8" | "hybridVIP" => (false, false, false, false, false, true, true),
+            "modernChip8" => (false, false, false, false, false, false, false),
+            "chip48" => (true, true, false, false, true, false, false),
+            "superchip1" => (true, true, false, false, true, false, false),
+            "superchip" => (true, false, true, false, true, false, false),
+            "megachip8" => (true, false, true, false, true, false, false),
+            "xochip" => (false, false, false, true, false, false, false),
+            _ => return None,
+        };
+        Some(Quirks {
+            shift,
+            memory_increment_by_x: inc_x,
+            memory_leave_i_unchanged: leave_i,
+            wrap,
+            jump,
+            vblank,
+            logic,
+        })
+    }
+
+    // Returns a summary of the quirks that are set, e.g. 'shift, jump'
+    pub fn summary(&self) -> String {
+        let set: Vec<&str> = NAMES
+            .iter()
+            .filter(|n| self.get(n).unwrap_or(false))
+            .copied()
+            .collect();
+        if set.is_empty() {
+            "none".to_string()
+        } else {
+            set.join(", ")
+        }
+    }
+}
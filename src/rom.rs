@@ -0,0 +1,137 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+// Extensions of the ROM files
+pub const ROM_EXTENSIONS: [&str; 7] = ["ch8", "c8", "sc8", "xo8", "hc8", "rom", "bin"];
+// Path that reads the ROM from the standard input
+pub const STDIN: &str = "-";
+
+// Reads the ROM at the given path, which is one of:
+// - '-' for the standard input.
+// - A zip archive, 'archive.zip/NAME' for one of its files. Without a name, the archive
+//   must have a single ROM.
+// - A plain ROM file.
+// Octo cartridges (.gif) hold the Octo source of the program, not a ROM, and are rejected.
+pub fn read(path: &str) -> Result<Vec<u8>, String> {
+    if path == STDIN {
+        let mut rom = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut rom)
+            .map_err(|e| format!("Error reading the ROM from the standard input: {}", e))?;
+        return Ok(rom);
+    }
+    if let Some((archive, name)) = split_archive(path) {
+        return read_archive(archive, name);
+    }
+    if is_archive(path) {
+        let roms = archive_roms(path)?;
+        return match roms.as_slice() {
+            [name] => read_archive(path, name),
+            [] => Err(format!("No ROMs in the archive '{}'", path)),
+            _ => Err(format!(
+                "Several ROMs in the archive '{}', choose one as '{}/NAME': {}",
+                path,
+                path,
+                roms.join(", ")
+            )),
+        };
+    }
+    if has_extension(path, &["gif"]) {
+        return Err(format!(
+            "Octo cartridges are not supported: '{}'. Assemble the program with Octo and run the ROM",
+            path
+        ));
+    }
+    fs::read(path).map_err(|e| format!("Error reading '{}': {}", path, e))
+}
+
+// Returns the file of the path, the archive for the files in archives
+pub fn file(path: &str) -> &str {
+    split_archive(path).map_or(path, |(archive, _)| archive)
+}
+
+// Returns whether the file is a zip archive
+pub fn is_archive(path: &str) -> bool {
+    has_extension(path, &["zip"]) && Path::new(path).is_file()
+}
+
+// Returns whether the file name has a ROM extension
+pub fn is_rom(path: &str) -> bool {
+    has_extension(path, &ROM_EXTENSIONS)
+}
+
+// Returns the names of the ROMs in the zip archive
+pub fn archive_roms(path: &str) -> Result<Vec<String>, String> {
+    let archive = open_archive(path)?;
+    let mut roms: Vec<String> = archive
+        .file_names()
+        .filter(|name| is_rom(name))
+        .map(|name| name.to_string())
+        .collect();
+    roms.sort();
+    Ok(roms)
+}
+
+// Splits the path of a file in an archive into the archive and the file name
+fn split_archive(path: &str) -> Option<(&str, &str)> {
+    // Compared in place, as lowercasing may change the byte offsets of other characters
+    let end = path.match_indices('/').map(|(i, _)| i).find(|&i| {
+        i >= ".zip".len()
+            && path
+                .get(i - ".zip".len()..i)
+                .is_some_and(|e| e.eq_ignore_ascii_case(".zip"))
+    })?;
+    Some((&path[..end], &path[end + 1..]))
+}
+
+fn open_archive(path: &str) -> Result<zip::ZipArchive<File>, String> {
+    let file = File::open(path).map_err(|e| format!("Error reading '{}': {}", path, e))?;
+    zip::ZipArchive::new(file).map_err(|e| format!("Invalid zip archive '{}': {}", path, e))
+}
+
+fn read_archive(path: &str, name: &str) -> Result<Vec<u8>, String> {
+    let mut archive = open_archive(path)?;
+    let mut file = archive
+        .by_name(name)
+        .map_err(|e| format!("Error reading '{}' in '{}': {}", name, path, e))?;
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)
+        .map_err(|e| format!("Error reading '{}' in '{}': {}", name, path, e))?;
+    Ok(rom)
+}
+
+fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.contains(&e.to_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octo_cartridges_are_rejected() {
+        let error = read("game.GIF").unwrap_err();
+        assert!(
+            error.starts_with("Octo cartridges are not supported"),
+            "{}",
+            error
+        );
+    }
+    #[test]
+    fn archive_paths_are_split_at_the_archive() {
+        assert_eq!(
+            split_archive("roms.ZIP/game.ch8"),
+            Some(("roms.ZIP", "game.ch8"))
+        );
+        assert_eq!(
+            split_archive("İİ/Ω.zip/a/b.ch8"),
+            Some(("İİ/Ω.zip", "a/b.ch8"))
+        );
+        assert_eq!(split_archive("ẞ.zipper/game.ch8"), None);
+        assert_eq!(split_archive("game.ch8"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::quirks::Quirks;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Platforms whose instruction set is implemented, the others only get their quirks
+pub const SUPPORTED_PLATFORMS: [&str; 4] = ["originalChip8", "hybridVIP", "modernChip8", "chip48"];
+
+// Database built into the executable, a pinned copy of the chip-8-database
+// 'programs.json' replaced by the one given with --rom-db or found in the data directory
+const BUILTIN: &str = include_str!("../data/programs.json");
+
+// Metadata of a ROM from the database
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    // chip-8-database platform id, e.g. 'originalChip8'
+    pub platform: Option<String>,
+    pub quirks: Option<Quirks>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    // CHIP-8 keys of the game buttons, e.g. ('up', 5)
+    pub keys: Vec<(String, u8)>,
+    // Background and foreground colors as hex codes
+    pub colors: Option<(String, String)>,
+}
+
+// ROM metadata database in the format of the community chip-8-database
+// (https://github.com/chip-8/chip-8-database): a 'programs.json' array of programs,
+// each one with its ROMs keyed by the SHA-1 of their bytes.
+pub struct RomDb {
+    programs: Vec<Value>,
+    // Program index of each ROM hash
+    index: HashMap<String, usize>,
+    // Where the database comes from: its file, or 'built-in'
+    source: String,
+}
+
+impl RomDb {
+    // Loads the database from the given 'programs.json' file or directory containing
+    // it. Without a path, loads it from the default location if it exists, or else the
+    // built-in copy.
+    pub fn load(path: Option<&str>) -> Result<RomDb, String> {
+        let file = match path {
+            Some(path) => find_programs(Path::new(path))
+                .ok_or_else(|| format!("ROM database not found in '{}'", path))?,
+            None => match default_dir().and_then(|dir| find_programs(&dir)) {
+                Some(file) => file,
+                None => return Ok(RomDb::builtin()),
+            },
+        };
+        let text = fs::read_to_string(&file)
+            .map_err(|e| format!("Error reading '{}': {}", file.display(), e))?;
+        RomDb::parse(&text, &file.display().to_string())
+    }
+
+    // Returns the database built into the executable
+    pub fn builtin() -> RomDb {
+        RomDb::parse(BUILTIN, "built-in").unwrap()
+    }
+
+    // Parses the database text, the source naming where it comes from in the messages
+    fn parse(text: &str, source: &str) -> Result<RomDb, String> {
+        let programs: Vec<Value> = serde_json::from_str(text)
+            .map_err(|e| format!("Invalid ROM database '{}': {}", source, e))?;
+
+        let mut index = HashMap::new();
+        for (i, program) in programs.iter().enumerate() {
+            if let Some(roms) = program.get("roms").and_then(|r| r.as_object()) {
+                for hash in roms.keys() {
+                    index.insert(hash.to_lowercase(), i);
+                }
+            }
+        }
+        Ok(RomDb {
+            programs,
+            index,
+            source: source.to_string(),
+        })
+    }
+
+    // Describes the database for the load message, e.g. '1 programs, 2 ROMs [built-in]'
+    pub fn summary(&self) -> String {
+        format!(
+            "{} programs, {} ROMs [{}]",
+            self.programs.len(),
+            self.index.len(),
+            self.source
+        )
+    }
+
+    // Returns the metadata of the ROM with the given SHA-1
+    pub fn lookup(&self, hash: &str) -> Option<RomInfo> {
+        let program = &self.programs[*self.index.get(hash)?];
+        let (_, rom) = program
+            .get("roms")?
+            .as_object()?
+            .iter()
+            .find(|(h, _)| h.eq_ignore_ascii_case(hash))?;
+
+        let string = |v: &Value| v.as_str().map(|s| s.to_string());
+        let title = program
+            .get("title")
+            .and_then(string)
+            .unwrap_or_else(|| "Unknown".to_string());
+        let authors = program
+            .get("authors")
+            .and_then(|a| a.as_array())
+            .map(|a| a.iter().filter_map(string).collect())
+            .unwrap_or_default();
+        let platform = rom
+            .get("platforms")
+            .and_then(|p| p.as_array())
+            .and_then(|p| p.first())
+            .and_then(string);
+
+        // Quirks of the platform, with the ROM specific ones on top
+        let quirks = platform.as_deref().and_then(Quirks::platform).map(|mut q| {
+            let quirky = rom
+                .get("quirkyPlatforms")
+                .and_then(|q| q.get(platform.as_deref()?))
+                .and_then(|q| q.as_object());
+            for (name, value) in quirky.into_iter().flatten() {
+                if let Some(value) = value.as_bool() {
+                    if let Err(error) = q.set(name, value) {
+                        println!("{}", error);
+                    }
+                }
+            }
+            q
+        });
+        let tickrate = rom
+            .get("tickrate")
+            .and_then(|t| t.as_u64())
+            .map(|t| t as u32)
+            .or_else(|| platform.as_deref().and_then(default_tickrate));
+        let keys = rom
+            .get("keys")
+            .and_then(|k| k.as_object())
+            .map(|k| {
+                k.iter()
+                    .filter_map(|(name, key)| Some((name.clone(), key.as_u64()? as u8)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let colors = rom
+            .get("colors")
+            .and_then(|c| c.get("pixels"))
+            .and_then(|p| p.as_array())
+            .and_then(|p| {
+                let color =
+                    |i: usize| Some(p.get(i)?.as_str()?.trim_start_matches('#').to_string());
+                Some((color(0)?, color(1)?))
+            });
+
+        Some(RomInfo {
+            title,
+            authors,
+            platform,
+            quirks,
+            tickrate,
+            keys,
+            colors,
+        })
+    }
+}
+
+// Returns the SHA-1 of the ROM bytes as lowercase hex
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+// Returns the default instructions per frame of the given platform
+fn default_tickrate(platform: &str) -> Option<u32> {
+    match platform {
+        "originalChip8" | "hybridVIP" => Some(15),
+        "modernChip8" => Some(12),
+        "chip48" | "superchip1" | "superchip" => Some(30),
+        "xochip" => Some(100),
+        "megachip8" => Some(1000),
+        _ => None,
+    }
+}
+
+// Default database directory, where the chip-8-database repository can be cloned
+fn default_dir() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("rchip8").join("chip-8-database"))
+}
+
+// Returns the 'programs.json' file at the path, in it, or in its 'database' directory
+fn find_programs(path: &Path) -> Option<PathBuf> {
+    [
+        path.to_path_buf(),
+        path.join("programs.json"),
+        path.join("database").join("programs.json"),
+    ]
+    .into_iter()
+    .find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[{
+        "title": "Game",
+        "authors": ["Someone"],
+        "roms": {
+            "AF6EDE91B0585D92F2D16F4B241E65E84B6EF06D": {
+                "platforms": ["originalChip8"],
+                "quirkyPlatforms": {"originalChip8": {"shift": true}},
+                "keys": {"up": 5, "a": 6},
+                "colors": {"pixels": ["#000000", "#ff0000"]}
+            }
+        }
+    }]"##;
+
+    #[test]
+    fn builtin_database_finds_its_roms() {
+        let db = RomDb::builtin();
+        let programs: Vec<Value> = serde_json::from_str(BUILTIN).unwrap();
+        let roms = programs.iter().filter_map(|p| p.get("roms")?.as_object());
+        for hash in roms.flat_map(|roms| roms.keys()) {
+            assert!(db.lookup(&hash.to_lowercase()).is_some(), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn lookup_reads_the_rom_metadata() {
+        let db = RomDb::parse(PROGRAMS, "test").unwrap();
+        assert!(db
+            .lookup("0000000000000000000000000000000000000000")
+            .is_none());
+        let info = db
+            .lookup("af6ede91b0585d92f2d16f4b241e65e84b6ef06d")
+            .unwrap();
+        assert_eq!(info.title, "Game");
+        assert_eq!(info.authors, ["Someone"]);
+        assert_eq!(info.platform.as_deref(), Some("originalChip8"));
+        assert_eq!(info.tickrate, Some(15));
+        assert!(info.quirks.unwrap().shift);
+        assert_eq!(info.keys, [("a".to_string(), 6), ("up".to_string(), 5)]);
+        assert_eq!(
+            info.colors,
+            Some(("000000".to_string(), "ff0000".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_database_is_an_error() {
+        assert!(RomDb::parse("{", "test").is_err());
+    }
+}
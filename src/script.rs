@@ -0,0 +1,540 @@
+use crate::bus::AccessKind;
+use crate::chip8::Chip8;
+use crate::constants;
+use crate::expr::Register;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Automation script in Rhai, run by the machine on its events for automated playtesting
+// and ROM test scenarios. The top level runs when the script is loaded and registers the
+// hooks, and the script functions run on the events:
+//
+//     on_exec(0x2A4, "crash");        // before the instruction at the address (or label())
+//     on_write(0x2F0, "score");       // after an instruction writes the address or range
+//     fn on_frame() { ... }           // at the end of every frame
+//     fn on_break(reason) { true }    // at breakpoints and watchpoints, true to keep running
+//     fn crash() { assert(reg("V3") < 8, "fell off"); screenshot("crash.gif"); quit(); }
+//     fn score(addr, value) { print(`score ${value}`); }
+//
+// The functions read and write the machine with reg(), set_reg(), peek(), poke() and
+// pixel(), hold keys with press() and release(), and keep their data in the map 'this',
+// e.g. 'this.hits = (this.hits ?? 0) + 1'. An error ends the run as a failure.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    // Data of the functions, bound to 'this'
+    this: Dynamic,
+    // Machine state and hooks shared with the native functions during a call
+    shared: Arc<Mutex<Shared>>,
+    // Hook functions by instruction address
+    exec: HashMap<usize, String>,
+    // Hook functions by inclusive range of written addresses
+    writes: Vec<(usize, usize, String)>,
+    // Whether on_frame() and on_break() are defined
+    on_frame: bool,
+    on_break: bool,
+    // Keys held by the script, pressed on top of the keypad
+    held: [bool; constants::N_KEYS],
+    // Keys held by the script that the keypad did not press, released with them
+    pressed: [bool; constants::N_KEYS],
+    // Number of failed assertions and script errors
+    pub failures: u32,
+    // Whether the script asked to end the run
+    pub quit: bool,
+}
+
+// Copy of the machine the native functions work on, written back after the call
+struct Shared {
+    registers: [u8; constants::N_REGISTERS],
+    index: u16,
+    istack: usize,
+    pc: usize,
+    dt: u8,
+    st: u8,
+    ram: [u8; constants::RAM_SIZE],
+    display: [u8; constants::DISPLAY_LEN],
+    frame_count: u64,
+    held: [bool; constants::N_KEYS],
+    // Hooks registered during the call
+    exec: HashMap<usize, String>,
+    writes: Vec<(usize, usize, String)>,
+    failures: u32,
+    quit: bool,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Shared {
+            registers: [0; constants::N_REGISTERS],
+            index: 0,
+            istack: 0,
+            pc: 0,
+            dt: 0,
+            st: 0,
+            ram: [0; constants::RAM_SIZE],
+            display: [0; constants::DISPLAY_LEN],
+            frame_count: 0,
+            held: [false; constants::N_KEYS],
+            exec: HashMap::new(),
+            writes: Vec::new(),
+            failures: 0,
+            quit: false,
+        }
+    }
+
+    // Copies the machine in
+    fn load(&mut self, chip8: &Chip8) {
+        self.registers = chip8.registers;
+        self.index = chip8.index;
+        self.istack = chip8.istack;
+        self.pc = chip8.pc;
+        self.dt = chip8.dt;
+        self.st = chip8.st;
+        self.ram = chip8.bus.ram;
+        self.display = chip8.display;
+        self.frame_count = chip8.frame_count;
+    }
+
+    // Writes the machine back
+    fn store(&self, chip8: &mut Chip8) {
+        chip8.registers = self.registers;
+        chip8.index = self.index;
+        chip8.istack = self.istack;
+        chip8.pc = self.pc;
+        chip8.dt = self.dt;
+        chip8.st = self.st;
+        chip8.bus.ram = self.ram;
+    }
+
+    fn set_reg(&mut self, reg: Register, value: i64) -> ScriptResult<()> {
+        match reg {
+            Register::V(x) => self.registers[x] = value as u8,
+            Register::I => self.index = value as u16,
+            Register::Pc => self.pc = addr(value)?,
+            Register::Sp if (0..=constants::STACK_SIZE as i64).contains(&value) => {
+                self.istack = value as usize
+            }
+            Register::Sp => return Err(format!("Invalid stack pointer {}", value).into()),
+            Register::Dt => self.dt = value as u8,
+            Register::St => self.st = value as u8,
+        }
+        Ok(())
+    }
+
+    fn reg(&self, reg: Register) -> i64 {
+        match reg {
+            Register::V(x) => self.registers[x] as i64,
+            Register::I => self.index as i64,
+            Register::Pc => self.pc as i64,
+            Register::Sp => self.istack as i64,
+            Register::Dt => self.dt as i64,
+            Register::St => self.st as i64,
+        }
+    }
+}
+
+impl Script {
+    // Loads the script and runs its top level on the machine, with its labels
+    pub fn load(path: &str, chip8: &mut Chip8) -> Result<Script, String> {
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Error reading '{}': {}", path, e))?;
+        let shared = Arc::new(Mutex::new(Shared::new()));
+        let engine = engine(&shared, chip8);
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| format!("Error in script '{}': {}", path, e))?;
+
+        let mut scope = Scope::new();
+        shared.lock().unwrap().load(chip8);
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| format!("Error in script '{}': {}", path, e))?;
+
+        let defined = |name: &str, params: usize| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == params)
+        };
+        let (on_frame, on_break) = (defined("on_frame", 0), defined("on_break", 1));
+        let mut script = Script {
+            engine,
+            ast,
+            scope,
+            this: Map::new().into(),
+            shared: shared.clone(),
+            exec: HashMap::new(),
+            writes: Vec::new(),
+            on_frame,
+            on_break,
+            held: [false; constants::N_KEYS],
+            pressed: [false; constants::N_KEYS],
+            failures: 0,
+            quit: false,
+        };
+        script.finish_call(chip8);
+        Ok(script)
+    }
+
+    // Runs the hooks before the next instruction, and holds the keys of the script over
+    // the keypad
+    pub fn before_instruction(&mut self, chip8: &mut Chip8) {
+        self.hold_keys(chip8);
+        if let Some(name) = self.exec.get(&chip8.pc).cloned() {
+            self.call(chip8, &name, ());
+        }
+    }
+
+    // Runs the hooks of the addresses written by the last instruction
+    pub fn after_instruction(&mut self, chip8: &mut Chip8) {
+        if self.writes.is_empty() {
+            return;
+        }
+        let hooks: Vec<(String, usize, u8)> = chip8
+            .bus
+            .accesses
+            .iter()
+            .filter(|a| a.kind == AccessKind::Write)
+            .flat_map(|a| {
+                self.writes
+                    .iter()
+                    .filter(move |(start, end, _)| a.addr >= *start && a.addr <= *end)
+                    .map(move |(_, _, name)| (name.clone(), a.addr, a.value))
+            })
+            .collect();
+        for (name, addr, value) in hooks {
+            self.call(chip8, &name, (addr as i64, value as i64));
+        }
+    }
+
+    // Runs on_frame() at the end of a frame
+    pub fn frame(&mut self, chip8: &mut Chip8) {
+        if self.on_frame {
+            self.call(chip8, "on_frame", ());
+        }
+    }
+
+    // Runs on_break() when the machine stops, returns true to keep running
+    pub fn stopped(&mut self, chip8: &mut Chip8, description: String) -> bool {
+        self.on_break
+            && self
+                .call(chip8, "on_break", (description,))
+                .is_some_and(|result| result.as_bool().unwrap_or(false))
+    }
+
+    // Calls the script function on the machine and returns its result, an error ends the
+    // run as a failure
+    fn call(&mut self, chip8: &mut Chip8, name: &str, args: impl FuncArgs) -> Option<Dynamic> {
+        self.shared.lock().unwrap().load(chip8);
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.this);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut self.scope,
+            &self.ast,
+            name,
+            args,
+        );
+        if let Err(error) = &result {
+            println!("Script error in {}(): {}", name, error);
+            let mut shared = self.shared.lock().unwrap();
+            shared.failures += 1;
+            shared.quit = true;
+        }
+        self.finish_call(chip8);
+        result.ok()
+    }
+
+    // Writes the machine back and takes the hooks registered and the outcome
+    fn finish_call(&mut self, chip8: &mut Chip8) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.store(chip8);
+        self.held = shared.held;
+        self.exec.extend(shared.exec.drain());
+        self.writes.append(&mut shared.writes);
+        self.failures = shared.failures;
+        self.quit = shared.quit;
+        drop(shared);
+        self.hold_keys(chip8);
+    }
+
+    // Presses the keys held over the keypad, and releases those let go that only the
+    // script pressed. The keys the player holds stay pressed, and the keypad sets all
+    // of them again at its next read.
+    fn hold_keys(&mut self, chip8: &mut Chip8) {
+        for (key, state) in chip8.keys.iter_mut().enumerate() {
+            if self.held[key] {
+                self.pressed[key] |= !*state;
+                *state = true;
+            } else if std::mem::take(&mut self.pressed[key]) {
+                *state = false;
+            }
+        }
+    }
+}
+
+// Creates the engine with the native functions of the scripts
+fn engine(shared: &Arc<Mutex<Shared>>, chip8: &Chip8) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = shared.clone();
+    engine.register_fn("reg", move |name: &str| -> ScriptResult<i64> {
+        Ok(s.lock().unwrap().reg(Register::parse(name)?))
+    });
+    let s = shared.clone();
+    engine.register_fn(
+        "set_reg",
+        move |name: &str, value: i64| -> ScriptResult<()> {
+            s.lock().unwrap().set_reg(Register::parse(name)?, value)
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn("peek", move |addr_: i64| -> ScriptResult<i64> {
+        Ok(s.lock().unwrap().ram[addr(addr_)?] as i64)
+    });
+    let s = shared.clone();
+    engine.register_fn("poke", move |addr_: i64, value: i64| -> ScriptResult<()> {
+        s.lock().unwrap().ram[addr(addr_)?] = value as u8;
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| -> bool {
+        let (x, y) = (x as usize, y as usize);
+        x < constants::DISPLAY_WIDTH
+            && y < constants::DISPLAY_HEIGHT
+            && s.lock().unwrap().display[y * constants::DISPLAY_WIDTH + x] != 0
+    });
+    let s = shared.clone();
+    engine.register_fn("frame", move || s.lock().unwrap().frame_count as i64);
+
+    let s = shared.clone();
+    engine.register_fn("press", move |k: i64| -> ScriptResult<()> {
+        s.lock().unwrap().held[key(k)?] = true;
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("release", move |k: i64| -> ScriptResult<()> {
+        s.lock().unwrap().held[key(k)?] = false;
+        Ok(())
+    });
+
+    let s = shared.clone();
+    engine.register_fn("screenshot", move |path: &str| -> ScriptResult<()> {
+        Ok(screenshot(&s.lock().unwrap().display, path)?)
+    });
+    let s = shared.clone();
+    engine.register_fn("assert", move |condition: bool, message: &str| {
+        let mut shared = s.lock().unwrap();
+        if !condition {
+            println!(
+                "Assertion failed at frame {}: {}",
+                shared.frame_count, message
+            );
+            shared.failures += 1;
+        }
+    });
+    let s = shared.clone();
+    engine.register_fn("quit", move || s.lock().unwrap().quit = true);
+
+    let s = shared.clone();
+    engine.register_fn(
+        "on_exec",
+        move |addr_: i64, name: &str| -> ScriptResult<()> {
+            s.lock()
+                .unwrap()
+                .exec
+                .insert(addr(addr_)?, name.to_string());
+            Ok(())
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn(
+        "on_write",
+        move |addr_: i64, name: &str| -> ScriptResult<()> {
+            let addr = addr(addr_)?;
+            s.lock()
+                .unwrap()
+                .writes
+                .push((addr, addr, name.to_string()));
+            Ok(())
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn(
+        "on_write",
+        move |start: i64, end: i64, name: &str| -> ScriptResult<()> {
+            let range = (addr(start)?, addr(end)?, name.to_string());
+            s.lock().unwrap().writes.push(range);
+            Ok(())
+        },
+    );
+    let symbols = chip8.symbols.clone();
+    engine.register_fn("label", move |name: &str| -> ScriptResult<i64> {
+        match symbols.addr(name) {
+            Some(addr) => Ok(addr as i64),
+            None => Err(format!("Unknown label '{}'", name).into()),
+        }
+    });
+    engine
+}
+
+// Checks a RAM address
+fn addr(addr: i64) -> ScriptResult<usize> {
+    if (0..constants::RAM_SIZE as i64).contains(&addr) {
+        Ok(addr as usize)
+    } else {
+        Err(format!("Out of memory: 0x{:x}", addr).into())
+    }
+}
+
+// Checks a key 0x0-0xF
+fn key(key: i64) -> ScriptResult<usize> {
+    if (0..constants::N_KEYS as i64).contains(&key) {
+        Ok(key as usize)
+    } else {
+        Err(format!("Invalid key {}", key).into())
+    }
+}
+
+// Writes the display to a GIF image, one pixel per CHIP-8 pixel in the default colors
+fn screenshot(display: &[u8; constants::DISPLAY_LEN], path: &str) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("Error writing '{}': {}", path, e);
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let (bg, fg) = (constants::DEF_BG, constants::DEF_FG);
+    let palette = [bg.0, bg.1, bg.2, fg.0, fg.1, fg.2];
+    let (width, height) = (
+        constants::DISPLAY_WIDTH as u16,
+        constants::DISPLAY_HEIGHT as u16,
+    );
+    let mut encoder = gif::Encoder::new(file, width, height, &palette).map_err(|e| error(&e))?;
+    let frame = gif::Frame {
+        width,
+        height,
+        buffer: Cow::Borrowed(display),
+        ..gif::Frame::default()
+    };
+    encoder.write_frame(&frame).map_err(|e| error(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Loads the script on a machine running the ROM
+    fn load(name: &str, rom: &[u8], source: &str) -> Chip8 {
+        let mut chip8 = Chip8::new(rom.to_vec(), 0, 10, false);
+        let path = std::env::temp_dir().join(format!(
+            "rchip8-script-{}-{}.rhai",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, source).unwrap();
+        let script = Script::load(path.to_str().unwrap(), &mut chip8);
+        fs::remove_file(&path).unwrap();
+        chip8.script = Some(script.unwrap());
+        chip8
+    }
+
+    fn script(chip8: &Chip8) -> &Script {
+        chip8.script.as_ref().unwrap()
+    }
+
+    #[test]
+    fn on_exec_runs_before_the_instruction() {
+        // LD V0, 1; loop: ADD V0, 1; JMP loop
+        let rom = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+        let source = r#"
+            on_exec(0x204, "jump");
+            fn jump() { poke(0x300, peek(0x300) + 1); poke(0x301, reg("V0")); }
+        "#;
+        let mut chip8 = load("exec", &rom, source);
+        for _ in 0..5 {
+            chip8.step();
+        }
+        assert_eq!(chip8.bus.ram[0x300..0x302], [2, 3]);
+    }
+
+    #[test]
+    fn on_write_gets_the_address_and_value() {
+        // LD V0, 0x2A; LD I, 0x301; LD [I], V0; JMP self
+        let rom = [0x60, 0x2A, 0xA3, 0x01, 0xF0, 0x55, 0x12, 0x06];
+        let source = r#"
+            on_write(0x300, 0x30F, "written");
+            fn written(addr, value) { set_reg("V5", addr - 0x300); set_reg("V6", value); }
+        "#;
+        let mut chip8 = load("write", &rom, source);
+        for _ in 0..3 {
+            chip8.step();
+        }
+        assert_eq!(chip8.registers[5..7], [1, 0x2A]);
+    }
+
+    #[test]
+    fn on_frame_runs_once_per_frame() {
+        let source = "fn on_frame() { poke(0x300, peek(0x300) + 1); }";
+        let mut chip8 = load("frame", &[0x12, 0x00], source);
+        for _ in 0..3 {
+            chip8.run_frame();
+        }
+        assert_eq!(chip8.bus.ram[0x300], 3);
+    }
+
+    #[test]
+    fn pressed_keys_take_the_skp_branch() {
+        // LD V0, 5; SKP V0; JMP self (released); JMP self (pressed)
+        let rom = [0x60, 0x05, 0xE0, 0x9E, 0x12, 0x04, 0x12, 0x06];
+        let mut chip8 = load("press", &rom, "press(5);");
+        chip8.run_frame();
+        assert_eq!(chip8.pc, 0x206);
+
+        let mut chip8 = load("no-press", &rom, "");
+        chip8.run_frame();
+        assert_eq!(chip8.pc, 0x204);
+    }
+
+    #[test]
+    fn release_keeps_the_keys_of_the_player() {
+        let source = r#"
+            on_exec(0x200, "toggle");
+            fn toggle() {
+                if peek(0x300) == 0 { press(5); press(6); } else { release(5); release(6); }
+                poke(0x300, 1);
+            }
+        "#;
+        let mut chip8 = load("release", &[0x12, 0x00], source);
+        // The player holds 6 on the keypad
+        chip8.keys[6] = true;
+        chip8.step();
+        assert!(chip8.keys[5] && chip8.keys[6]);
+        chip8.step();
+        assert!(!chip8.keys[5]);
+        assert!(chip8.keys[6]);
+    }
+
+    #[test]
+    fn failed_assertions_are_counted() {
+        let chip8 = load(
+            "assert",
+            &[0x12, 0x00],
+            r#"assert(false, "a"); assert(true, "b");"#,
+        );
+        assert_eq!(script(&chip8).failures, 1);
+        assert!(!script(&chip8).quit);
+    }
+
+    #[test]
+    fn errors_end_the_run() {
+        let source = "fn on_frame() { peek(0x1000); }";
+        let mut chip8 = load("error", &[0x12, 0x00], source);
+        assert!(!script(&chip8).quit);
+        chip8.run_frame();
+        assert!(script(&chip8).quit);
+        assert_eq!(script(&chip8).failures, 1);
+    }
+}
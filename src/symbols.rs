@@ -0,0 +1,205 @@
+use crate::constants;
+use crate::debug;
+use crate::expr;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+// Labels of the ROM addresses, loaded from a symbol file.
+// Two formats are supported:
+// - JSON, as written by Octo: an object with a 'labels' object mapping the names to
+//   their addresses, or directly that mapping.
+// - Text, one label per line as 'ADDR NAME', 'NAME ADDR' or 'NAME = ADDR', with
+//   addresses in hex with the 0x prefix or in decimal. Lines starting with '#' or ';'
+//   are comments.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    names: BTreeMap<usize, String>,
+    addrs: HashMap<String, usize>,
+}
+
+impl Symbols {
+    // Loads the symbol file at the given path
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("Error reading '{}': {}", path, e))?;
+        let mut symbols = Symbols::default();
+        if text.trim_start().starts_with('{') {
+            symbols.parse_json(&text)?;
+        } else {
+            symbols.parse_text(&text)?;
+        }
+        println!("Loaded {} symbols from: {}", symbols.addrs.len(), path);
+        Ok(symbols)
+    }
+
+    fn parse_json(&mut self, text: &str) -> Result<(), String> {
+        let json: serde_json::Value =
+            serde_json::from_str(text).map_err(|e| format!("Invalid symbol file: {}", e))?;
+        let labels = json.get("labels").unwrap_or(&json);
+        let labels = labels
+            .as_object()
+            .ok_or("Invalid symbol file: expected an object of labels")?;
+        for (name, addr) in labels {
+            // Skip the non-address entries
+            if let Some(addr) = addr
+                .as_u64()
+                .filter(|a| (*a as usize) < constants::RAM_SIZE)
+            {
+                self.insert(name, addr as usize);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_text(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|f| !f.is_empty())
+                .collect();
+            let label = match fields[..] {
+                [a, b] => match (expr::parse_addr(a), expr::parse_addr(b)) {
+                    (Ok(addr), Err(_)) => Some((b, addr)),
+                    (Err(_), Ok(addr)) => Some((a, addr)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match label {
+                Some((name, addr)) => self.insert(name, addr),
+                None => return Err(format!("Invalid symbol at line {}: '{}'", n + 1, line)),
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, name: &str, addr: usize) {
+        self.names.insert(addr, name.to_string());
+        self.addrs.insert(name.to_string(), addr);
+    }
+
+    // Returns the label at the address
+    pub fn name(&self, addr: usize) -> Option<&str> {
+        self.names.get(&addr).map(|n| n.as_str())
+    }
+
+    // Returns the address of the label
+    pub fn addr(&self, name: &str) -> Option<usize> {
+        self.addrs.get(name).copied()
+    }
+
+    // Returns the nearest label at or before the address, with the offset, e.g. 'draw+4'
+    pub fn locate(&self, addr: usize) -> Option<String> {
+        let (label_addr, name) = self.names.range(..=addr).next_back()?;
+        match addr - label_addr {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{}", name, offset)),
+        }
+    }
+
+    // Returns the address in hex followed by its nearest label, e.g. '0x0206 (draw+2)'
+    pub fn describe(&self, addr: usize) -> String {
+        match self.locate(addr) {
+            Some(label) => format!("0x{:04x} ({})", addr, label),
+            None => format!("0x{:04x}", addr),
+        }
+    }
+
+    // Parses a label or an address in hex with the 0x prefix, or in decimal
+    pub fn parse_addr(&self, text: &str) -> Result<usize, String> {
+        match self.addr(text.trim()) {
+            Some(addr) => Ok(addr),
+            None => expr::parse_addr(text),
+        }
+    }
+
+    // Parses an inclusive address range 'ADDR[-END]' of labels or addresses.
+    // Labels may contain dashes, so every split is tried.
+    pub fn parse_range(&self, text: &str) -> Result<(usize, usize), String> {
+        let (start, end) = match self.parse_addr(text) {
+            Ok(addr) => (addr, addr),
+            Err(error) => text
+                .match_indices('-')
+                .find_map(|(i, _)| {
+                    let start = self.parse_addr(&text[..i]).ok()?;
+                    let end = self.parse_addr(&text[i + 1..]).ok()?;
+                    Some((start, end))
+                })
+                .ok_or(error)?,
+        };
+        if end < start {
+            return Err(format!("Empty address range '{}'", text));
+        }
+        Ok((start, end))
+    }
+
+    // Disassembles the instruction, replacing the address operand by its label
+    pub fn disassemble(&self, instr: u16) -> String {
+        let text = debug::disassemble(instr);
+        let nnn = (instr & 0x0FFF) as usize;
+        match (instr & 0xF000, self.name(nnn)) {
+            (0x1000 | 0x2000 | 0xA000 | 0xB000, Some(name)) => {
+                text.replace(&format!("0x{:04x}", nnn), name)
+            }
+            _ => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Symbols {
+        let mut symbols = Symbols::default();
+        symbols
+            .parse_text("# Labels\n0x200 main\ndraw 0x20A\n; Data\nsprite-a = 0x300\n")
+            .unwrap();
+        symbols
+    }
+
+    #[test]
+    fn parses_text_and_json() {
+        let symbols = symbols();
+        assert_eq!(symbols.addr("main"), Some(0x200));
+        assert_eq!(symbols.addr("draw"), Some(0x20A));
+        assert_eq!(symbols.name(0x300), Some("sprite-a"));
+        assert!(Symbols::default().parse_text("main").is_err());
+        assert!(Symbols::default().parse_text("main draw").is_err());
+
+        let mut json = Symbols::default();
+        json.parse_json(r#"{"labels": {"main": 512, "big": 4096, "text": "x"}}"#)
+            .unwrap();
+        assert_eq!(json.addr("main"), Some(0x200));
+        assert_eq!(json.addr("big"), None);
+        assert_eq!(json.addr("text"), None);
+        assert!(Symbols::default().parse_json("[]").is_err());
+    }
+
+    #[test]
+    fn locates_addresses() {
+        let symbols = symbols();
+        assert_eq!(symbols.locate(0x20A).as_deref(), Some("draw"));
+        assert_eq!(symbols.describe(0x20E), "0x020e (draw+4)");
+        assert_eq!(symbols.locate(0x100), None);
+        assert_eq!(symbols.disassemble(0x220A), "CALL draw");
+        assert_eq!(symbols.disassemble(0x6A0A), debug::disassemble(0x6A0A));
+    }
+
+    #[test]
+    fn parses_labels_and_ranges() {
+        let symbols = symbols();
+        assert_eq!(symbols.parse_addr(" draw "), Ok(0x20A));
+        assert_eq!(symbols.parse_addr("0x20"), Ok(0x20));
+        assert_eq!(symbols.parse_range("sprite-a"), Ok((0x300, 0x300)));
+        assert_eq!(symbols.parse_range("main-sprite-a"), Ok((0x200, 0x300)));
+        assert_eq!(symbols.parse_range("0x300-0x30F"), Ok((0x300, 0x30F)));
+        assert!(symbols.parse_range("sprite-a-main").is_err());
+        assert!(symbols.parse_range("nowhere").is_err());
+    }
+}
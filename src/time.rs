@@ -6,3 +6,42 @@ pub fn time_nanos() -> u128 {
         .unwrap()
         .as_nanos()
 }
+
+// Measures the instruction and frame rates over windows of one second
+pub struct RateMeter {
+    // Start of the current window [ns]
+    start_t: u128,
+    // Counters at the start of the current window
+    instructions: u64,
+    frames: u64,
+    // Measured instructions per second
+    pub ips: u64,
+    // Measured frames per second
+    pub fps: u64,
+}
+
+impl RateMeter {
+    pub fn new(t: u128, instructions: u64, frames: u64) -> Self {
+        RateMeter {
+            start_t: t,
+            instructions,
+            frames,
+            ips: 0,
+            fps: 0,
+        }
+    }
+
+    // Updates the counters at time t [ns], returns true if a new measure is available
+    pub fn update(&mut self, t: u128, instructions: u64, frames: u64) -> bool {
+        let elapsed = t - self.start_t;
+        if elapsed < 1_000_000_000 {
+            return false;
+        }
+        self.ips = ((instructions - self.instructions) as u128 * 1_000_000_000 / elapsed) as u64;
+        self.fps = ((frames - self.frames) as u128 * 1_000_000_000 / elapsed) as u64;
+        self.start_t = t;
+        self.instructions = instructions;
+        self.frames = frames;
+        true
+    }
+}
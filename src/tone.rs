@@ -0,0 +1,103 @@
+use crate::constants;
+
+use rand::random;
+use std::f32::consts::PI;
+
+// Shape of the beeper waveform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    // Parses a waveform name (square, sine, triangle, noise)
+    pub fn parse(name: &str) -> Result<Waveform, String> {
+        match name.to_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Unknown waveform '{}'", name)),
+        }
+    }
+}
+
+// Beeper tone settings
+#[derive(Clone, Copy, Debug)]
+pub struct Tone {
+    pub waveform: Waveform,
+    // Frequency [Hz]
+    pub freq: f32,
+    // Volume in [0, 1]
+    pub volume: f32,
+    // Fraction of the period the square wave is high, in (0, 1)
+    pub duty: f32,
+}
+
+// Oscillator generating the beeper samples
+pub struct Oscillator {
+    tone: Tone,
+    phase_inc: f32,
+    phase: f32,
+    // Current envelope gain in [0, 1]
+    gain: f32,
+    // Gain change per sample during attack and release
+    gain_step: f32,
+    // Whether the beep is on
+    pub gate: bool,
+    // Noise value held during the current period
+    noise: f32,
+}
+
+impl Oscillator {
+    // Creates the oscillator for the given sample rate [Hz], silent
+    pub fn new(tone: Tone, rate: f32) -> Self {
+        Oscillator {
+            tone,
+            phase_inc: tone.freq / rate,
+            phase: 0.0,
+            gain: 0.0,
+            gain_step: 1.0 / (constants::AUDIO_ENVELOPE_S * rate),
+            gate: false,
+            noise: 0.0,
+        }
+    }
+
+    // Returns the raw waveform value in [-1, 1] at the current phase
+    fn sample(&self) -> f32 {
+        match self.tone.waveform {
+            Waveform::Square => {
+                if self.phase < self.tone.duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (self.phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    // Fills the buffer with the next samples
+    pub fn render(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            // Ramp the envelope towards the gate to avoid clicks
+            if self.gate {
+                self.gain = (self.gain + self.gain_step).min(1.0);
+            } else {
+                self.gain = (self.gain - self.gain_step).max(0.0);
+            }
+            *x = self.sample() * self.tone.volume * self.gain;
+
+            self.phase += self.phase_inc;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                self.noise = random::<f32>() * 2.0 - 1.0;
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::chip8::Chip8;
+use crate::constants;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Execution trace writing one line per executed instruction:
+//   CYCLE PC OPCODE MNEMONIC ; CHANGED_REGISTERS I=.. DT=.. ST=..
+// e.g. '000000002 0204 8014 ADD V0, V1         ; V0=0c VF=00 I=0000 DT=00 ST=00'.
+// Only the instructions within the address ranges are traced, if any are given.
+// In ring-buffer mode only the last instructions are kept, and they are written
+// when the machine stops at a breakpoint or the emulator panics.
+pub struct Trace {
+    out: BufWriter<File>,
+    // Inclusive address ranges of the traced instructions, empty to trace everything
+    ranges: Vec<(usize, usize)>,
+    // Last traced lines in ring-buffer mode
+    ring: Option<VecDeque<String>>,
+    ring_size: usize,
+    // Whether writing failed, which stops the trace
+    failed: bool,
+}
+
+impl Trace {
+    // Creates the trace file, keeping only the last ring_size lines if it is not zero
+    pub fn create(
+        path: &str,
+        ranges: Vec<(usize, usize)>,
+        ring_size: usize,
+    ) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Error creating '{}': {}", path, e))?;
+        let ring = if ring_size > 0 {
+            Some(VecDeque::with_capacity(ring_size))
+        } else {
+            None
+        };
+        Ok(Trace {
+            out: BufWriter::new(file),
+            ranges,
+            ring,
+            ring_size,
+            failed: false,
+        })
+    }
+
+    // Returns true if the instruction at the given address is traced
+    pub fn traces(&self, pc: usize) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|(s, e)| pc >= *s && pc <= *e)
+    }
+
+    // Records the instruction that just ran, given its cycle, address, opcode and the
+    // registers before it
+    pub fn record(
+        &mut self,
+        cycle: u64,
+        pc: usize,
+        instr: u16,
+        before: &[u8; constants::N_REGISTERS],
+        chip8: &Chip8,
+    ) {
+        if self.failed {
+            return;
+        }
+        let mut line = format!(
+            "{:09} {:04x} {:04x} {:<20};",
+            cycle,
+            pc,
+            instr,
+            chip8.symbols.disassemble(instr)
+        );
+        for (i, (old, new)) in before.iter().zip(chip8.registers.iter()).enumerate() {
+            if old != new {
+                line.push_str(&format!(" V{:X}={:02x}", i, new));
+            }
+        }
+        line.push_str(&format!(
+            " I={:04x} DT={:02x} ST={:02x}",
+            chip8.index, chip8.dt, chip8.st
+        ));
+
+        match self.ring.as_mut() {
+            Some(ring) => {
+                if ring.len() == self.ring_size {
+                    ring.pop_front();
+                }
+                ring.push_back(line);
+            }
+            None => {
+                if let Err(e) = writeln!(self.out, "{}", line) {
+                    self.fail(e);
+                }
+            }
+        }
+    }
+
+    // Writes the lines kept in the ring buffer, if any
+    pub fn dump(&mut self) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.write_ring() {
+            self.fail(e);
+        }
+    }
+
+    // Reports the write error and stops tracing, the emulation goes on
+    fn fail(&mut self, error: std::io::Error) {
+        eprintln!("Error writing the trace, tracing stopped: {}", error);
+        self.failed = true;
+        self.ring = None;
+    }
+
+    fn write_ring(&mut self) -> std::io::Result<()> {
+        if let Some(ring) = self.ring.as_mut() {
+            if ring.is_empty() {
+                return Ok(());
+            }
+            println!("Writing the last {} traced instructions", ring.len());
+            for line in ring.drain(..) {
+                writeln!(self.out, "{}", line)?;
+            }
+            writeln!(self.out, "--")?;
+        }
+        self.out.flush()
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        // Keep the history that led to the error. A write error is ignored, as panicking
+        // again while unwinding would abort.
+        if std::thread::panicking() && !self.failed {
+            let _ = self.write_ring();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic;
+
+    #[test]
+    fn ring_is_written_when_panicking() {
+        let path = std::env::temp_dir().join(format!("rchip8-trace-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let chip8 = Chip8::new(vec![0x60, 0x01], 0, 10, false);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut trace = Trace::create(&path, Vec::new(), 2).unwrap();
+            for cycle in 0..3 {
+                trace.record(cycle, 0x200, 0x6001, &[0; constants::N_REGISTERS], &chip8);
+            }
+            panic!("Emulation error");
+        }));
+        assert!(result.is_err());
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("000000001 0200 6001 LD V0, 0x0001"));
+        assert_eq!(lines[2], "--");
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn write_errors_stop_the_trace() {
+        let chip8 = Chip8::new(vec![0x60, 0x01], 0, 10, false);
+        // Writes to /dev/full fail with no space left
+        let mut trace = Trace::create("/dev/full", Vec::new(), 0).unwrap();
+        for cycle in 0..1000 {
+            trace.record(cycle, 0x200, 0x6001, &[0; constants::N_REGISTERS], &chip8);
+        }
+        assert!(trace.failed);
+        trace.dump();
+    }
+}
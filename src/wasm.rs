@@ -0,0 +1,107 @@
+use crate::chip8::{self, Chip8};
+use crate::constants;
+use crate::detect;
+use crate::quirks::Quirks;
+
+use wasm_bindgen::prelude::*;
+
+// WebAssembly bindings, built with the 'wasm' feature for wasm32-unknown-unknown and
+// wasm-bindgen. The page runs a frame per animation frame (60 Hz), feeds the keys and
+// draws the framebuffer:
+//
+//     const chip8 = new Chip8(rom);
+//     chip8.setKey(5, true);
+//     chip8.runFrame();
+//     const pixels = chip8.framebuffer(); // Uint8Array, width * height
+//     beeper.muted = !chip8.soundOn;
+#[wasm_bindgen(js_name = Chip8)]
+pub struct WasmChip8 {
+    chip8: Chip8,
+}
+
+#[wasm_bindgen(js_class = Chip8)]
+impl WasmChip8 {
+    // Loads the ROM, with the quirks of its detected platform
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8]) -> WasmChip8 {
+        let ips: u32 = constants::DEF_IPS_STR.parse().unwrap_or(1000);
+        let mut chip8 = Chip8::new(rom.to_vec(), 0, chip8::cycles_per_frame(ips), false);
+        chip8.quirks = quirks(rom);
+        WasmChip8 { chip8 }
+    }
+
+    // Restarts the machine with a new ROM, with the quirks of its detected platform
+    pub fn load(&mut self, rom: &[u8]) {
+        self.chip8.quirks = quirks(rom);
+        self.chip8.reset(rom.to_vec(), 0);
+    }
+
+    // Runs a frame, 1/60 s of emulation
+    #[wasm_bindgen(js_name = runFrame)]
+    pub fn run_frame(&mut self) {
+        self.chip8.run_frame();
+        // The page reads soundOn instead
+        self.chip8.sound_events.clear();
+    }
+
+    // Presses or releases the key 0x0-0xF
+    #[wasm_bindgen(js_name = setKey)]
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        if let Some(state) = self.chip8.keys.get_mut(key) {
+            *state = pressed;
+        }
+    }
+
+    // Returns the display, width * height bytes row by row, 1 if the pixel is on
+    pub fn framebuffer(&self) -> Vec<u8> {
+        self.chip8.display.to_vec()
+    }
+
+    // Whether the beeper sounds, the sound timer is running
+    #[wasm_bindgen(getter, js_name = soundOn)]
+    pub fn sound_on(&self) -> bool {
+        self.chip8.st > 0
+    }
+
+    // Sets the speed in instructions per frame
+    #[wasm_bindgen(js_name = setCyclesPerFrame)]
+    pub fn set_cycles_per_frame(&mut self, cycles_per_frame: u32) {
+        self.chip8.cycles_per_frame = cycles_per_frame.max(1);
+    }
+
+    // Sets a quirk by name, see Quirks
+    #[wasm_bindgen(js_name = setQuirk)]
+    pub fn set_quirk(&mut self, name: &str, value: bool) -> Result<(), String> {
+        self.chip8.quirks.set(name, value)
+    }
+
+    // Seeds the random number generator of RND
+    pub fn seed(&mut self, seed: u32) {
+        self.chip8.seed(seed as u64);
+    }
+
+    // Number of frames run, as a JS number
+    #[wasm_bindgen(getter, js_name = frameCount)]
+    pub fn frame_count(&self) -> f64 {
+        self.chip8.frame_count as f64
+    }
+
+    // Display width and height in pixels
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> usize {
+        constants::DISPLAY_WIDTH
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> usize {
+        constants::DISPLAY_HEIGHT
+    }
+}
+
+// Returns the quirks of the detected platform of the ROM
+fn quirks(rom: &[u8]) -> Quirks {
+    detect::detect(rom, "")
+        .platform
+        .and_then(Quirks::platform)
+        .unwrap_or_default()
+}
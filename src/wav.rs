@@ -0,0 +1,202 @@
+use crate::constants;
+use crate::tone::{Oscillator, Tone};
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// Size of the RIFF/WAVE header in bytes
+const HEADER_LEN: u32 = 44;
+
+// Writes mono 16-bit PCM samples to a WAV file.
+// The header sizes are patched when the writer is dropped.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    // Number of samples written so far
+    samples: u32,
+}
+
+impl WavWriter {
+    // Creates the file at the given path and writes a provisional header
+    pub fn create(path: &str, sample_rate: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Error creating '{}': {}", path, e))?;
+        let mut out = BufWriter::new(file);
+        let byte_rate = sample_rate * 2;
+
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_LEN - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        // Format chunk size
+        header.extend_from_slice(&16_u32.to_le_bytes());
+        // Format: PCM
+        header.extend_from_slice(&1_u16.to_le_bytes());
+        // Channels: mono
+        header.extend_from_slice(&1_u16.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        // Block align
+        header.extend_from_slice(&2_u16.to_le_bytes());
+        // Bits per sample
+        header.extend_from_slice(&16_u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0_u32.to_le_bytes());
+
+        out.write_all(&header)
+            .map_err(|e| format!("Error writing '{}': {}", path, e))?;
+
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    // Appends the given samples in [-1, 1] to the file
+    pub fn write(&mut self, samples: &[f32]) {
+        for s in samples {
+            let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            if self.out.write_all(&v.to_le_bytes()).is_err() {
+                return;
+            }
+            self.samples += 1;
+        }
+    }
+
+    // Writes the final chunk sizes into the header
+    fn finalize(&mut self) -> std::io::Result<()> {
+        let data_len = self.samples * 2;
+        self.out.flush()?;
+        let file = self.out.get_mut();
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        file.seek(SeekFrom::Start(40))?;
+        file.write_all(&data_len.to_le_bytes())?;
+        file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            println!("Error finalizing WAV file: {}", e);
+        }
+    }
+}
+
+// Renders the beeper to a WAV file from the sound events of the machine, in emulated
+// time and without an audio device, so that headless runs can check the sound timing
+pub struct Recorder {
+    wav: WavWriter,
+    oscillator: Oscillator,
+    // Time of the first sample [ns]
+    start_t: u128,
+    // Number of samples rendered since start_t
+    generated: u128,
+    // Scratch buffer for the rendered samples
+    buffer: Vec<f32>,
+}
+
+impl Recorder {
+    // Creates the WAV file at the given path, rendering the tone from the start time [ns]
+    pub fn create(path: &str, tone: Tone, start_t: u128) -> Result<Self, String> {
+        let rate = constants::AUDIO_SAMPLE_RATE;
+        Ok(Recorder {
+            wav: WavWriter::create(path, rate)?,
+            oscillator: Oscillator::new(tone, rate as f32),
+            start_t,
+            generated: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Renders the samples up to time t [ns], applying the given sound events
+    pub fn update(&mut self, t: u128, events: impl IntoIterator<Item = (u128, bool)>) {
+        for (event_t, on) in events {
+            self.render(event_t);
+            self.oscillator.gate = on;
+        }
+        self.render(t);
+    }
+
+    // Renders the samples from the last rendered one up to time t [ns]
+    fn render(&mut self, t: u128) {
+        let target = t.saturating_sub(self.start_t) * constants::AUDIO_SAMPLE_RATE as u128
+            / 1_000_000_000;
+        if target <= self.generated {
+            return;
+        }
+        self.buffer.resize((target - self.generated) as usize, 0.0);
+        self.oscillator.render(&mut self.buffer);
+        self.wav.write(&self.buffer);
+        self.generated = target;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::tone::Waveform;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rchip8-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    // Samples of a mono 16-bit WAV file, checking its header
+    fn read_samples(path: &str) -> Vec<i16> {
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let riff_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let data_len = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        assert_eq!(riff_len as usize, bytes.len() - 8);
+        assert_eq!(data_len as usize, bytes.len() - HEADER_LEN as usize);
+        bytes[HEADER_LEN as usize..]
+            .chunks(2)
+            .map(|s| i16::from_le_bytes([s[0], s[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn writer_patches_header_sizes() {
+        let path = temp_path("writer.wav");
+        let mut wav = WavWriter::create(&path, 8000).unwrap();
+        wav.write(&[0.0, 1.0, -2.0]);
+        drop(wav);
+        assert_eq!(read_samples(&path), vec![0, i16::MAX, -i16::MAX]);
+    }
+
+    #[test]
+    fn recorder_follows_sound_timer() {
+        // V0 = 6, ST = V0, loop
+        let rom = vec![0x60, 0x06, 0xF0, 0x18, 0x12, 0x04];
+        let mut chip8 = Chip8::new(rom, 0, 10, false);
+        let tone = Tone {
+            waveform: Waveform::Square,
+            freq: 440.0,
+            volume: 0.5,
+            duty: 0.5,
+        };
+        let path = temp_path("recorder.wav");
+        let mut recorder = Recorder::create(&path, tone, 0).unwrap();
+        for _ in 0..20 {
+            chip8.run_frame();
+            recorder.update(chip8.time(), chip8.sound_events.drain(..));
+        }
+        drop(recorder);
+
+        let samples = read_samples(&path);
+        let rate = constants::AUDIO_SAMPLE_RATE as usize;
+        assert_eq!(samples.len(), 20 * rate / 60);
+        // The beep starts at the second instruction and ends at the 6th timer tick
+        let first = samples.iter().position(|s| *s != 0).unwrap();
+        let last = samples.iter().rposition(|s| *s != 0).unwrap();
+        let ms = |sample: usize| sample * 1000 / rate;
+        assert!(ms(first) <= 4, "beep starts at {} ms", ms(first));
+        let release = (constants::AUDIO_ENVELOPE_S * 1000.0) as usize;
+        assert!(
+            (99..=101 + release).contains(&ms(last)),
+            "beep ends at {} ms",
+            ms(last)
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// Runs the libretro core as a frontend does: loads the built library, registers the
+// callbacks, loads a ROM and runs frames. Run with:
+//   cargo test --no-default-features --features libretro --test libretro
+#![cfg(feature = "libretro")]
+
+use libloading::{Library, Symbol};
+use std::ffi::{c_char, c_uint, c_void};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+#[repr(C)]
+struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+const WIDTH: c_uint = 64;
+const HEIGHT: c_uint = 32;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+// Draws the 0 digit at (0, 0) and stores 0xAB at 0x300
+const ROM: [u8; 12] = [
+    0xA0, 0x00, // LD I, 0x000
+    0xD0, 0x05, // DRW V0, V0, 5
+    0x60, 0xAB, // LD V0, 0xAB
+    0xA3, 0x00, // LD I, 0x300
+    0xF0, 0x55, // LD [I], V0
+    0x12, 0x0A, // JMP 0x20A
+];
+
+static LIBRARY: OnceLock<Library> = OnceLock::new();
+// Frames received by the video callback, the lit pixels of the last one, and the byte
+// 0x300 of the RAM read back from the callback
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+static LIT: AtomicUsize = AtomicUsize::new(0);
+static RAM_300: Mutex<Option<u8>> = Mutex::new(None);
+static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+// Loads the core built next to the test executable
+fn library() -> &'static Library {
+    LIBRARY.get_or_init(|| {
+        let exe = std::env::current_exe().unwrap();
+        let name = libloading::library_filename("rchip8");
+        let path = exe
+            .ancestors()
+            .skip(1)
+            .take(2)
+            .map(|dir| dir.join(&name))
+            .find(|path| path.is_file())
+            .expect("Core library not built");
+        unsafe { Library::new(path).unwrap() }
+    })
+}
+
+fn symbol<T>(name: &str) -> Symbol<'static, T> {
+    unsafe { library().get(name.as_bytes()).unwrap() }
+}
+
+unsafe extern "C" fn environment(_cmd: c_uint, _data: *mut c_void) -> bool {
+    true
+}
+
+unsafe extern "C" fn video_refresh(
+    data: *const c_void,
+    width: c_uint,
+    height: c_uint,
+    pitch: usize,
+) {
+    assert_eq!((width, height, pitch), (WIDTH, HEIGHT, WIDTH as usize * 4));
+    let pixels = std::slice::from_raw_parts(data as *const u32, (width * height) as usize);
+    let background = pixels[pixels.len() - 1];
+    LIT.store(
+        pixels.iter().filter(|p| **p != background).count(),
+        Ordering::SeqCst,
+    );
+    FRAMES.fetch_add(1, Ordering::SeqCst);
+    // Calling the core back from a callback must not deadlock
+    let memory_data: Symbol<extern "C" fn(c_uint) -> *mut c_void> = symbol("retro_get_memory_data");
+    let ram = memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8;
+    *RAM_300.lock().unwrap() = Some(*ram.add(0x300));
+}
+
+unsafe extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    AUDIO_FRAMES.fetch_add(frames, Ordering::SeqCst);
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(
+    _port: c_uint,
+    _device: c_uint,
+    _index: c_uint,
+    _id: c_uint,
+) -> i16 {
+    0
+}
+
+#[test]
+fn frontend_runs_a_rom() {
+    type Environment = unsafe extern "C" fn(c_uint, *mut c_void) -> bool;
+    type VideoRefresh = unsafe extern "C" fn(*const c_void, c_uint, c_uint, usize);
+    type AudioSampleBatch = unsafe extern "C" fn(*const i16, usize) -> usize;
+    type InputPoll = unsafe extern "C" fn();
+    type InputState = unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16;
+    let set_environment: Symbol<extern "C" fn(Option<Environment>)> =
+        symbol("retro_set_environment");
+    let set_video: Symbol<extern "C" fn(Option<VideoRefresh>)> = symbol("retro_set_video_refresh");
+    let set_audio: Symbol<extern "C" fn(Option<AudioSampleBatch>)> =
+        symbol("retro_set_audio_sample_batch");
+    let set_input_poll: Symbol<extern "C" fn(Option<InputPoll>)> = symbol("retro_set_input_poll");
+    let set_input_state: Symbol<extern "C" fn(Option<InputState>)> =
+        symbol("retro_set_input_state");
+    let init: Symbol<extern "C" fn()> = symbol("retro_init");
+    let load_game: Symbol<unsafe extern "C" fn(*const GameInfo) -> bool> =
+        symbol("retro_load_game");
+    let run: Symbol<extern "C" fn()> = symbol("retro_run");
+    let serialize_size: Symbol<extern "C" fn() -> usize> = symbol("retro_serialize_size");
+    let serialize: Symbol<unsafe extern "C" fn(*mut c_void, usize) -> bool> =
+        symbol("retro_serialize");
+    let unserialize: Symbol<unsafe extern "C" fn(*const c_void, usize) -> bool> =
+        symbol("retro_unserialize");
+    let unload_game: Symbol<extern "C" fn()> = symbol("retro_unload_game");
+    let deinit: Symbol<extern "C" fn()> = symbol("retro_deinit");
+
+    set_environment(Some(environment));
+    set_video(Some(video_refresh));
+    set_audio(Some(audio_sample_batch));
+    set_input_poll(Some(input_poll));
+    set_input_state(Some(input_state));
+    init();
+    let game = GameInfo {
+        path: c"test.ch8".as_ptr(),
+        data: ROM.as_ptr() as *const c_void,
+        size: ROM.len(),
+        meta: std::ptr::null(),
+    };
+    assert!(unsafe { load_game(&game) });
+
+    let frames = 10;
+    for _ in 0..frames {
+        run();
+    }
+    assert_eq!(FRAMES.load(Ordering::SeqCst), frames);
+    // The 0 digit has 14 pixels on
+    assert_eq!(LIT.load(Ordering::SeqCst), 14);
+    assert_eq!(*RAM_300.lock().unwrap(), Some(0xAB));
+    // 735 samples per frame at 44100 Hz
+    assert_eq!(AUDIO_FRAMES.load(Ordering::SeqCst), frames * 735);
+
+    // Save state round trip
+    let mut state = vec![0_u8; serialize_size()];
+    assert!(unsafe { serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+    run();
+    assert!(unsafe { unserialize(state.as_ptr() as *const c_void, state.len()) });
+    assert!(!unsafe { unserialize(state.as_ptr() as *const c_void, 4) });
+
+    unload_game();
+    run();
+    assert_eq!(FRAMES.load(Ordering::SeqCst), frames + 1);
+    deinit();
+}
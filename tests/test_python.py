@@ -0,0 +1,78 @@
+# Smoke test of the Python extension module. Build it into the environment first:
+#
+#     maturin develop
+#     pytest tests/test_python.py
+#
+# or run this file with Python, without pytest.
+import rchip8
+
+# Draws the 0 digit at (V0, V0) and counts at 0x301 while the key 5 is pressed
+ROM = bytes([
+    0x00, 0xE0,  # CLS
+    0xF0, 0x29,  # LD F, V0
+    0xD0, 0x05,  # DRW V0, V0, 5
+    0x62, 0x05,  # LD V2, 5
+    0xE2, 0x9E,  # SKP V2
+    0x12, 0x00,  # JMP 0x200
+    0x71, 0x01,  # ADD V1, 1
+    0xA3, 0x00,  # LD I, 0x300
+    0xF1, 0x55,  # LD [I], V1 (and V0)
+    0x12, 0x00,  # JMP 0x200
+])
+
+
+def test_frame_draws_the_framebuffer():
+    chip8 = rchip8.Chip8(ROM, cycles_per_frame=15, seed=1)
+    chip8.frame(2)
+    screen = chip8.framebuffer()
+    assert len(screen) == rchip8.WIDTH * rchip8.HEIGHT
+    # Top row of the 0 digit: 0xF0
+    assert list(screen[:8]) == [1, 1, 1, 1, 0, 0, 0, 0]
+    assert chip8.frame_count == 2
+    assert chip8.instruction_count == 30
+
+
+def test_keys_and_memory():
+    chip8 = rchip8.Chip8(ROM, platform="modernChip8")
+    chip8.frame(3)
+    assert chip8.peek(0x301) == b"\x00"
+    chip8.set_keys(1 << 5)
+    chip8.frame(3)
+    assert chip8.peek(0x301)[0] > 0
+    chip8.poke(0x400, b"\x01\x02")
+    assert chip8.peek(0x400, 2) == b"\x01\x02"
+
+
+def test_state_round_trip():
+    chip8 = rchip8.Chip8(ROM)
+    chip8.set_key(5, True)
+    chip8.frame(2)
+    state = chip8.save_state()
+    chip8.frame(5)
+    after = (chip8.peek(0, rchip8.RAM_SIZE), chip8.registers, chip8.pc)
+    chip8.load_state(state)
+    chip8.frame(5)
+    assert (chip8.peek(0, rchip8.RAM_SIZE), chip8.registers, chip8.pc) == after
+
+
+def test_invalid_arguments_raise_value_errors():
+    chip8 = rchip8.Chip8(ROM)
+    for call in (
+        lambda: chip8.peek(rchip8.RAM_SIZE),
+        lambda: chip8.poke(rchip8.RAM_SIZE - 1, b"\x00\x00"),
+        lambda: chip8.set_key(16, True),
+        lambda: rchip8.Chip8(ROM, platform="unknown"),
+        lambda: rchip8.Chip8(ROM, quirks={"unknown": True}),
+    ):
+        try:
+            call()
+        except ValueError:
+            continue
+        raise AssertionError("No ValueError")
+
+
+if __name__ == "__main__":
+    for name, test in list(globals().items()):
+        if name.startswith("test_"):
+            test()
+            print(name, "ok")
@@ -0,0 +1,58 @@
+// Runs the WebAssembly bindings in Node, as a page does. Run with wasm-bindgen-test-runner,
+// installed with `cargo install wasm-bindgen-cli` at the version of wasm-bindgen:
+//   CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner \
+//     cargo test --target wasm32-unknown-unknown --no-default-features --features wasm --test wasm
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use rchip8::wasm::WasmChip8;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+// Draws the 0 digit at (0, 0) while the key 5 is released, and clears the screen while
+// it is pressed
+const ROM: [u8; 16] = [
+    0x00, 0xE0, // CLS
+    0x60, 0x05, // LD V0, 5
+    0xE0, 0x9E, // SKP V0
+    0xA0, 0x00, // LD I, 0x000
+    0xE0, 0xA1, // SKNP V0
+    0x12, 0x00, // JMP 0x200
+    0xD1, 0x15, // DRW V1, V1, 5
+    0x12, 0x0E, // JMP 0x20E
+];
+
+// Lit pixels of the framebuffer
+fn lit(chip8: &WasmChip8) -> usize {
+    chip8.framebuffer().iter().filter(|p| **p == 1).count()
+}
+
+#[wasm_bindgen_test]
+fn frames_draw_the_framebuffer() {
+    let mut chip8 = WasmChip8::new(&ROM);
+    assert_eq!(chip8.framebuffer().len(), chip8.width() * chip8.height());
+    chip8.run_frame();
+    chip8.run_frame();
+    assert_eq!(chip8.frame_count(), 2.0);
+    // Top row of the 0 digit: 0xF0
+    assert_eq!(chip8.framebuffer()[..8], [1, 1, 1, 1, 0, 0, 0, 0]);
+    assert_eq!(lit(&chip8), 14);
+}
+
+#[wasm_bindgen_test]
+fn keys_quirks_and_reload() {
+    let mut chip8 = WasmChip8::new(&ROM);
+    chip8.seed(1);
+    chip8.set_key(5, true);
+    chip8.run_frame();
+    assert_eq!(lit(&chip8), 0);
+    // Out of range keys are ignored
+    chip8.set_key(16, true);
+    assert!(chip8.set_quirk("unknown", true).is_err());
+    chip8.set_cycles_per_frame(0);
+    chip8.set_key(5, false);
+    chip8.run_frame();
+    assert!(!chip8.sound_on());
+
+    chip8.load(&[0x00, 0xE0, 0x12, 0x00]);
+    chip8.run_frame();
+    assert_eq!(lit(&chip8), 0);
+}